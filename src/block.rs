@@ -2,12 +2,59 @@ use std::time::SystemTime;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use merkle_cbt::CBMT;
+use merkle_cbt::MerkleProof as CbtProof;
 use merkle_cbt::merkle_tree::Merge;
 use serde::{Deserialize, Serialize};
-use crate::errors::Result;
+use serde_json::json;
+use crate::errors::{BlockError, Result};
 use crate::transaction::Transaction;
+use crate::wallet::Wallet;
 
 const TARGET_HEX:usize = 4;
+
+// everything proof of work commits to, without the transactions
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BlockHeader {
+    pub timestamp: u128,
+    pub prev_block_hash: String,
+    pub merkle_root: Vec<u8>,
+    pub hash: String,
+    pub height: i32,
+    pub nonce: i32,
+}
+
+impl BlockHeader {
+    // the hash must be the one of the header fields and meet the target
+    pub fn check_pow(&self) -> Result<()> {
+        let hash = self.compute_hash()?;
+        if hash != self.hash {
+            return Err(BlockError::HashMismatch(self.hash.clone()).into());
+        }
+        if !meets_target(&hash) {
+            return Err(BlockError::InsufficientWork(self.hash.clone()).into());
+        }
+        Ok(())
+    }
+
+    pub fn compute_hash(&self) -> Result<String> {
+        hash_data(&self.prev_block_hash, self.timestamp, &self.merkle_root, self.nonce)
+    }
+
+    // whether proof shows txs are in the block, against the merkle root of the header
+    pub fn verify_merkle_proof(&self, txs: &[Transaction], proof: &MerkleProof) -> Result<bool> {
+        let proof = CbtProof::<Vec<u8>, MergeTX>::new(proof.indices.clone(), proof.lemmas.clone());
+        Ok(proof.verify(&self.merkle_root, &merkle_leaves(txs)?))
+    }
+}
+
+// the nodes of the merkle tree of a block needed to rebuild its root from some of
+// its transactions, as built by merkle-cbt
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MerkleProof {
+    pub indices: Vec<u32>,
+    pub lemmas: Vec<Vec<u8>>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Block {
     timestamp: u128,
@@ -27,6 +74,10 @@ impl Block {
         self.prev_block_hash.clone()
     }
 
+    pub fn get_timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
     pub fn get_height(&self) -> i32 {
         self.height
     }
@@ -35,6 +86,30 @@ impl Block {
         &self.transactions
     }
 
+    pub fn get_header(&self) -> Result<BlockHeader> {
+        Ok(BlockHeader {
+            timestamp: self.timestamp,
+            prev_block_hash: self.prev_block_hash.clone(),
+            merkle_root: self.hash_transaction()?,
+            hash: self.hash.clone(),
+            height: self.height,
+            nonce: self.nonce,
+        })
+    }
+
+    // a block received as its header and transactions, which connecting it checks
+    // against the merkle root
+    pub fn from_header(header: BlockHeader, transactions: Vec<Transaction>) -> Block {
+        Block {
+            timestamp: header.timestamp,
+            transactions,
+            prev_block_hash: header.prev_block_hash,
+            hash: header.hash,
+            height: header.height,
+            nonce: header.nonce,
+        }
+    }
+
     pub(crate) fn new_genesis_block(coinbase: Transaction) -> Block {
         Block::new_block(vec![coinbase], String::new(), 0).unwrap()
     }
@@ -57,51 +132,101 @@ impl Block {
     }
 
     fn run_proof_of_work(&mut self) -> Result<()> {
-        while !self.validate()? {
+        let merkle_root = self.hash_transaction()?;
+        loop {
+            let hash = hash_data(&self.prev_block_hash, self.timestamp, &merkle_root, self.nonce)?;
+            if meets_target(&hash) {
+                // mining success
+                self.hash = hash;
+                return Ok(());
+            }
             self.nonce += 1;
         }
-        // mining success
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        self.hash = hasher.result_str();
-        Ok(())
     }
 
-    fn validate(&self) -> Result<bool> {
-        let data = self.prepare_hash_data()?;
-        let mut hasher = Sha256::new();
-        hasher.input(&data[..]);
-        let res = hasher.result_str()[0..TARGET_HEX].as_bytes()
-            .iter().all(|&b| b == '0' as u8);
-        Ok(res)
-        // let mut vec1: Vec<u8> = Vec::new();
-        // vec1.resize(TARGET_HEX, '0' as u8);
-        // Ok(&hasher.result_str()[0..TARGET_HEX] == String::from_utf8(vec1)?)
+    fn hash_transaction(&self) -> Result<Vec<u8>> {
+        let txs = merkle_leaves(&self.transactions)?;
+        let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&txs);
+        Ok(tree.root())
+    }
 
+    // proof that the transactions txids are in the block, None if none of them is
+    pub fn merkle_proof(&self, txids: &[String]) -> Result<Option<MerkleProof>> {
+        let indices: Vec<u32> = self.transactions.iter().enumerate()
+            .filter(|(_, tx)| txids.contains(&tx.id))
+            .map(|(index, _)| index as u32)
+            .collect();
+        let leaves = merkle_leaves(&self.transactions)?;
+        Ok(CBMT::<Vec<u8>, MergeTX>::build_merkle_proof(&leaves, &indices).map(|proof| MerkleProof {
+            indices: proof.indices().to_vec(),
+            lemmas: proof.lemmas().to_vec(),
+        }))
     }
 
-    fn prepare_hash_data(&self) -> Result<Vec<u8>> {
-        let data = (
-            self.prev_block_hash.clone(),
-            self.timestamp,
-            self.hash_transaction()?,
-            TARGET_HEX,
-            self.nonce);
-        let bytes = bincode::serialize(&data)?;
-        Ok(bytes)
+    // serialized size in bytes
+    pub fn size(&self) -> Result<usize> {
+        Ok(bincode::serialized_size(self)? as usize)
     }
 
-    fn hash_transaction(&self) -> Result<Vec<u8>> {
-        let mut txs = Vec::new();
-        for tx in &self.transactions {
-            txs.push(tx.hash()?.as_bytes().to_vec());
-        }
-        let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&txs);
-        Ok(tree.root())
+    // value of every output of the block, the coinbase included
+    pub fn total_output(&self) -> i64 {
+        self.transactions.iter()
+            .flat_map(|tx| tx.vout.iter())
+            .map(|out| out.value as i64)
+            .sum()
+    }
+
+    // address the coinbase pays to
+    pub fn miner(&self) -> Option<String> {
+        self.transactions.iter()
+            .find(|tx| tx.is_coinbase())
+            .and_then(|tx| tx.vout.iter().find(|out| !out.is_data()))
+            .map(|out| Wallet::address_of(out.pub_key_hash.clone()))
+    }
+
+    // human readable form with the summary of the block and its transactions
+    pub fn to_json(&self) -> Result<serde_json::Value> {
+        Ok(json!({
+            "hash": self.hash,
+            "prev_hash": self.prev_block_hash,
+            "height": self.height,
+            "timestamp": self.timestamp as u64,
+            "nonce": self.nonce,
+            "merkle_root": hex::encode(self.hash_transaction()?),
+            "tx_count": self.transactions.len(),
+            "size": self.size()?,
+            "total_output": self.total_output(),
+            "miner": self.miner(),
+            "transactions": self.transactions.iter().map(|tx| tx.to_json()).collect::<Vec<_>>(),
+        }))
     }
 }
 
+fn merkle_leaves(txs: &[Transaction]) -> Result<Vec<Vec<u8>>> {
+    let mut leaves = Vec::new();
+    for tx in txs {
+        leaves.push(tx.hash()?.as_bytes().to_vec());
+    }
+    Ok(leaves)
+}
+
+fn hash_data(prev_block_hash: &str, timestamp: u128, merkle_root: &[u8], nonce: i32) -> Result<String> {
+    let data = (
+        prev_block_hash.to_string(),
+        timestamp,
+        merkle_root.to_vec(),
+        TARGET_HEX,
+        nonce);
+    let bytes = bincode::serialize(&data)?;
+    let mut hasher = Sha256::new();
+    hasher.input(&bytes[..]);
+    Ok(hasher.result_str())
+}
+
+fn meets_target(hash: &str) -> bool {
+    hash.as_bytes()[0..TARGET_HEX].iter().all(|&b| b == b'0')
+}
+
 struct MergeTX {}
 
 impl Merge for MergeTX{
@@ -116,4 +241,85 @@ impl Merge for MergeTX{
         hasher.result(&mut output);
         output.to_vec()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tx::{TxInput, TxOutput};
+
+    fn tx(seed: usize) -> Transaction {
+        Transaction {
+            id: format!("{:064x}", seed),
+            vin: vec![TxInput { txid: format!("{:064x}", seed + 1_000), vout: 0, signature: vec![1; 64], pub_key: vec![2; 32] }],
+            vout: vec![TxOutput { value: seed as i32 + 1, pub_key_hash: vec![3; 20], data: false }],
+            replaceable: false,
+        }
+    }
+
+    // a block of n transactions and its header with the merkle root; no proof of
+    // work is needed for proofs
+    fn block(n: usize) -> (Block, BlockHeader) {
+        let header = BlockHeader {
+            timestamp: 0,
+            prev_block_hash: String::new(),
+            merkle_root: Vec::new(),
+            hash: format!("block{}", n),
+            height: 1,
+            nonce: 0,
+        };
+        let block = Block::from_header(header, (0..n).map(tx).collect());
+        let header = block.get_header().unwrap();
+        (block, header)
+    }
+
+    // the transactions a proof is for, in block order
+    fn proved(block: &Block, txids: &[String]) -> Vec<Transaction> {
+        block.get_transactions().iter().filter(|tx| txids.contains(&tx.id)).cloned().collect()
+    }
+
+    #[test]
+    fn proof_of_each_transaction_verifies() {
+        for n in 1..=9 {
+            let (block, header) = block(n);
+            for tx in block.get_transactions() {
+                let txids = vec![tx.id.clone()];
+                let proof = block.merkle_proof(&txids).unwrap().unwrap();
+                assert!(header.verify_merkle_proof(&proved(&block, &txids), &proof).unwrap(), "{} of {}", tx.id, n);
+            }
+        }
+    }
+
+    #[test]
+    fn proof_of_several_transactions_verifies() {
+        let (block, header) = block(7);
+        let txids = vec![tx(1).id, tx(4).id, tx(6).id];
+        let proof = block.merkle_proof(&txids).unwrap().unwrap();
+        assert!(header.verify_merkle_proof(&proved(&block, &txids), &proof).unwrap());
+    }
+
+    #[test]
+    fn proof_rejects_altered_transactions() {
+        let (block, header) = block(5);
+        let txids = vec![tx(2).id];
+        let proof = block.merkle_proof(&txids).unwrap().unwrap();
+        let mut altered = proved(&block, &txids);
+        altered[0].vout[0].value += 1;
+        assert!(!header.verify_merkle_proof(&altered, &proof).unwrap());
+        assert!(!header.verify_merkle_proof(&[tx(3)], &proof).unwrap());
+    }
+
+    #[test]
+    fn proof_rejects_another_header() {
+        let (first, _) = block(4);
+        let (_, other) = block(5);
+        let txids = vec![tx(0).id];
+        let proof = first.merkle_proof(&txids).unwrap().unwrap();
+        assert!(!other.verify_merkle_proof(&proved(&first, &txids), &proof).unwrap());
+    }
+
+    #[test]
+    fn no_proof_without_matching_transactions() {
+        let (block, _) = block(3);
+        assert!(block.merkle_proof(&[tx(10).id]).unwrap().is_none());
+    }
+}
@@ -1,12 +1,23 @@
 use std::collections::{HashMap, HashSet};
 use failure::format_err;
-use log::{info};
-use crate::block::Block;
-use crate::errors::Result;
-use crate::transaction::{Transaction};
-use crate::tx::{TxOutputs};
+use log::{error, info};
+use crate::block::{Block, BlockHeader};
+use crate::errors::{BlockError, Result, TxError};
+use crate::transaction::{Transaction, SUBSIDY};
+use crate::utxoset::{UtxoEntry, UtxoRecord};
 
-#[derive(Debug)]
+// pruned mode keeps at least the bodies and undo data of this many blocks,
+// so the blocks that may be disconnected are still there
+pub const MIN_BLOCKS_TO_KEEP: i32 = 10;
+
+// storage:
+// key: block hash, value: binary of Block, removed once pruned
+// key: "LAST", value: hash of the tip
+// key: "PRUNE", value: big endian number of block bodies kept, set in pruned mode
+// key: "PRUNED", value: big endian height of the highest pruned block
+// tree "headers": key: block hash, value: binary of BlockHeader
+// tree "undo": key: block hash, value: binary of the UtxoRecords the block spent
+#[derive(Debug, Clone)]
 pub struct Blockchain {
     db: sled::Db,
     current_hash: String,
@@ -16,15 +27,33 @@ impl Blockchain {
     pub fn new() -> Result<Blockchain> {
         let db = sled::open("data/blocks")?;
         match db.get("LAST")? {
-            Some(hash) => {
-                Ok(Blockchain{db, current_hash: String::from_utf8(hash.to_vec())?})
-            }
+            Some(hash) => Blockchain::wrap(db, &hash),
             None => {
                 panic!("must create a new blockchain first")
             }
         }
     }
 
+    // the local chain, None before any block is known
+    pub fn open() -> Result<Option<Blockchain>> {
+        let db = sled::open("data/blocks")?;
+        match db.get("LAST")? {
+            Some(hash) => Ok(Some(Blockchain::wrap(db, &hash)?)),
+            None => Ok(None),
+        }
+    }
+
+    // the chain ending at hash, refusing blocks stored in an older format
+    fn wrap(db: sled::Db, hash: &[u8]) -> Result<Blockchain> {
+        let current_hash = String::from_utf8(hash.to_vec())?;
+        if let Some(value) = db.get(&current_hash)? {
+            if bincode::deserialize::<Block>(&value).is_err() {
+                return Err(format_err!("data/blocks was written by an older version: remove data/ and create or sync the chain again"));
+            }
+        }
+        Ok(Blockchain{db, current_hash})
+    }
+
     pub fn create_blockchain(address: String) -> Result<Blockchain> {
         info!("Creating new blockchain at address {}", address);
         let path = "data/blocks";
@@ -35,44 +64,249 @@ impl Blockchain {
         let db = sled::open("data/blocks")?;
         let coinbase = Transaction::new_coinbase(address, String::from("Genesis Block"))?;
         let block = Block::new_genesis_block(coinbase);
-        db.insert(block.get_hash(), bincode::serialize(&block)?)?;
-        db.insert("LAST", block.get_hash().as_bytes())?;
-        db.flush()?;
-        Ok(Blockchain{db, current_hash: block.get_hash()})
+        let mut bc = Blockchain{db, current_hash: String::new()};
+        bc.store_block(&block)?;
+        Ok(bc)
+    }
+
+    // start the chain from the genesis block of a peer
+    pub fn create_from_genesis(block: &Block) -> Result<Blockchain> {
+        Blockchain::check_genesis(block)?;
+        let db = sled::open("data/blocks")?;
+        let mut bc = Blockchain{db, current_hash: String::new()};
+        bc.store_block(block)?;
+        Ok(bc)
+    }
+
+    // the local chain, or a new one started from genesis when there is none;
+    // true if it was created
+    pub fn open_or_create_from_genesis(genesis: &Block) -> Result<(Blockchain, bool)> {
+        let db = sled::open("data/blocks")?;
+        if let Some(hash) = db.get("LAST")? {
+            return Ok((Blockchain::wrap(db, &hash)?, false));
+        }
+        Blockchain::check_genesis(genesis)?;
+        let mut bc = Blockchain{db, current_hash: String::new()};
+        bc.store_block(genesis)?;
+        Ok((bc, true))
     }
 
+    fn check_genesis(block: &Block) -> Result<()> {
+        if block.get_height() != 0 || !block.get_prev_hash().is_empty() {
+            return Err(BlockError::NotGenesis(block.get_hash()).into());
+        }
+        // the genesis block only holds the coinbase of the first reward
+        match block.get_transactions().as_slice() {
+            [coinbase] if coinbase.is_coinbase() => {
+                coinbase.check_id()?;
+                for out in &coinbase.vout {
+                    out.check()?;
+                }
+                let value = coinbase.output_value()?;
+                if value > SUBSIDY as i64 {
+                    return Err(TxError::CoinbaseValue { value, allowed: SUBSIDY as i64 }.into());
+                }
+            }
+            _ => return Err(BlockError::GenesisCoinbase(block.get_hash()).into()),
+        }
+        block.get_header()?.check_pow()
+    }
+
+    // mine a block of transactions already checked against the UTXO set
     pub fn add_block(&mut self, data: Vec<Transaction>) -> Result<Block>{
-        // verify transaction first
-        for tx in &data {
-            if !self.verify_transaction(tx)? {
-                return Err(format_err!("ERROR: Invalid transaction"));
+        let new_block = Block::new_block(data, self.current_hash.clone(), self.get_best_height()?+1)?;
+        self.store_block(&new_block)?;
+        Ok(new_block)
+    }
+
+    // append a block mined by a peer on top of the tip, only checking it extends the
+    // tip with valid proof of work: its transactions are checked against the UTXO set,
+    // or vouched for by a UTXO snapshot
+    pub fn connect_block(&mut self, block: &Block) -> Result<()> {
+        self.check_extends_tip(block)?;
+        self.store_block(block)
+    }
+
+    fn check_extends_tip(&self, block: &Block) -> Result<()> {
+        if block.get_prev_hash() != self.current_hash {
+            return Err(BlockError::NotOnTip { hash: block.get_hash(), tip: self.current_hash.clone() }.into());
+        }
+        let height = self.get_best_height()? + 1;
+        if block.get_height() != height {
+            return Err(BlockError::WrongHeight { hash: block.get_hash(), height: block.get_height(), expected: height }.into());
+        }
+        block.get_header()?.check_pow()
+    }
+
+    fn store_block(&mut self, block: &Block) -> Result<()> {
+        self.db.insert(block.get_hash(), bincode::serialize(block)?)?;
+        self.db.open_tree("headers")?.insert(block.get_hash(), bincode::serialize(&block.get_header()?)?)?;
+        self.db.insert("LAST", block.get_hash().as_bytes())?;
+        self.db.flush()?;
+        self.current_hash = block.get_hash();
+        let pruned = self.prune()?;
+        if pruned > 0 {
+            info!("pruned {} blocks", pruned);
+        }
+        Ok(())
+    }
+
+    // drop the tip block with its undo data, the chain ending at its parent
+    pub fn remove_tip(&mut self) -> Result<Block> {
+        let block = self.get_block(&self.current_hash)?
+            .ok_or_else(|| format_err!("the body of tip block {} is pruned", self.current_hash))?;
+        if block.get_height() == 0 {
+            return Err(format_err!("can't disconnect the genesis block"));
+        }
+        self.db.remove(&self.current_hash)?;
+        self.db.open_tree("headers")?.remove(&self.current_hash)?;
+        self.db.open_tree("undo")?.remove(&self.current_hash)?;
+        self.db.insert("LAST", block.get_prev_hash().as_bytes())?;
+        self.db.flush()?;
+        self.current_hash = block.get_prev_hash();
+        Ok(block)
+    }
+
+    // switch to pruned mode, keeping the bodies and undo data of the last keep blocks
+    // only; returns how many blocks were pruned
+    pub fn set_prune(&mut self, keep: i32) -> Result<usize> {
+        if keep < MIN_BLOCKS_TO_KEEP {
+            return Err(format_err!("must keep at least {} blocks so they can be disconnected", MIN_BLOCKS_TO_KEEP));
+        }
+        self.db.insert("PRUNE", &keep.to_be_bytes())?;
+        self.prune()
+    }
+
+    // number of block bodies kept, None unless in pruned mode
+    pub fn get_prune_depth(&self) -> Result<Option<i32>> {
+        self.get_height_key("PRUNE")
+    }
+
+    // height of the highest pruned block, None while every body is kept
+    pub fn get_pruned_height(&self) -> Result<Option<i32>> {
+        self.get_height_key("PRUNED")
+    }
+
+    fn get_height_key(&self, key: &str) -> Result<Option<i32>> {
+        match self.db.get(key)? {
+            Some(value) => Ok(Some(i32::from_be_bytes(value.as_ref().try_into()?))),
+            None => Ok(None),
+        }
+    }
+
+    // number of block bodies stored and their size in bytes
+    pub fn get_body_usage(&self) -> Result<(usize, u64)> {
+        let mut count = 0;
+        let mut size = 0;
+        for kv in self.db.iter() {
+            let (key, value) = kv?;
+            if ![&b"LAST"[..], b"PRUNE", b"PRUNED"].contains(&&key[..]) {
+                count += 1;
+                size += value.len() as u64;
+            }
+        }
+        Ok((count, size))
+    }
+
+    // fail when the bodies of the whole chain are needed but some are pruned
+    pub fn check_unpruned(&self, what: &str) -> Result<()> {
+        match self.get_pruned_height()? {
+            Some(height) => Err(format_err!("{} needs every block, but the blocks up to height {} are pruned", what, height)),
+            None => Ok(()),
+        }
+    }
+
+    // remove the bodies and undo data of the blocks deeper than the prune depth,
+    // keeping their headers
+    fn prune(&self) -> Result<usize> {
+        let keep = match self.get_prune_depth()? {
+            Some(keep) => keep,
+            None => return Ok(0),
+        };
+        let pruned_height = self.get_pruned_height()?.unwrap_or(-1);
+        let last = self.get_best_height()? - keep;
+        if last <= pruned_height {
+            return Ok(0);
+        }
+        let headers = self.db.open_tree("headers")?;
+        let undo = self.db.open_tree("undo")?;
+        let mut pruned = 0;
+        for header in self.headers().skip(keep as usize) {
+            if header.height <= pruned_height {
+                break;
+            }
+            headers.insert(header.hash.as_bytes(), bincode::serialize(&header)?)?;
+            undo.remove(header.hash.as_bytes())?;
+            if self.db.remove(header.hash.as_bytes())?.is_some() {
+                pruned += 1;
             }
         }
+        self.db.insert("PRUNED", &last.to_be_bytes())?;
+        self.db.flush()?;
+        Ok(pruned)
+    }
 
-        let value = self.db.get("LAST")?.unwrap();
-        let current_hash = String::from_utf8(value.to_vec())?;
-        let value = self.db.get(&current_hash)?.unwrap();
-        let block = bincode::deserialize::<Block>(&value)?;
+    // the outputs spent by a block, restored when it is disconnected
+    pub fn put_undo(&self, hash: &str, spent: &[UtxoRecord]) -> Result<()> {
+        self.db.open_tree("undo")?.insert(hash, bincode::serialize(spent)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
 
-        let new_block = Block::new_block(data, current_hash.clone(), block.get_height()+1)?;
-        self.db.insert(new_block.get_hash(), bincode::serialize(&new_block)?)?;
-        self.db.insert("LAST", new_block.get_hash().as_bytes())?;
-        self.current_hash = new_block.get_hash();
+    pub fn get_undo(&self, hash: &str) -> Result<Option<Vec<UtxoRecord>>> {
+        match self.db.open_tree("undo")?.get(hash)? {
+            Some(value) => Ok(Some(bincode::deserialize(&value)?)),
+            None => Ok(None),
+        }
+    }
 
-        Ok(new_block)
+    pub fn get_block(&self, hash: &str) -> Result<Option<Block>> {
+        match self.db.get(hash)? {
+            Some(value) => Ok(Some(bincode::deserialize(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    // the header of a block of the chain, pruned or not
+    pub fn get_header(&self, hash: &str) -> Result<Option<BlockHeader>> {
+        if let Some(value) = self.db.open_tree("headers")?.get(hash)? {
+            return Ok(Some(bincode::deserialize(&value)?));
+        }
+        // chains stored before headers were kept apart
+        match self.get_block(hash)? {
+            Some(block) => Ok(Some(block.get_header()?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_tip_hash(&self) -> String {
+        self.current_hash.clone()
+    }
+
+    // hashes of the blocks of the chain, indexed by height
+    pub fn get_block_hashes(&self) -> Vec<String> {
+        let mut hashes: Vec<String> = self.headers().map(|header| header.hash).collect();
+        hashes.reverse();
+        hashes
     }
 
-    pub fn find_all_utxos(&self) -> HashMap<String,TxOutputs> {
-        let mut utxos = HashMap::new();
+    pub fn find_all_utxos(&self) -> HashMap<String, Vec<(usize, UtxoEntry)>> {
+        let mut utxos = HashMap::<String, Vec<(usize, UtxoEntry)>>::new();
         let mut spent_utxos = HashMap::<String,Vec<i32>>::new();
 
         for block in self.iter() {
-            for tx in block.get_transactions() {
+            // newest first, so a spend in the block is seen before the output it spends
+            for tx in block.get_transactions().iter().rev() {
                 for (idx,item) in tx.vout.iter().enumerate() {
+                    if item.is_data() {
+                        continue;
+                    }
                     if !spent_utxos.contains_key(&tx.id) || !spent_utxos[&tx.id].contains(&(idx as i32)){
-                        utxos.insert(tx.id.clone(), TxOutputs{
-                            outputs: vec![item.clone()],
-                        });
+                        utxos.entry(tx.id.clone()).or_default().push((idx, UtxoEntry {
+                            output: item.clone(),
+                            height: block.get_height(),
+                            coinbase: tx.is_coinbase(),
+                        }));
                     }
                 }
 
@@ -122,35 +356,50 @@ impl Blockchain {
     //     utxos
     // }
 
-    pub fn iter(&self) -> BlockChainIterator {
+    pub fn get_best_height(&self) -> Result<i32> {
+        let header = self.get_header(&self.current_hash)?
+            .ok_or_else(|| format_err!("can't find tip block {}", self.current_hash))?;
+        Ok(header.height)
+    }
+
+    // blocks from the tip down, ending at the first pruned one
+    pub fn iter(&self) -> BlockChainIterator<'_> {
         BlockChainIterator {
-            chain: &self,
+            chain: self,
             current_hash: self.current_hash.clone(),
         }
     }
 
-    pub fn sign_transaction(&self, tx: &mut Transaction, private_key: &[u8]) ->Result<()> {
-        let prev_txs = self.get_prev_txs(tx)?;
-        tx.sign(private_key, prev_txs)?;
-        Ok(())
+    // headers from the tip down to the genesis, pruned blocks included
+    pub fn headers(&self) -> HeaderIterator<'_> {
+        HeaderIterator {
+            chain: self,
+            current_hash: self.current_hash.clone(),
+        }
     }
 
-    pub fn verify_transaction(&self, tx: &Transaction) -> Result<bool> {
-        let prev_txs = self.get_prev_txs(tx)?;
-        tx.verify(prev_txs)
+    // every pub_key_hash that ever received an output
+    pub fn find_used_pub_key_hashes(&self) -> HashSet<Vec<u8>> {
+        let mut used = HashSet::new();
+        for block in self.iter() {
+            for tx in block.get_transactions() {
+                for out in tx.vout.iter().filter(|out| !out.is_data()) {
+                    used.insert(out.pub_key_hash.clone());
+                }
+            }
+        }
+        used
     }
 
-    fn get_prev_txs(&self, tx: &Transaction) -> Result<HashMap<String,Transaction>> {
-        let mut txs = HashMap::<String,Transaction>::new();
-        let ids: HashSet<String> = tx.vin.iter().map(|vin| vin.txid.clone()).collect();
+    pub fn find_transaction(&self, id: &str) -> Option<Transaction> {
         for block in self.iter() {
             for transaction in block.get_transactions() {
-                if ids.contains(&transaction.id) {
-                    txs.insert(transaction.id.clone(), transaction.clone());
+                if transaction.id == id {
+                    return Some(transaction.clone());
                 }
             }
         }
-        Ok(txs)
+        None
     }
 }
 
@@ -164,10 +413,33 @@ impl<'a> Iterator for BlockChainIterator<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         // get block using iter.current_hash
         match self.chain.db.get(&self.current_hash) {
-            Ok(Some(value)) => {
-                let block = bincode::deserialize::<Block>(&value).unwrap();
-                self.current_hash = block.get_prev_hash();
-                Some(block)
+            Ok(Some(value)) => match bincode::deserialize::<Block>(&value) {
+                Ok(block) => {
+                    self.current_hash = block.get_prev_hash();
+                    Some(block)
+                }
+                Err(e) => {
+                    error!("can't read block {}: {}", self.current_hash, e);
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+}
+
+pub struct HeaderIterator<'a> {
+    chain: &'a Blockchain,
+    current_hash: String,
+}
+impl<'a> Iterator for HeaderIterator<'a> {
+    type Item = BlockHeader;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.chain.get_header(&self.current_hash) {
+            Ok(Some(header)) => {
+                self.current_hash = header.prev_block_hash.clone();
+                Some(header)
             }
             _ => None,
         }
@@ -176,6 +448,126 @@ impl<'a> Iterator for BlockChainIterator<'a> {
 
 #[cfg(test)]
 mod tests {
-    //use super::*;
+    use super::*;
+    use crate::coinselect::LargestFirst;
+    use crate::filters::FilterIndex;
+    use crate::headers::HeaderChain;
+    use crate::mempool::Mempool;
+    use crate::testutil::{enter_temp_dir, mine, new_chain, spend, tip_reward, utxo_bytes, wallet};
+    use crate::transaction::SendOptions;
+    use crate::utxoset::UTXOSet;
+
+    #[test]
+    fn disconnect_restores_the_spent_outputs() {
+        let _dir = enter_temp_dir();
+        let (miner, payee) = (wallet(1), wallet(2));
+        let mut utxo_set = new_chain(&miner);
+        let genesis = utxo_set.blockchain.get_tip_hash();
+        let before = utxo_bytes(&utxo_set);
+        let options = SendOptions {
+            selector: &LargestFirst,
+            fee_rate: 0,
+            min_conf: 1,
+            change_address: None,
+            data: None,
+            replaceable: false,
+            replaces: None,
+        };
+        let tx = Transaction::new_utxo(&miner, 30, &payee.get_address(), &utxo_set, &options).unwrap();
+        let block = utxo_set.add_block(vec![tx.clone()]).unwrap();
+        HeaderChain{}.catch_up(&utxo_set.blockchain).unwrap();
+        assert_ne!(utxo_bytes(&utxo_set), before);
+
+        let disconnected = utxo_set.disconnect_tip().unwrap();
+        assert_eq!(disconnected.get_hash(), block.get_hash());
+        assert_eq!(utxo_set.blockchain.get_tip_hash(), genesis);
+        assert_eq!(utxo_bytes(&utxo_set), before);
+        assert!(utxo_set.blockchain.get_block(&block.get_hash()).unwrap().is_none());
+        assert!(utxo_set.blockchain.get_undo(&block.get_hash()).unwrap().is_none());
+        assert!(FilterIndex{}.get(&block.get_hash()).unwrap().is_none());
+        assert_eq!(HeaderChain{}.get_best().unwrap().unwrap().hash, genesis);
+        assert!(Mempool{}.get_transaction(&tx.id).unwrap().is_some());
 
-}
\ No newline at end of file
+        assert!(utxo_set.disconnect_tip().is_err());
+        assert_eq!(utxo_set.blockchain.get_tip_hash(), genesis);
+        assert_eq!(utxo_bytes(&utxo_set), before);
+    }
+
+    #[test]
+    fn pruned_chain_disconnects_the_kept_blocks_only() {
+        let _dir = enter_temp_dir();
+        let miner = wallet(1);
+        let mut utxo_set = new_chain(&miner);
+        // the UTXO set at each height
+        let mut history = vec![utxo_bytes(&utxo_set)];
+        for _ in 0..13 {
+            mine(&mut utxo_set, &miner);
+            history.push(utxo_bytes(&utxo_set));
+        }
+        assert_eq!(utxo_set.blockchain.set_prune(MIN_BLOCKS_TO_KEEP).unwrap(), 4);
+        assert_eq!(utxo_set.blockchain.get_pruned_height().unwrap(), Some(3));
+
+        for height in (4..=13).rev() {
+            assert_eq!(utxo_set.disconnect_tip().unwrap().get_height(), height);
+            assert_eq!(utxo_bytes(&utxo_set), history[height as usize - 1]);
+        }
+        let tip = utxo_set.blockchain.get_tip_hash();
+        assert!(utxo_set.disconnect_tip().is_err());
+        assert_eq!(utxo_set.blockchain.get_tip_hash(), tip);
+        assert_eq!(utxo_set.blockchain.get_best_height().unwrap(), 3);
+        assert_eq!(utxo_bytes(&utxo_set), history[3]);
+    }
+
+    // a spends the genesis reward, b spends the first output of a and c the first of b
+    fn dependent_chain(utxo_set: &UTXOSet) -> Vec<Transaction> {
+        let (miner, payee, other) = (wallet(1), wallet(2), wallet(3));
+        let a = spend(&miner, &[(tip_reward(utxo_set), 0)], &[(payee.get_address(), 60), (miner.get_address(), 40)], false);
+        let b = spend(&payee, &[(a.id.clone(), 0)], &[(other.get_address(), 60)], false);
+        let c = spend(&other, &[(b.id.clone(), 0)], &[(payee.get_address(), 25), (other.get_address(), 35)], false);
+        vec![a, b, c]
+    }
+
+    #[test]
+    fn block_spends_outputs_of_its_earlier_transactions() {
+        let _dir = enter_temp_dir();
+        let miner = wallet(1);
+        let mut utxo_set = new_chain(&miner);
+        let before = utxo_bytes(&utxo_set);
+        let txs = dependent_chain(&utxo_set);
+        let block = utxo_set.add_block(txs.clone()).unwrap();
+
+        let mut outpoints: Vec<(String, i32)> = utxo_set.get_entries().unwrap().into_iter()
+            .map(|(txid, vout, _)| (txid, vout))
+            .collect();
+        outpoints.sort();
+        let mut expected = vec![(txs[0].id.clone(), 1), (txs[2].id.clone(), 0), (txs[2].id.clone(), 1)];
+        expected.sort();
+        assert_eq!(outpoints, expected);
+        // only the output from before the block is kept to undo it
+        let undo = utxo_set.blockchain.get_undo(&block.get_hash()).unwrap().unwrap();
+        let undone: Vec<(String, i32)> = undo.into_iter().map(|(txid, vout, _)| (txid, vout)).collect();
+        assert_eq!(undone, vec![(txs[0].vin[0].txid.clone(), 0)]);
+
+        utxo_set.disconnect_tip().unwrap();
+        assert_eq!(utxo_bytes(&utxo_set), before);
+        for tx in &txs {
+            assert!(Mempool{}.get_transaction(&tx.id).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn block_spending_an_output_before_its_transaction_is_rejected() {
+        let _dir = enter_temp_dir();
+        let miner = wallet(1);
+        let mut utxo_set = new_chain(&miner);
+        let (tip, before) = (utxo_set.blockchain.get_tip_hash(), utxo_bytes(&utxo_set));
+        let txs = dependent_chain(&utxo_set);
+        for order in [[1, 0, 2], [0, 2, 1]] {
+            let shuffled: Vec<Transaction> = order.iter().map(|&idx| txs[idx].clone()).collect();
+            let err = utxo_set.add_block(shuffled).unwrap_err();
+            assert!(matches!(err.downcast_ref::<TxError>(), Some(TxError::UnknownOutput { .. })), "{}", err);
+        }
+        assert_eq!(utxo_set.blockchain.get_tip_hash(), tip);
+        assert_eq!(utxo_bytes(&utxo_set), before);
+    }
+}
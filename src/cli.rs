@@ -1,15 +1,43 @@
+use std::collections::{BTreeMap, HashSet};
 use bitcoincash_addr::Address;
+use crate::block::Block;
 use crate::blockchain::Blockchain;
+use crate::coinselect::{selector_by_name, LargestFirst};
+use crate::history::list_transactions;
+use crate::headers::HeaderChain;
+use crate::light::{LightClient, SpvWallet};
 use crate::errors::Result;
-use clap::{arg, Command};
-use crate::transaction::Transaction;
-use crate::utxoset::UTXOSet;
-use crate::wallet::WalletManager;
+use crate::explorer::Explorer;
+use clap::builder::PossibleValuesParser;
+use clap::{arg, Arg, ArgAction, Command};
+use failure::format_err;
+use serde::Deserialize;
+use crate::mempool::{self, Mempool};
+use crate::psbt::Psbt;
+use crate::peers::DEFAULT_BAN_SECONDS;
+use crate::server::{self, Message, Server};
+use crate::snapshot;
+use crate::transaction::{SendOptions, Transaction};
+use crate::utxoset::{Balance, UTXOSet};
+use crate::wallet::{Wallet, WalletBackup, WalletManager};
 
 pub struct Cli {
 
 }
 
+#[derive(Deserialize)]
+struct RawInput {
+    txid: String,
+    vout: i32,
+}
+
+#[derive(Deserialize)]
+struct RawPrevOut {
+    txid: String,
+    vout: i32,
+    address: String,
+}
+
 impl Cli {
     pub fn new() -> Result<Self> {
         Ok(Self {})
@@ -20,25 +48,143 @@ impl Cli {
             .version("0.1.0")
             .about("a simple blockchain for learning")
             .subcommand(
-                Command::new("printchain").about("print all the block chain")
+                Command::new("printchain").about("print the blocks of the chain, newest first")
+                    .arg(arg!(--format <FORMAT>"'json, table or text'").default_value("text")
+                        .value_parser(PossibleValuesParser::new(["json", "table", "text"])))
+                    .arg(arg!(--from <HEIGHT>"'lowest height to print, the first block kept when omitted'"))
+                    .arg(arg!(--to <HEIGHT>"'highest height to print, the tip when omitted'"))
             )
             .subcommand(
                 Command::new("getbalance").about("get balance in the chain")
-                    .arg(arg!(<ADDRESS>"'the address of the balance'")))
+                    .arg(arg!([ADDRESS]"'the address of the balance, every wallet address when omitted or *'"))
+                    .arg(arg!([MINCONF]"'confirmations for coins to count as confirmed, 1 by default'")))
             .subcommand(Command::new("create").about("create new chain")
                 .arg(arg!(<ADDRESS>"'the address to send the genesis block to'")))
             .subcommand(Command::new("transfer").about("transfer in the chain")
                 .arg(arg!(<FROM>"'the address to send the transaction from'").required(true))
-                .arg(arg!(<TO>"'the address to send the transaction to'").required(true))
-                .arg(arg!(<AMOUNT>"'the amount of the transaction'").required(true)))
+                .arg(arg!(<TO>"'the address, contact name or label to send the transaction to'").required(true))
+                .arg(arg!(<AMOUNT>"'the amount of the transaction'").required(true))
+                .args(send_args()))
+            .subcommand(Command::new("sendmany").about("pay several addresses in one transaction")
+                .arg(arg!(<FROM>"'the address to send the transaction from'"))
+                .arg(arg!(<RECIPIENTS>"'the payments as address:amount, a contact name or label can replace the address'").num_args(1..))
+                .arg(arg!(--change <ADDRESS>"'the address receiving the change, the sender by default'"))
+                .arg(arg!(--data <DATA>"'text attached to the transaction in an unspendable output'"))
+                .args(send_args()))
+            .subcommand(Command::new("createrawtransaction").about("create an unsigned transaction")
+                .arg(arg!(<INPUTS>"'json array of inputs: [{\"txid\":\"..\",\"vout\":0}]'"))
+                .arg(arg!(<OUTPUTS>"'json object of outputs: {\"address\":amount}'")))
+            .subcommand(Command::new("signrawtransaction").about("sign a hex encoded transaction")
+                .arg(arg!(<HEX>"'the hex encoded transaction'"))
+                .arg(arg!(--privkey <KEY>"'hex encoded private key to sign with instead of the local wallets'"))
+                .arg(arg!(--prevouts <PREVOUTS>"'json array of spent outputs: [{\"txid\":\"..\",\"vout\":0,\"address\":\"..\"}]'")))
+            .subcommand(Command::new("decoderawtransaction").about("decode a hex encoded transaction")
+                .arg(arg!(<HEX>"'the hex encoded transaction'")))
+            .subcommand(Command::new("sendrawtransaction").about("validate a signed transaction and mine it")
+                .arg(arg!(<HEX>"'the hex encoded transaction'"))
+                .arg(arg!(--nomine "'leave the transaction in the mempool instead of mining it'"))
+                .arg(arg!(--node <ADDRESS>"'hand the transaction to the running node at this address, which relays it'")))
+            .subcommand(Command::new("mine").about("mine the pending transactions into a new block")
+                .arg(arg!(<ADDRESS>"'the address receiving the block reward and fees'"))
+                .arg(arg!(--node <ADDRESS>"'ask the running node at this address to mine and relay the block'")))
+            .subcommand(Command::new("startnode").about("run a node catching up with its peers and serving its chain")
+                .arg(arg!(<PORT>"'the port to listen on'"))
+                .arg(arg!(--connect <ADDRESS>"'address of a peer, such as localhost:3000, may be repeated'")
+                    .action(ArgAction::Append))
+                .arg(arg!(--explorer <ADDRESS>"'serve the block explorer at this address, such as localhost:8080'")))
+            .subcommand(Command::new("getpeerinfo").about("list the peers known to a running node")
+                .arg(arg!(--node <ADDRESS>"'address of the node'").default_value("localhost:3000")))
+            .subcommand(Command::new("setban").about("ban a peer address or host from a running node")
+                .arg(arg!(<ADDRESS>"'the peer address, or a bare host to ban all its ports'"))
+                .arg(arg!([SECONDS]"'duration of the ban, 0 lifts it, one day by default'"))
+                .arg(arg!(--node <ADDRESS>"'address of the node'").default_value("localhost:3000")))
+            .subcommand(Command::new("scanfilters").about("find the coins of addresses through the block filters of a running node")
+                .arg(arg!(<ADDRESS>"'the addresses to look for'").num_args(1..))
+                .arg(arg!(--node <ADDRESS>"'address of the node'").default_value("localhost:3000")))
+            .subcommand(Command::new("spvsync").about("sync the headers of a running node and verify the payments to the wallet by merkle proofs, keeping no blocks")
+                .arg(arg!(--node <ADDRESS>"'address of the node'").default_value("localhost:3000")))
+            .subcommand(Command::new("spvbalance").about("list the coins of the wallet found by spvsync"))
+            .subcommand(Command::new("listmempool").about("list the pending transactions"))
+            .subcommand(Command::new("bumpfee").about("replace a pending replaceable transaction by one paying a higher fee")
+                .arg(arg!(<TXID>"'the id of the pending transaction'"))
+                .arg(arg!(--feerate <FEERATE>"'new fee per 1000 bytes, twice the current rate plus one by default'")))
+            .subcommand(Command::new("createpsbt").about("wrap an unsigned transaction with its spent outputs")
+                .arg(arg!(<HEX>"'the hex encoded unsigned transaction'")))
+            .subcommand(Command::new("signpsbt").about("add signatures to a psbt")
+                .arg(arg!(<PSBT>"'the hex encoded psbt'"))
+                .arg(arg!(--privkey <KEY>"'hex encoded private key to sign with instead of the local wallets'")))
+            .subcommand(Command::new("combinepsbt").about("merge the signatures of several psbt of the same transaction")
+                .arg(arg!(<PSBT>"'the hex encoded psbt'").num_args(1..)))
+            .subcommand(Command::new("finalizepsbt").about("finalize a psbt and extract the signed transaction")
+                .arg(arg!(<PSBT>"'the hex encoded psbt'")))
+            .subcommand(Command::new("decodepsbt").about("decode a hex encoded psbt")
+                .arg(arg!(<PSBT>"'the hex encoded psbt'")))
             .subcommand(Command::new("createwallet").about("create wallet"))
+            .subcommand(Command::new("encryptwallet").about("encrypt the wallet keys with a passphrase")
+                .arg(arg!(<PASSPHRASE>"'the passphrase protecting the wallet'")))
+            .subcommand(Command::new("walletpassphrase").about("unlock the wallet for signing")
+                .arg(arg!(<PASSPHRASE>"'the wallet passphrase'"))
+                .arg(arg!(<TIMEOUT>"'seconds to keep the wallet unlocked'")))
+            .subcommand(Command::new("walletlock").about("lock the wallet"))
+            .subcommand(Command::new("restorewallet").about("restore the hd wallet from its mnemonic")
+                .arg(arg!(<MNEMONIC>"'the mnemonic words, quoted'")))
+            .subcommand(Command::new("signmessage").about("sign a message with the key of an address")
+                .arg(arg!(<ADDRESS>"'the wallet address'"))
+                .arg(arg!(<MESSAGE>"'the message to sign'")))
+            .subcommand(Command::new("verifymessage").about("check a message was signed by the key of an address")
+                .arg(arg!(<ADDRESS>"'the address expected to have signed'"))
+                .arg(arg!(<SIGNATURE>"'the hex signature given by signmessage'"))
+                .arg(arg!(<MESSAGE>"'the signed message'")))
+            .subcommand(Command::new("dumpprivkey").about("print the hex encoded private key of an address")
+                .arg(arg!(<ADDRESS>"'the wallet address'")))
+            .subcommand(Command::new("importprivkey").about("add a private key to the wallet")
+                .arg(arg!(<KEY>"'the hex encoded private key'"))
+                .arg(arg!([RESCAN]"'rebuild the UTXO set and show the balance of the address, true by default'")))
+            .subcommand(Command::new("backupwallet").about("write every key of the wallet to a json file")
+                .arg(arg!(<FILE>"'the backup file'")))
+            .subcommand(Command::new("importwallet").about("import the keys of a backup file")
+                .arg(arg!(<FILE>"'the backup file'")))
+            .subcommand(Command::new("importaddress").about("watch an address without its private key")
+                .arg(arg!(<ADDRESS>"'the address to watch'"))
+                .arg(arg!([RESCAN]"'rebuild the UTXO set and show the balance of the address, true by default'")))
+            .subcommand(Command::new("importpubkey").about("watch the address of a public key")
+                .arg(arg!(<PUBKEY>"'the hex encoded public key'"))
+                .arg(arg!([RESCAN]"'rebuild the UTXO set and show the balance of the address, true by default'")))
+            .subcommand(Command::new("listtransactions").about("list the wallet transactions, most recent first")
+                .arg(arg!([ADDRESS]"'only the transactions of this address, every wallet address when omitted'"))
+                .arg(arg!([COUNT]"'the number of transactions to list, 10 by default'"))
+                .arg(arg!([SKIP]"'the number of most recent transactions to skip, 0 by default'")))
+            .subcommand(Command::new("setlabel").about("label a wallet address, an empty label removes it")
+                .arg(arg!(<ADDRESS>"'the wallet address'"))
+                .arg(arg!(<LABEL>"'the label'")))
+            .subcommand(Command::new("addcontact").about("add an external address to the address book")
+                .arg(arg!(<NAME>"'the contact name'"))
+                .arg(arg!(<ADDRESS>"'the contact address'")))
+            .subcommand(Command::new("removecontact").about("remove a contact from the address book")
+                .arg(arg!(<NAME>"'the contact name'")))
+            .subcommand(Command::new("listcontacts").about("list the address book"))
             .subcommand(Command::new("listaddresses").about("list addresses of the wallet"))
             .subcommand(Command::new("reindex").about("reindex wallet"))
             .subcommand(Command::new("printutxo").about("printutxo transactions"))
+            .subcommand(Command::new("dumpchain").about("write the blocks in height order to a file")
+                .arg(arg!(<FILE>"'the dump file'")))
+            .subcommand(Command::new("loadchain").about("connect the blocks of a dump after the local chain")
+                .arg(arg!(<FILE>"'the dump file'"))
+                .arg(arg!(--utxo <FILE>"'UTXO snapshot of the last block, skips replaying the transactions'")))
+            .subcommand(Command::new("dumputxo").about("write a snapshot of the UTXO set at the chain tip to a file")
+                .arg(arg!(<FILE>"'the snapshot file'")))
+            .subcommand(Command::new("loadutxo").about("replace the UTXO set by a snapshot taken at the chain tip")
+                .arg(arg!(<FILE>"'the snapshot file'")))
+            .subcommand(Command::new("prune").about("keep only the headers and the last blocks, from now on")
+                .arg(arg!(<BLOCKS>"'number of recent blocks to keep'")))
+            .subcommand(Command::new("disconnectblock").about("remove the tip block, restoring the outputs it spent"))
+            .subcommand(Command::new("diskusage").about("print the disk space used by each store"))
             .get_matches();
 
-        if let Some(_) = matches.subcommand_matches("printchain") {
-            self.cmd_print_chain()?;
+        if let Some(matches) = matches.subcommand_matches("printchain") {
+            let from = matches.get_one::<String>("from").map(|height| height.parse::<i32>()).transpose()?;
+            let to = matches.get_one::<String>("to").map(|height| height.parse::<i32>()).transpose()?;
+            self.cmd_print_chain(matches.get_one::<String>("format").unwrap(), from, to)?;
         }
         if let Some(matches) = matches.subcommand_matches("create") {
             if let Some(addr) = matches.get_one::<String>("ADDRESS") {
@@ -54,50 +200,480 @@ impl Cli {
             let to = matches.get_one::<String>("TO").unwrap();
             let amount = matches.get_one::<String>("AMOUNT").unwrap();
             let amount = amount.parse::<i32>()?;
+            let selector = selector_by_name(matches.get_one::<String>("strategy").unwrap())?;
+            let options = SendOptions {
+                selector: selector.as_ref(),
+                fee_rate: parse_fee_rate(matches.get_one::<String>("feerate").unwrap())?,
+                min_conf: matches.get_one::<String>("minconf").unwrap().parse::<i32>()?,
+                change_address: None,
+                data: None,
+                replaceable: matches.get_flag("replaceable"),
+                replaces: None,
+            };
             let bc = Blockchain::new()?;
             let mut utxo_set = UTXOSet {blockchain: bc};
-            let wm = WalletManager::new()?;
-            let wallet_from = wm.get_wallet(from.as_str()).unwrap();
-            let wallet_to = wm.get_wallet(to.as_str()).unwrap();
-            let tx = Transaction::new_utxo(wallet_from, amount, wallet_to, &utxo_set)?;
+            let mut wm = WalletManager::new()?;
+            let wallet_from = wm.get_wallet(&wm.resolve(from))
+                .ok_or_else(|| format_err!("no wallet for address {}", from))?;
+            let tx = Transaction::new_utxo(wallet_from, amount, &wm.resolve(to), &utxo_set, &options)?;
             // let cbtx = Transaction::new_coinbase(String::from(to), String::new())?;
-            let new_block = utxo_set.blockchain.add_block(vec![tx])?;
-            utxo_set.update(&new_block)?;
+            let change = change_vout(&tx, 1);
+            let txid = tx.id.clone();
+            self.submit(&mut utxo_set, tx, !matches.get_flag("nomine"))?;
+            record_change(&mut wm, &txid, change)?;
             println!("Transferred amount {} from {} to {}", amount, *from, *to);
         }
+        if let Some(matches) = matches.subcommand_matches("sendmany") {
+            let from = matches.get_one::<String>("FROM").unwrap();
+            let mut recipients = Vec::new();
+            for item in matches.get_many::<String>("RECIPIENTS").unwrap() {
+                let (address, amount) = item.rsplit_once(':')
+                    .ok_or_else(|| format_err!("expect address:amount, got {}", item))?;
+                recipients.push((address.to_string(), amount.parse::<i32>()?));
+            }
+            let mut wm = WalletManager::new()?;
+            let recipients: Vec<(String, i32)> = recipients.into_iter()
+                .map(|(name, amount)| (wm.resolve(&name), amount))
+                .collect();
+            let change = matches.get_one::<String>("change").map(|name| wm.resolve(name));
+            let selector = selector_by_name(matches.get_one::<String>("strategy").unwrap())?;
+            let options = SendOptions {
+                selector: selector.as_ref(),
+                fee_rate: parse_fee_rate(matches.get_one::<String>("feerate").unwrap())?,
+                min_conf: matches.get_one::<String>("minconf").unwrap().parse::<i32>()?,
+                change_address: change.as_deref(),
+                data: matches.get_one::<String>("data").map(|s| s.as_bytes().to_vec()),
+                replaceable: matches.get_flag("replaceable"),
+                replaces: None,
+            };
+            let bc = Blockchain::new()?;
+            let mut utxo_set = UTXOSet {blockchain: bc};
+            let wallet_from = wm.get_wallet(&wm.resolve(from))
+                .ok_or_else(|| format_err!("no wallet for address {}", from))?;
+            let tx = Transaction::new_send_many(wallet_from, &recipients, &utxo_set, &options)?;
+            let change = change_vout(&tx, recipients.len() + usize::from(options.data.is_some()));
+            let txid = tx.id.clone();
+            self.submit(&mut utxo_set, tx, !matches.get_flag("nomine"))?;
+            record_change(&mut wm, &txid, change)?;
+            println!("Sent transaction {} from {} to {} recipients", txid, from, recipients.len());
+        }
         if let Some(matches) = matches.subcommand_matches("getbalance") {
-            if let Some(addr) = matches.get_one::<String>("ADDRESS") {
-                let pub_key_hash = Address::decode(addr).unwrap().body;
-                let bc = Blockchain::new()?;
-                // let utxos = bc.find_utxo(&pub_key_hash);
-                let utxo_set = UTXOSet{blockchain: bc};
-                let utxos = utxo_set.find_utxo(&pub_key_hash)?;
-                let balance:i32 = utxos.iter()
-                    .map(|item| item.get_value())
-                    .sum();
-                println!("UTXO {} balance is {}", addr, balance);
+            let min_conf = match matches.get_one::<String>("MINCONF") {
+                Some(min_conf) => min_conf.parse::<i32>()?,
+                None => 1,
+            };
+            let (addresses, wm) = match matches.get_one::<String>("ADDRESS") {
+                Some(addr) if addr != "*" => (vec![addr.clone()], None),
+                _ => {
+                    let wm = WalletManager::new()?;
+                    (wm.get_tracked_addresses(), Some(wm))
+                }
+            };
+            let utxo_set = UTXOSet{blockchain: Blockchain::new()?};
+            let pending = Mempool{}.get_transactions()?;
+            let mut total = Balance::default();
+            for addr in &addresses {
+                let pub_key_hash = Address::decode(addr)
+                    .map_err(|e| format_err!("invalid address {}: {:?}", addr, e))?.body;
+                let balance = utxo_set.get_balance(&pub_key_hash, min_conf, &pending)?;
+                let tag = match &wm {
+                    Some(wm) if wm.is_watch_only(addr) => " (watch-only)",
+                    _ => "",
+                };
+                println!("UTXO {}{} balance is {} (unconfirmed {}, immature {})",
+                         addr, tag, balance.confirmed, balance.unconfirmed, balance.immature);
+                total.confirmed += balance.confirmed;
+                total.unconfirmed += balance.unconfirmed;
+                total.immature += balance.immature;
+            }
+            if wm.is_some() {
+                println!("Wallet balance is {} (unconfirmed {}, immature {})",
+                         total.confirmed, total.unconfirmed, total.immature);
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("mine") {
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            let (block, fees) = match matches.get_one::<String>("node") {
+                Some(node) => match server::rpc(node, &Message::Mine(address.clone()))? {
+                    Message::Mined { block, fees } => (block, fees),
+                    msg => return Err(format_err!("unexpected reply from the node: {:?}", msg)),
+                },
+                None => UTXOSet{blockchain: Blockchain::new()?}.mine_pending(address)?,
+            };
+            println!("Mined block {} at height {} with {} transactions, fees {}",
+                     block.get_hash(), block.get_height(), block.get_transactions().len() - 1, fees);
+        }
+        if let Some(matches) = matches.subcommand_matches("startnode") {
+            let port = matches.get_one::<String>("PORT").unwrap();
+            let peers: Vec<String> = matches.get_many::<String>("connect")
+                .map(|peers| peers.cloned().collect())
+                .unwrap_or_default();
+            let server = Server::new(port, &peers)?;
+            if let Some(address) = matches.get_one::<String>("explorer") {
+                Explorer::new(server.clone()).spawn(address)?;
             }
+            server.start()?;
+        }
+        if let Some(matches) = matches.subcommand_matches("getpeerinfo") {
+            let node = matches.get_one::<String>("node").unwrap();
+            print_peers(server::rpc(node, &Message::GetPeerInfo)?)?;
+        }
+        if let Some(matches) = matches.subcommand_matches("scanfilters") {
+            let node = matches.get_one::<String>("node").unwrap();
+            let addresses: Vec<String> = matches.get_many::<String>("ADDRESS").unwrap().cloned().collect();
+            self.cmd_scan_filters(node, &addresses)?;
+        }
+        if let Some(matches) = matches.subcommand_matches("spvsync") {
+            self.cmd_spv_sync(matches.get_one::<String>("node").unwrap())?;
+        }
+        if matches.subcommand_matches("spvbalance").is_some() {
+            self.cmd_spv_balance()?;
+        }
+        if let Some(matches) = matches.subcommand_matches("setban") {
+            let node = matches.get_one::<String>("node").unwrap();
+            let seconds = match matches.get_one::<String>("SECONDS") {
+                Some(seconds) => seconds.parse::<u64>()?,
+                None => DEFAULT_BAN_SECONDS,
+            };
+            let addr = matches.get_one::<String>("ADDRESS").unwrap().clone();
+            print_peers(server::rpc(node, &Message::SetBan { addr, seconds })?)?;
+        }
+        if matches.subcommand_matches("listmempool").is_some() {
+            let utxo_set = UTXOSet{blockchain: Blockchain::new()?};
+            let pool = Mempool{}.load()?;
+            for tx in pool.values() {
+                let replaceable = if tx.replaceable { " replaceable" } else { "" };
+                println!("{} inputs:{} outputs:{} fee:{} ancestors:{} descendants:{}{}",
+                         tx.id, tx.vin.len(), tx.vout.len(), Mempool{}.get_fee(tx, &utxo_set)?,
+                         mempool::ancestors(&pool, tx).len(), mempool::descendants(&pool, &tx.id).len(), replaceable);
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("bumpfee") {
+            let fee_rate = match matches.get_one::<String>("feerate") {
+                Some(rate) => Some(parse_fee_rate(rate)? as i64),
+                None => None,
+            };
+            self.cmd_bump_fee(matches.get_one::<String>("TXID").unwrap(), fee_rate)?;
         }
 
-        if let Some(_) = matches.subcommand_matches("createwallet") {
+        if let Some(matches) = matches.subcommand_matches("createrawtransaction") {
+            let inputs = matches.get_one::<String>("INPUTS").unwrap();
+            let outputs = matches.get_one::<String>("OUTPUTS").unwrap();
+            self.cmd_create_raw_transaction(inputs, outputs)?;
+        }
+        if let Some(matches) = matches.subcommand_matches("signrawtransaction") {
+            let hex = matches.get_one::<String>("HEX").unwrap();
+            let privkey = matches.get_one::<String>("privkey");
+            let prevouts = matches.get_one::<String>("prevouts");
+            self.cmd_sign_raw_transaction(hex, privkey, prevouts)?;
+        }
+        if let Some(matches) = matches.subcommand_matches("decoderawtransaction") {
+            let hex = matches.get_one::<String>("HEX").unwrap();
+            let tx = Transaction::from_hex(hex)?;
+            println!("{}", serde_json::to_string_pretty(&tx.to_json())?);
+        }
+        if let Some(matches) = matches.subcommand_matches("sendrawtransaction") {
+            let hex = matches.get_one::<String>("HEX").unwrap();
+            let tx = Transaction::from_hex(hex)?;
+            if tx.is_coinbase() {
+                return Err(format_err!("coinbase transaction {} can only be mined", tx.id));
+            }
+            if !tx.is_fully_signed() {
+                return Err(format_err!("transaction {} is not fully signed", tx.id));
+            }
+            let txid = tx.id.clone();
+            if let Some(node) = matches.get_one::<String>("node") {
+                server::rpc(node, &Message::SendTx(tx))?;
+            } else {
+                let bc = Blockchain::new()?;
+                let mut utxo_set = UTXOSet{blockchain: bc};
+                self.submit(&mut utxo_set, tx, !matches.get_flag("nomine"))?;
+            }
+            println!("Sent transaction {}", txid);
+        }
+        if let Some(matches) = matches.subcommand_matches("createpsbt") {
+            let hex = matches.get_one::<String>("HEX").unwrap();
+            let tx = Transaction::from_hex(hex)?;
+            let utxo_set = UTXOSet{blockchain: Blockchain::new()?};
+            let mut spent_outputs = Vec::new();
+            for vin in &tx.vin {
+                let out = utxo_set.find_output(&vin.txid, vin.vout)?
+                    .ok_or_else(|| format_err!("output {}:{} is not in the UTXO set", vin.txid, vin.vout))?;
+                spent_outputs.push(out);
+            }
+            let psbt = Psbt::new(tx, spent_outputs)?;
+            println!("{}", psbt.to_hex()?);
+        }
+        if let Some(matches) = matches.subcommand_matches("signpsbt") {
+            let mut psbt = Psbt::from_hex(matches.get_one::<String>("PSBT").unwrap())?;
+            let mut signed = 0;
+            match matches.get_one::<String>("privkey") {
+                Some(key) => {
+                    let wallet = Wallet::from_private_key(&hex::decode(key)?)?;
+                    signed += psbt.sign(&wallet)?;
+                }
+                None => {
+                    let wm = WalletManager::new()?;
+                    for address in wm.get_all_addresses() {
+                        signed += psbt.sign(wm.get_wallet(&address).unwrap())?;
+                    }
+                }
+            }
+            println!("Added {} signatures", signed);
+            println!("{}", psbt.to_hex()?);
+        }
+        if let Some(matches) = matches.subcommand_matches("combinepsbt") {
+            let mut psbts = matches.get_many::<String>("PSBT").unwrap();
+            let mut psbt = Psbt::from_hex(psbts.next().unwrap())?;
+            for other in psbts {
+                psbt.combine(&Psbt::from_hex(other)?)?;
+            }
+            println!("{}", psbt.to_hex()?);
+        }
+        if let Some(matches) = matches.subcommand_matches("finalizepsbt") {
+            let mut psbt = Psbt::from_hex(matches.get_one::<String>("PSBT").unwrap())?;
+            if psbt.finalize()? {
+                println!("{}", psbt.extract()?.to_hex()?);
+            } else {
+                println!("Incomplete, still missing signatures");
+                println!("{}", psbt.to_hex()?);
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("decodepsbt") {
+            let psbt = Psbt::from_hex(matches.get_one::<String>("PSBT").unwrap())?;
+            let inputs: Vec<serde_json::Value> = psbt.inputs.iter().map(|input| serde_json::json!({
+                "value": input.spent_output.value,
+                "address": Wallet::address_of(input.spent_output.pub_key_hash.clone()),
+                "partial_sigs": input.partial_sigs.iter()
+                    .map(|(pub_key, sig)| (hex::encode(pub_key), serde_json::Value::from(hex::encode(sig))))
+                    .collect::<serde_json::Map<_, _>>(),
+            })).collect();
+            let json = serde_json::json!({
+                "tx": psbt.tx.to_json(),
+                "inputs": inputs,
+                "complete": psbt.is_finalized(),
+            });
+            println!("{}", serde_json::to_string_pretty(&json)?);
+        }
+        if matches.subcommand_matches("createwallet").is_some() {
+            let mut wm = WalletManager::new()?;
+            let new_seed = wm.get_hd_chain().is_none();
+            let address = wm.new_wallet()?;
+            wm.save_all()?;
+            if new_seed {
+                println!("Created hd seed, write down the mnemonic to restore the wallet:");
+                println!("{}", wm.get_hd_chain().unwrap().mnemonic()?);
+            }
+            let index = wm.get_hd_chain().unwrap().next_index - 1;
+            println!("Created wallet at {} (index {})", address, index);
+        }
+        if let Some(matches) = matches.subcommand_matches("restorewallet") {
+            let mnemonic = matches.get_one::<String>("MNEMONIC").unwrap();
+            let bc = Blockchain::new()?;
+            bc.check_unpruned("restorewallet")?;
+            let used = bc.find_used_pub_key_hashes();
+            let mut wm = WalletManager::new()?;
+            let restored = wm.restore(mnemonic, |pub_key_hash| used.contains(pub_key_hash))?;
+            wm.save_all()?;
+            println!("Restored {} used addresses {:?}", restored.len(), restored);
+        }
+        if let Some(matches) = matches.subcommand_matches("encryptwallet") {
+            let mut wm = WalletManager::new()?;
+            wm.encrypt(matches.get_one::<String>("PASSPHRASE").unwrap())?;
+            println!("Wallet encrypted, unlock it with walletpassphrase before sending");
+        }
+        if let Some(matches) = matches.subcommand_matches("walletpassphrase") {
+            let passphrase = matches.get_one::<String>("PASSPHRASE").unwrap();
+            let timeout = matches.get_one::<String>("TIMEOUT").unwrap().parse::<u64>()?;
+            let mut wm = WalletManager::new()?;
+            let token = wm.unlock(passphrase, timeout)?;
+            println!("Wallet unlocked for {} seconds, set the session in the shell that signs:", timeout);
+            println!("export {}={}", WalletManager::SESSION_VAR, token);
+        }
+        if matches.subcommand_matches("walletlock").is_some() {
+            let mut wm = WalletManager::new()?;
+            wm.lock()?;
+            println!("Wallet locked");
+        }
+        if let Some(matches) = matches.subcommand_matches("signmessage") {
+            let wm = WalletManager::new()?;
+            let address = wm.resolve(matches.get_one::<String>("ADDRESS").unwrap());
+            let wallet = wm.get_wallet(&address)
+                .ok_or_else(|| format_err!("no wallet for address {}", address))?;
+            println!("{}", wallet.sign_message(matches.get_one::<String>("MESSAGE").unwrap())?);
+        }
+        if let Some(matches) = matches.subcommand_matches("verifymessage") {
+            let wm = WalletManager::new()?;
+            let address = wm.resolve(matches.get_one::<String>("ADDRESS").unwrap());
+            let valid = Wallet::verify_message(&address,
+                matches.get_one::<String>("SIGNATURE").unwrap(),
+                matches.get_one::<String>("MESSAGE").unwrap())?;
+            println!("{}", valid);
+        }
+        if let Some(matches) = matches.subcommand_matches("dumpprivkey") {
+            let wm = WalletManager::new()?;
+            println!("{}", wm.dump_private_key(matches.get_one::<String>("ADDRESS").unwrap())?);
+        }
+        if let Some(matches) = matches.subcommand_matches("importprivkey") {
+            let rescan = parse_rescan(matches.get_one::<String>("RESCAN"))?;
+            let mut wm = WalletManager::new()?;
+            let address = wm.import_private_key(matches.get_one::<String>("KEY").unwrap())?;
+            wm.save_all()?;
+            println!("Imported wallet at {}", address);
+            if rescan {
+                self.rescan(&[address])?;
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("importaddress") {
+            let rescan = parse_rescan(matches.get_one::<String>("RESCAN"))?;
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            let mut wm = WalletManager::new()?;
+            wm.import_address(address)?;
+            wm.save_all()?;
+            println!("Watching {}", address);
+            if rescan {
+                self.rescan(std::slice::from_ref(address))?;
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("importpubkey") {
+            let rescan = parse_rescan(matches.get_one::<String>("RESCAN"))?;
+            let mut wm = WalletManager::new()?;
+            let address = wm.import_pub_key(matches.get_one::<String>("PUBKEY").unwrap())?;
+            wm.save_all()?;
+            println!("Watching {}", address);
+            if rescan {
+                self.rescan(&[address])?;
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("backupwallet") {
+            let file = matches.get_one::<String>("FILE").unwrap();
+            let wm = WalletManager::new()?;
+            let backup = wm.backup()?;
+            std::fs::write(file, serde_json::to_string_pretty(&backup)?)?;
+            println!("Wrote {} keys to {}", backup.keys.len(), file);
+        }
+        if let Some(matches) = matches.subcommand_matches("importwallet") {
+            let file = matches.get_one::<String>("FILE").unwrap();
+            let backup: WalletBackup = serde_json::from_str(&std::fs::read_to_string(file)?)?;
+            let mut wm = WalletManager::new()?;
+            let imported = wm.import_backup(&backup)?;
+            wm.save_all()?;
+            println!("Imported {} keys from {}", imported.len(), file);
+            self.rescan(&imported)?;
+        }
+        if let Some(matches) = matches.subcommand_matches("listtransactions") {
+            let wm = WalletManager::new()?;
+            let addresses = match matches.get_one::<String>("ADDRESS") {
+                Some(address) if address != "*" => vec![wm.resolve(address)],
+                _ => wm.get_tracked_addresses(),
+            };
+            let count = match matches.get_one::<String>("COUNT") {
+                Some(count) => count.parse::<usize>()?,
+                None => 10,
+            };
+            let skip = match matches.get_one::<String>("SKIP") {
+                Some(skip) => skip.parse::<usize>()?,
+                None => 0,
+            };
+            let mut tracked = HashSet::new();
+            for address in &addresses {
+                let address = Address::decode(address)
+                    .map_err(|e| format_err!("invalid address {}: {:?}", address, e))?;
+                tracked.insert(address.body);
+            }
+            let bc = Blockchain::new()?;
+            let mut history: Vec<_> = list_transactions(&bc, &tracked)?.into_iter().skip(skip).take(count).collect();
+            for tx in &mut history {
+                for address in tx.addresses.iter().chain(tx.counterparties.iter()) {
+                    if let Some(label) = wm.get_label(address) {
+                        tx.labels.insert(address.clone(), label.to_string());
+                    }
+                }
+            }
+            println!("{}", serde_json::to_string_pretty(&history)?);
+        }
+        if matches.subcommand_matches("listaddresses").is_some() {
+            let wm = WalletManager::new()?;
+            for address in wm.get_tracked_addresses() {
+                let label = wm.labels.get(&address).map(|label| format!(" \"{}\"", label)).unwrap_or_default();
+                let tag = if wm.is_watch_only(&address) { " (watch-only)" } else { "" };
+                println!("{}{}{}", address, label, tag);
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("setlabel") {
+            let mut wm = WalletManager::new()?;
+            let address = matches.get_one::<String>("ADDRESS").unwrap();
+            wm.set_label(address, matches.get_one::<String>("LABEL").unwrap())?;
+            wm.save_all()?;
+            println!("Labelled {}", address);
+        }
+        if let Some(matches) = matches.subcommand_matches("addcontact") {
+            let mut wm = WalletManager::new()?;
+            let name = matches.get_one::<String>("NAME").unwrap();
+            wm.add_contact(name, matches.get_one::<String>("ADDRESS").unwrap())?;
+            wm.save_all()?;
+            println!("Added contact {}", name);
+        }
+        if let Some(matches) = matches.subcommand_matches("removecontact") {
             let mut wm = WalletManager::new()?;
-            let address = wm.new_wallet();
+            let name = matches.get_one::<String>("NAME").unwrap();
+            wm.remove_contact(name)?;
             wm.save_all()?;
-            println!("Created wallet at {}", address);
+            println!("Removed contact {}", name);
         }
-        if let Some(_) = matches.subcommand_matches("listaddresses") {
+        if matches.subcommand_matches("listcontacts").is_some() {
             let wm = WalletManager::new()?;
-            let addresses = wm.get_all_addresses();
-            println!("Addresses {:?}", addresses);
+            let mut contacts: Vec<_> = wm.contacts.iter().collect();
+            contacts.sort();
+            for (name, address) in contacts {
+                println!("{}: {}", name, address);
+            }
         }
-        if let Some(_) = matches.subcommand_matches("reindex") {
+        if matches.subcommand_matches("reindex").is_some() {
             let bc = Blockchain::new()?;
             let utxo_set = UTXOSet{blockchain: bc};
             utxo_set.reindex()?;
             let count = utxo_set.count_transactions()?;
             println!("After reindex, there are {} transactions", count);
         }
-        if let Some(_) = matches.subcommand_matches("printutxo") {
+        if let Some(matches) = matches.subcommand_matches("dumpchain") {
+            let file = matches.get_one::<String>("FILE").unwrap();
+            let count = snapshot::dump_chain(&Blockchain::new()?, file)?;
+            println!("Wrote {} blocks to {}", count, file);
+        }
+        if let Some(matches) = matches.subcommand_matches("loadchain") {
+            let file = matches.get_one::<String>("FILE").unwrap();
+            let utxo = matches.get_one::<String>("utxo").map(|utxo| utxo.as_str());
+            let (loaded, skipped) = snapshot::load_chain(file, utxo)?;
+            println!("Loaded {} blocks from {}, {} already in the chain", loaded, file, skipped);
+        }
+        if let Some(matches) = matches.subcommand_matches("dumputxo") {
+            let file = matches.get_one::<String>("FILE").unwrap();
+            let utxo_set = UTXOSet{blockchain: Blockchain::new()?};
+            let snapshot = snapshot::dump_utxo(&utxo_set, file)?;
+            println!("Wrote {} outputs at block {} height {} to {}", snapshot.count, snapshot.block_hash, snapshot.height, file);
+        }
+        if let Some(matches) = matches.subcommand_matches("loadutxo") {
+            let file = matches.get_one::<String>("FILE").unwrap();
+            let utxo_set = UTXOSet{blockchain: Blockchain::new()?};
+            let snapshot = snapshot::load_utxo(&utxo_set, file)?;
+            println!("Loaded {} outputs at block {} height {}", snapshot.count, snapshot.block_hash, snapshot.height);
+        }
+        if let Some(matches) = matches.subcommand_matches("prune") {
+            let keep = matches.get_one::<String>("BLOCKS").unwrap().parse::<i32>()?;
+            let mut bc = Blockchain::new()?;
+            let pruned = bc.set_prune(keep)?;
+            println!("Pruned {} blocks, keeping the last {}", pruned, keep);
+        }
+        if matches.subcommand_matches("disconnectblock").is_some() {
+            let mut utxo_set = UTXOSet{blockchain: Blockchain::new()?};
+            let block = utxo_set.disconnect_tip()?;
+            println!("Disconnected block {} at height {}", block.get_hash(), block.get_height());
+        }
+        if matches.subcommand_matches("diskusage").is_some() {
+            self.cmd_disk_usage()?;
+        }
+        if matches.subcommand_matches("printutxo").is_some() {
             let bc = Blockchain::new()?;
             let utxo_set = UTXOSet{blockchain: bc};
             println!("{:?}", utxo_set);
@@ -105,11 +681,347 @@ impl Cli {
         Ok(())
     }
 
-    fn cmd_print_chain(&self) -> Result<()> {
+    // mine the transaction right away, or leave it to the next mine command
+    fn submit(&self, utxo_set: &mut UTXOSet, tx: Transaction, mine: bool) -> Result<()> {
+        if mine {
+            let block = utxo_set.add_block(vec![tx])?;
+            println!("Mined block {} at height {}", block.get_hash(), block.get_height());
+        } else {
+            Mempool{}.add(&tx, utxo_set)?;
+            println!("Added transaction {} to the mempool", tx.id);
+        }
+        Ok(())
+    }
+
+    // rebuild a pending transaction with the same inputs at a higher fee rate, its change
+    // output, recorded by the wallet when it was sent, shrinks to pay the difference
+    fn cmd_bump_fee(&self, txid: &str, fee_rate: Option<i64>) -> Result<()> {
+        let mut utxo_set = UTXOSet{blockchain: Blockchain::new()?};
+        let original = Mempool{}.get_transaction(txid)?
+            .ok_or_else(|| format_err!("transaction {} is not in the mempool", txid))?;
+        if !original.replaceable {
+            return Err(format_err!("transaction {} is not replaceable", txid));
+        }
+        let mut wm = WalletManager::new()?;
+        let mut sender_hash = original.vin[0].pub_key.clone();
+        Wallet::hash_pub_key(&mut sender_hash);
+        let sender = Wallet::address_of(sender_hash);
+        let wallet = wm.get_wallet(&sender)
+            .ok_or_else(|| format_err!("transaction {} was not sent by this wallet", txid))?;
+
+        let change = wm.change_outputs.get(txid).copied();
+        let mut change_address = None;
+        let mut recipients = Vec::new();
+        let mut data = None;
+        for (idx, out) in original.vout.iter().enumerate() {
+            let address = Wallet::address_of(out.pub_key_hash.clone());
+            if out.is_data() {
+                data = Some(out.pub_key_hash.clone());
+            } else if change == Some(idx as i32) {
+                change_address = Some(address);
+            } else {
+                recipients.push((address, out.value));
+            }
+        }
+        let old_fee = Mempool{}.get_fee(&original, &utxo_set)?;
+        let old_rate = mempool::fee_rate(old_fee, original.size()? as i64);
+        let new_rate = fee_rate.unwrap_or(old_rate * 2 + 1);
+        let options = SendOptions {
+            selector: &LargestFirst,
+            fee_rate: i32::try_from(new_rate)?,
+            min_conf: 1,
+            change_address: change_address.as_deref(),
+            data,
+            replaceable: true,
+            replaces: Some(&original),
+        };
+        let tx = Transaction::new_send_many(wallet, &recipients, &utxo_set, &options)?;
+        let new_fee = Mempool{}.get_fee(&tx, &utxo_set)?;
+        let new_change = change_vout(&tx, recipients.len() + usize::from(options.data.is_some()));
+        let new_txid = tx.id.clone();
+        self.submit(&mut utxo_set, tx, false)?;
+        wm.change_outputs.remove(txid);
+        if let Some(vout) = new_change {
+            wm.change_outputs.insert(new_txid.clone(), vout);
+        }
+        wm.save_all()?;
+        println!("Replaced transaction {} (fee {}) by {} (fee {})", txid, old_fee, new_txid, new_fee);
+        Ok(())
+    }
+
+    // rebuild the UTXO set from the chain and report what the addresses own
+    fn rescan(&self, addresses: &[String]) -> Result<()> {
+        let utxo_set = UTXOSet{blockchain: Blockchain::new()?};
+        utxo_set.reindex()?;
+        for address in addresses {
+            let pub_key_hash = Address::decode(address).unwrap().body;
+            let balance: i32 = utxo_set.find_utxo(&pub_key_hash)?.iter()
+                .map(|item| item.get_value())
+                .sum();
+            println!("Rescanned {}: balance {}", address, balance);
+        }
+        Ok(())
+    }
+
+    fn cmd_create_raw_transaction(&self, inputs: &str, outputs: &str) -> Result<()> {
+        let inputs: Vec<RawInput> = serde_json::from_str(inputs)?;
+        let outputs: BTreeMap<String, i32> = serde_json::from_str(outputs)?;
+        let inputs: Vec<(String, i32)> = inputs.into_iter().map(|i| (i.txid, i.vout)).collect();
+        let outputs: Vec<(String, i32)> = outputs.into_iter().collect();
+        let tx = Transaction::new_raw(&inputs, &outputs)?;
+        println!("{}", tx.to_hex()?);
+        Ok(())
+    }
+
+    fn cmd_sign_raw_transaction(&self, hex: &str, privkey: Option<&String>, prevouts: Option<&String>) -> Result<()> {
+        let mut tx = Transaction::from_hex(hex)?;
+        // the spent outputs come from the command line when signing offline, from the chain otherwise
+        let prev_pub_key_hashes = match prevouts {
+            Some(prevouts) => {
+                let prevouts: Vec<RawPrevOut> = serde_json::from_str(prevouts)?;
+                let mut hashes = Vec::new();
+                for vin in &tx.vin {
+                    let prevout = prevouts.iter()
+                        .find(|p| p.txid == vin.txid && p.vout == vin.vout)
+                        .ok_or_else(|| format_err!("missing prevout for {}:{}", vin.txid, vin.vout))?;
+                    let address = Address::decode(&prevout.address)
+                        .map_err(|e| format_err!("invalid address {}: {:?}", prevout.address, e))?;
+                    hashes.push(address.body);
+                }
+                hashes
+            }
+            None => {
+                let utxo_set = UTXOSet { blockchain: Blockchain::new()? };
+                utxo_set.find_prev_pub_key_hashes(&tx, &Mempool{}.load()?)?
+            }
+        };
+
+        let mut signed = 0;
+        match privkey {
+            Some(key) => {
+                let wallet = Wallet::from_private_key(&hex::decode(key)?)?;
+                signed += tx.sign_inputs(&wallet, &prev_pub_key_hashes)?;
+            }
+            None => {
+                let wm = WalletManager::new()?;
+                for pub_key_hash in prev_pub_key_hashes.iter().collect::<HashSet<_>>() {
+                    let address = Wallet::address_of(pub_key_hash.clone());
+                    if let Some(wallet) = wm.get_wallet(&address) {
+                        signed += tx.sign_inputs(wallet, &prev_pub_key_hashes)?;
+                    }
+                }
+            }
+        }
+        println!("Signed {} of {} inputs, complete: {}", signed, tx.vin.len(), tx.is_fully_signed());
+        println!("{}", tx.to_hex()?);
+        Ok(())
+    }
+
+    fn cmd_scan_filters(&self, node: &str, addresses: &[String]) -> Result<()> {
+        let mut pub_key_hashes = Vec::new();
+        for addr in addresses {
+            pub_key_hashes.push(Address::decode(addr)
+                .map_err(|e| format_err!("invalid address {}: {:?}", addr, e))?.body);
+        }
+        let scan = LightClient::new(node).scan(&pub_key_hashes)?;
+        for (header, relevant) in &scan.matched {
+            let note = if *relevant { "" } else { " (false positive)" };
+            println!("Block {} at height {} matches{}", header.hash, header.height, note);
+        }
+        for ((txid, vout), value) in &scan.utxos {
+            println!("Unspent {}:{} value {}", txid, vout, value);
+        }
+        println!("Downloaded {} of {} blocks, balance is {}",
+                 scan.matched.len(), scan.blocks, scan.utxos.values().map(|value| *value as i64).sum::<i64>());
+        Ok(())
+    }
+
+    fn cmd_spv_sync(&self, node: &str) -> Result<()> {
+        let wm = WalletManager::new()?;
+        let mut pub_key_hashes = Vec::new();
+        for addr in wm.get_tracked_addresses() {
+            pub_key_hashes.push(Address::decode(&addr)
+                .map_err(|e| format_err!("invalid address {}: {:?}", addr, e))?.body);
+        }
+        let update = LightClient::new(node).sync_spv(&pub_key_hashes)?;
+        for coin in &update.received {
+            println!("Received {} at {} in {}:{}, block {}, merkle proof verified",
+                     coin.value, Wallet::address_of(coin.pub_key_hash.clone()), coin.txid, coin.vout, coin.height);
+        }
+        for coin in &update.spent {
+            println!("Spent {} of {} from {}:{}",
+                     coin.value, Wallet::address_of(coin.pub_key_hash.clone()), coin.txid, coin.vout);
+        }
+        println!("Headers synced at height {}", HeaderChain{}.get_best_height()?);
+        self.cmd_spv_balance()
+    }
+
+    fn cmd_spv_balance(&self) -> Result<()> {
+        let best = HeaderChain{}.get_best_height()?;
+        let coins = SpvWallet{}.get_coins()?;
+        for coin in &coins {
+            println!("{}:{} {} at {} ({} confirmations)", coin.txid, coin.vout, coin.value,
+                     Wallet::address_of(coin.pub_key_hash.clone()), best - coin.height + 1);
+        }
+        println!("SPV balance is {}", coins.iter().map(|coin| coin.value as i64).sum::<i64>());
+        Ok(())
+    }
+
+    fn cmd_print_chain(&self, format: &str, from: Option<i32>, to: Option<i32>) -> Result<()> {
+        if let (Some(from), Some(to)) = (from, to) {
+            if from > to {
+                return Err(format_err!("--from {} is above --to {}", from, to));
+            }
+        }
         let blockchain = Blockchain::new()?;
-        for block in blockchain.iter() {
-            println!("{:#?}", block);
+        let first_kept = blockchain.get_pruned_height()?.map_or(0, |height| height + 1);
+        let from = from.unwrap_or(first_kept);
+        let to = to.unwrap_or(i32::MAX);
+        if from < first_kept {
+            return Err(format_err!("blocks up to height {} are pruned", first_kept - 1));
+        }
+        let blocks: Vec<Block> = blockchain.iter()
+            .skip_while(|block| block.get_height() > to)
+            .take_while(|block| block.get_height() >= from)
+            .collect();
+
+        match format {
+            "json" => {
+                let blocks = blocks.iter().map(|block| block.to_json()).collect::<Result<Vec<_>>>()?;
+                println!("{}", serde_json::to_string_pretty(&blocks)?);
+            }
+            "table" => {
+                println!("{:>6}  {:<64}  {:>4}  {:>7}  {:>9}  {:<34}  TIMESTAMP", "HEIGHT", "HASH", "TXS", "SIZE", "OUTPUT", "MINER");
+                for block in &blocks {
+                    println!("{:>6}  {:<64}  {:>4}  {:>7}  {:>9}  {:<34}  {}", block.get_height(), block.get_hash(),
+                             block.get_transactions().len(), block.size()?, block.total_output(),
+                             block.miner().unwrap_or_default(), block.get_timestamp());
+                }
+            }
+            _ => {
+                for block in &blocks {
+                    print_block(block)?;
+                }
+            }
         }
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn cmd_disk_usage(&self) -> Result<()> {
+        let mut total = 0;
+        let mut stores: Vec<_> = std::fs::read_dir("data")?.collect::<std::io::Result<_>>()?;
+        stores.sort_by_key(|entry| entry.file_name());
+        for entry in stores {
+            let size = dir_size(&entry.path())?;
+            total += size;
+            println!("{:<10} {:>12} bytes", entry.file_name().to_string_lossy(), size);
+        }
+        println!("{:<10} {:>12} bytes", "total", total);
+        let bc = Blockchain::new()?;
+        let (count, size) = bc.get_body_usage()?;
+        println!("{} block bodies take {} bytes", count, size);
+        match (bc.get_prune_depth()?, bc.get_pruned_height()?) {
+            (Some(keep), Some(height)) => println!("Pruned mode keeping {} blocks, blocks up to height {} are pruned", keep, height),
+            (Some(keep), None) => println!("Pruned mode keeping {} blocks, none pruned yet", keep),
+            (None, _) => println!("Every block is kept"),
+        }
+        Ok(())
+    }
+}
+
+fn send_args() -> Vec<Arg> {
+    vec![
+        arg!(--strategy <STRATEGY>"'coin selection strategy: largest, smallest, bnb or random'")
+            .default_value("largest"),
+        arg!(--feerate <FEERATE>"'fee per 1000 bytes of transaction'").default_value("0"),
+        arg!(--minconf <MINCONF>"'confirmations required for a coin to be spent'").default_value("1"),
+        arg!(--nomine "'leave the transaction in the mempool instead of mining it'"),
+        arg!(--replaceable "'allow bumpfee to replace the transaction while it is pending'"),
+    ]
+}
+
+// bumpfee tells the change of a replaceable transaction from its payments by the
+// output recorded here, new_send_many appends the change after the other n_outputs
+fn change_vout(tx: &Transaction, n_outputs: usize) -> Option<i32> {
+    if tx.replaceable && tx.vout.len() > n_outputs {
+        Some(n_outputs as i32)
+    } else {
+        None
+    }
+}
+
+fn record_change(wm: &mut WalletManager, txid: &str, change: Option<i32>) -> Result<()> {
+    if let Some(vout) = change {
+        wm.change_outputs.insert(txid.to_string(), vout);
+        wm.save_all()?;
+    }
+    Ok(())
+}
+
+// fee rates are never negative
+fn parse_fee_rate(rate: &str) -> Result<i32> {
+    let rate = rate.parse::<u32>()
+        .map_err(|_| format_err!("fee rate must be a non-negative integer, got {}", rate))?;
+    Ok(i32::try_from(rate)?)
+}
+
+fn parse_rescan(rescan: Option<&String>) -> Result<bool> {
+    match rescan {
+        Some(rescan) => Ok(rescan.parse::<bool>()?),
+        None => Ok(true),
+    }
+}
+
+fn print_block(block: &Block) -> Result<()> {
+    let header = block.get_header()?;
+    println!("Block {} at height {}", header.hash, header.height);
+    println!("  prev hash:   {}", header.prev_block_hash);
+    println!("  merkle root: {}", hex::encode(&header.merkle_root));
+    println!("  timestamp:   {}, nonce {}", header.timestamp, header.nonce);
+    println!("  {} transactions, {} bytes, total output {}, mined by {}", block.get_transactions().len(),
+             block.size()?, block.total_output(), block.miner().unwrap_or_default());
+    for tx in block.get_transactions() {
+        if tx.is_coinbase() {
+            println!("  tx {} (coinbase)", tx.id);
+        } else {
+            println!("  tx {}", tx.id);
+            for vin in &tx.vin {
+                let mut pub_key_hash = vin.pub_key.clone();
+                Wallet::hash_pub_key(&mut pub_key_hash);
+                println!("    in  {}:{} from {}, signature {}", vin.txid, vin.vout,
+                         Wallet::address_of(pub_key_hash), hex::encode(&vin.signature));
+            }
+        }
+        for (idx, out) in tx.vout.iter().enumerate() {
+            if out.is_data() {
+                println!("    out {}: data {}", idx, hex::encode(&out.pub_key_hash));
+            } else {
+                println!("    out {}: {} to {}", idx, out.value, Wallet::address_of(out.pub_key_hash.clone()));
+            }
+        }
+    }
+    println!();
+    Ok(())
+}
+
+// bytes taken by the files under path
+fn dir_size(path: &std::path::Path) -> Result<u64> {
+    if !path.is_dir() {
+        return Ok(std::fs::metadata(path)?.len());
+    }
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        size += dir_size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+fn print_peers(reply: Message) -> Result<()> {
+    match reply {
+        Message::PeerInfo(peers) => {
+            println!("{}", serde_json::to_string_pretty(&peers)?);
+            Ok(())
+        }
+        msg => Err(format_err!("unexpected reply from the node: {:?}", msg)),
+    }
+}
@@ -0,0 +1,358 @@
+use std::cmp::Reverse;
+use failure::format_err;
+use rand::seq::SliceRandom;
+use crate::errors::Result;
+use crate::tx::TxOutput;
+
+// approximate bincode sizes of the transaction parts, used to estimate fees
+const BASE_SIZE: usize = 89;      // id, vector lengths and replaceable flag
+const INPUT_SIZE: usize = 188;    // txid, vout, signature and public key
+const OUTPUT_SIZE: usize = 33;    // value, pub_key_hash and data flag
+const BNB_MAX_TRIES: usize = 100_000;
+
+#[derive(Debug, Clone)]
+pub struct Utxo {
+    pub txid: String,
+    pub vout: i32,
+    pub output: TxOutput,
+    pub height: i32,        // height of the block holding the output
+    pub coinbase: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct SelectionParams {
+    pub target: i32,        // total value of the payment outputs
+    pub n_outputs: usize,   // number of payment outputs, change excluded
+    pub fee_rate: i32,      // fee per 1000 bytes
+    pub min_conf: i32,      // confirmations required for a coin to be selected
+}
+
+#[derive(Debug)]
+pub struct Selection {
+    pub utxos: Vec<Utxo>,
+    pub fee: i64,
+    pub change: i64,    // may not fit an output value, checked when the output is made
+}
+
+impl SelectionParams {
+    pub fn new(target: i32, n_outputs: usize, fee_rate: i32, min_conf: i32) -> Self {
+        SelectionParams { target, n_outputs, fee_rate, min_conf }
+    }
+
+    fn fee(&self, size: usize) -> i64 {
+        (size as i64 * self.fee_rate as i64 + 999) / 1000
+    }
+
+    // cost of adding one more input, an output worth less is not worth creating
+    pub fn input_fee(&self) -> i64 {
+        self.fee(INPUT_SIZE)
+    }
+
+    pub fn tx_fee(&self, n_inputs: usize, n_outputs: usize) -> i64 {
+        self.fee(BASE_SIZE) + n_inputs as i64 * self.input_fee() + n_outputs as i64 * self.fee(OUTPUT_SIZE)
+    }
+
+    pub fn dust_threshold(&self) -> i64 {
+        self.input_fee()
+    }
+
+    // turn a set of coins into a selection, None if they don't cover target and fee;
+    // change at or below the dust threshold is left to the fee
+    fn finish(&self, utxos: Vec<Utxo>) -> Option<Selection> {
+        let total: i64 = utxos.iter().map(|u| u.output.value as i64).sum();
+        let target = self.target as i64;
+        if total < target + self.tx_fee(utxos.len(), self.n_outputs) {
+            return None;
+        }
+        let fee = self.tx_fee(utxos.len(), self.n_outputs + 1);
+        let change = total - target - fee;
+        if change > self.dust_threshold() && change > 0 {
+            Some(Selection { utxos, fee, change })
+        } else {
+            Some(Selection { utxos, fee: total - target, change: 0 })
+        }
+    }
+}
+
+pub trait CoinSelector {
+    fn select(&self, utxos: &[Utxo], params: &SelectionParams) -> Result<Selection>;
+}
+
+pub fn selector_by_name(name: &str) -> Result<Box<dyn CoinSelector>> {
+    match name {
+        "largest" => Ok(Box::new(LargestFirst)),
+        "smallest" => Ok(Box::new(SmallestFirst)),
+        "bnb" => Ok(Box::new(BranchAndBound)),
+        "random" => Ok(Box::new(RandomImprove)),
+        _ => Err(format_err!("unknown coin selection strategy {}, expect one of largest, smallest, bnb, random", name)),
+    }
+}
+
+fn insufficient(utxos: &[Utxo], params: &SelectionParams) -> failure::Error {
+    let accum: i64 = utxos.iter().map(|u| u.output.value as i64).sum();
+    format_err!("not enough outputs to fulfill transaction, spentable:{} < {} plus fee", accum, params.target)
+}
+
+// take coins in the given order until target and fee are covered
+fn accumulate(utxos: Vec<Utxo>, params: &SelectionParams) -> Option<Selection> {
+    let mut selected = Vec::new();
+    for utxo in utxos {
+        selected.push(utxo);
+        if let Some(selection) = params.finish(selected.clone()) {
+            return Some(selection);
+        }
+    }
+    None
+}
+
+// a replacement keeps every coin of the transaction it replaces so the two conflict,
+// and tops them up with the largest other coins when the higher fee needs it
+pub fn select_replacement(required: Vec<Utxo>, utxos: &[Utxo], params: &SelectionParams) -> Result<Selection> {
+    let mut others = utxos.to_vec();
+    others.sort_by_key(|u| Reverse(u.output.value));
+    let mut selected = required;
+    if let Some(selection) = params.finish(selected.clone()) {
+        return Ok(selection);
+    }
+    for utxo in others {
+        selected.push(utxo);
+        if let Some(selection) = params.finish(selected.clone()) {
+            return Ok(selection);
+        }
+    }
+    Err(insufficient(&selected, params))
+}
+
+pub struct LargestFirst;
+
+impl CoinSelector for LargestFirst {
+    fn select(&self, utxos: &[Utxo], params: &SelectionParams) -> Result<Selection> {
+        let mut sorted = utxos.to_vec();
+        sorted.sort_by_key(|u| Reverse(u.output.value));
+        accumulate(sorted, params).ok_or_else(|| insufficient(utxos, params))
+    }
+}
+
+pub struct SmallestFirst;
+
+impl CoinSelector for SmallestFirst {
+    fn select(&self, utxos: &[Utxo], params: &SelectionParams) -> Result<Selection> {
+        let mut sorted = utxos.to_vec();
+        sorted.sort_by_key(|u| u.output.value);
+        accumulate(sorted, params).ok_or_else(|| insufficient(utxos, params))
+    }
+}
+
+// depth first search for a set of coins matching target plus fee closely enough
+// that no change output is needed, falls back to largest first otherwise
+pub struct BranchAndBound;
+
+impl CoinSelector for BranchAndBound {
+    fn select(&self, utxos: &[Utxo], params: &SelectionParams) -> Result<Selection> {
+        let input_fee = params.input_fee();
+        let mut candidates: Vec<&Utxo> = utxos.iter()
+            .filter(|u| u.output.value as i64 > input_fee)
+            .collect();
+        candidates.sort_by_key(|u| Reverse(u.output.value));
+        let effective: Vec<i64> = candidates.iter().map(|u| u.output.value as i64 - input_fee).collect();
+
+        let lower = params.target as i64 + params.tx_fee(0, params.n_outputs);
+        let upper = lower + params.tx_fee(1, 1) - params.fee(BASE_SIZE);   // cost of creating and spending change
+        let mut remaining: i64 = effective.iter().sum();
+        let mut selected = vec![false; candidates.len()];
+        let mut current: i64 = 0;
+        let mut depth = 0;
+        let mut best: Option<Vec<bool>> = None;
+
+        for _ in 0..BNB_MAX_TRIES {
+            let backtrack = if current > upper || current + remaining < lower {
+                true
+            } else if current >= lower {
+                best = Some(selected.clone());
+                break;
+            } else {
+                depth >= candidates.len()
+            };
+
+            if backtrack {
+                // undo the last inclusion and try the branch without it
+                while depth > 0 && !selected[depth - 1] {
+                    depth -= 1;
+                    remaining += effective[depth];
+                }
+                if depth == 0 {
+                    break;
+                }
+                selected[depth - 1] = false;
+                current -= effective[depth - 1];
+            } else {
+                remaining -= effective[depth];
+                selected[depth] = true;
+                current += effective[depth];
+                depth += 1;
+            }
+        }
+
+        match best {
+            Some(best) => {
+                let chosen = candidates.iter().zip(best.iter())
+                    .filter(|(_, &s)| s)
+                    .map(|(u, _)| (*u).clone())
+                    .collect();
+                params.finish(chosen).ok_or_else(|| insufficient(utxos, params))
+            }
+            None => LargestFirst.select(utxos, params),
+        }
+    }
+}
+
+// pick random coins until the target is covered, then keep adding random coins
+// while they bring the change closer to the payment amount, up to three times it
+pub struct RandomImprove;
+
+impl CoinSelector for RandomImprove {
+    fn select(&self, utxos: &[Utxo], params: &SelectionParams) -> Result<Selection> {
+        let mut shuffled = utxos.to_vec();
+        shuffled.shuffle(&mut rand::thread_rng());
+
+        let mut selected = Vec::new();
+        let mut selection = None;
+        while let Some(utxo) = shuffled.pop() {
+            selected.push(utxo);
+            if let Some(s) = params.finish(selected.clone()) {
+                selection = Some(s);
+                break;
+            }
+        }
+        let mut selection = selection.ok_or_else(|| insufficient(utxos, params))?;
+
+        let ideal = params.target as i64;
+        let limit = 3 * params.target as i64;
+        for utxo in shuffled {
+            let mut candidate = selection.utxos.clone();
+            candidate.push(utxo);
+            if let Some(s) = params.finish(candidate) {
+                let total: i64 = s.utxos.iter().map(|u| u.output.value as i64).sum();
+                if total <= limit && (ideal - s.change).abs() < (ideal - selection.change).abs() {
+                    selection = s;
+                }
+            }
+        }
+        Ok(selection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coins(values: &[i32]) -> Vec<Utxo> {
+        values.iter().enumerate().map(|(idx, &value)| Utxo {
+            txid: format!("{:064x}", idx),
+            vout: 0,
+            output: TxOutput { value, pub_key_hash: vec![0; 20], data: false },
+            height: 1,
+            coinbase: false,
+        }).collect()
+    }
+
+    fn values(selection: &Selection) -> Vec<i32> {
+        let mut values: Vec<i32> = selection.utxos.iter().map(|u| u.output.value).collect();
+        values.sort_unstable();
+        values
+    }
+
+    // the selected coins pay exactly the target, the fee and the change
+    fn assert_balanced(selection: &Selection, params: &SelectionParams) {
+        let total: i64 = selection.utxos.iter().map(|u| u.output.value as i64).sum();
+        assert_eq!(total, params.target as i64 + selection.fee + selection.change);
+        let n_outputs = params.n_outputs + usize::from(selection.change > 0);
+        assert!(selection.fee >= params.tx_fee(selection.utxos.len(), n_outputs));
+        assert!(selection.change == 0 || selection.change > params.dust_threshold());
+    }
+
+    #[test]
+    fn largest_first_takes_the_largest_coins() {
+        let params = SelectionParams::new(1_000, 1, 1_000, 0);
+        let selection = LargestFirst.select(&coins(&[100, 2_000, 500, 3_000]), &params).unwrap();
+        assert_eq!(values(&selection), vec![3_000]);
+        assert_eq!(selection.fee, params.tx_fee(1, 2));
+        assert_balanced(&selection, &params);
+    }
+
+    #[test]
+    fn smallest_first_takes_the_smallest_coins() {
+        let params = SelectionParams::new(1_000, 1, 1_000, 0);
+        let selection = SmallestFirst.select(&coins(&[600, 3_000, 700, 2_000]), &params).unwrap();
+        assert_eq!(values(&selection), vec![600, 700, 2_000]);
+        assert_balanced(&selection, &params);
+    }
+
+    #[test]
+    fn dust_change_goes_to_the_fee() {
+        let params = SelectionParams::new(1_000, 1, 1_000, 0);
+        let needed = 1_000 + params.tx_fee(1, 1) as i32;
+        let selection = LargestFirst.select(&coins(&[needed + 10]), &params).unwrap();
+        assert_eq!(selection.change, 0);
+        assert_eq!(selection.fee, params.tx_fee(1, 1) + 10);
+        assert_balanced(&selection, &params);
+    }
+
+    #[test]
+    fn branch_and_bound_finds_a_changeless_match() {
+        let params = SelectionParams::new(1_200, 1, 0, 0);
+        let selection = BranchAndBound.select(&coins(&[1_000, 500, 700, 300]), &params).unwrap();
+        assert_eq!(values(&selection), vec![500, 700]);
+        assert_eq!(selection.change, 0);
+        assert_balanced(&selection, &params);
+    }
+
+    #[test]
+    fn branch_and_bound_falls_back_to_largest_first() {
+        let params = SelectionParams::new(1_000, 1, 1_000, 0);
+        let utxos = coins(&[5_000, 7_000]);
+        let selection = BranchAndBound.select(&utxos, &params).unwrap();
+        assert_eq!(values(&selection), values(&LargestFirst.select(&utxos, &params).unwrap()));
+        assert_balanced(&selection, &params);
+    }
+
+    #[test]
+    fn random_improve_covers_the_target() {
+        let params = SelectionParams::new(1_000, 2, 1_000, 0);
+        let utxos = coins(&[300, 800, 1_500, 2_500, 4_000, 600, 900]);
+        for _ in 0..20 {
+            let selection = RandomImprove.select(&utxos, &params).unwrap();
+            assert_balanced(&selection, &params);
+        }
+    }
+
+    #[test]
+    fn change_above_an_output_value_is_not_truncated() {
+        // a replacement keeps all the coins of the original
+        let params = SelectionParams::new(10, 1, 0, 0);
+        let selection = select_replacement(coins(&[i32::MAX, i32::MAX]), &[], &params).unwrap();
+        assert_eq!(selection.utxos.len(), 2);
+        assert_eq!(selection.change, 2 * i32::MAX as i64 - 10);
+        assert_balanced(&selection, &params);
+    }
+
+    #[test]
+    fn every_selector_fails_without_enough_coins() {
+        let params = SelectionParams::new(1_000, 1, 1_000, 0);
+        let utxos = coins(&[400, 600]);
+        for name in ["largest", "smallest", "bnb", "random"] {
+            assert!(selector_by_name(name).unwrap().select(&utxos, &params).is_err(), "{}", name);
+        }
+        assert!(selector_by_name("oldest").is_err());
+    }
+
+    #[test]
+    fn replacement_keeps_the_replaced_coins() {
+        let params = SelectionParams::new(1_000, 1, 1_000, 0);
+        let required = coins(&[1_200]);
+        let others = coins(&[5_000, 200]);
+        let selection = select_replacement(required, &others, &params).unwrap();
+        assert_eq!(values(&selection), vec![1_200, 5_000]);
+        assert_balanced(&selection, &params);
+    }
+}
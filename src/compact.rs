@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use serde::{Deserialize, Serialize};
+use crate::block::{Block, BlockHeader};
+use crate::errors::Result;
+use crate::transaction::Transaction;
+
+// bytes of a transaction id kept in a short id
+const SHORT_ID_SIZE: usize = 6;
+
+// a block announced by its header and short ids of its transactions, which the
+// receiver finds in its mempool; the coinbase, which no mempool holds, is sent in full
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    pub short_ids: Vec<u64>,
+    pub prefilled: Vec<(u32, Transaction)>,     // index in the block, transaction
+}
+
+impl CompactBlock {
+    pub fn new(block: &Block) -> Result<CompactBlock> {
+        let header = block.get_header()?;
+        let mut short_ids = Vec::new();
+        let mut prefilled = Vec::new();
+        for (index, tx) in block.get_transactions().iter().enumerate() {
+            if tx.is_coinbase() {
+                prefilled.push((index as u32, tx.clone()));
+            } else {
+                short_ids.push(short_id(&header.hash, &tx.id));
+            }
+        }
+        Ok(CompactBlock { header, short_ids, prefilled })
+    }
+
+    pub fn tx_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+
+    // the block transactions, from the prefilled ones and pool, None where missing
+    pub fn reconstruct(&self, pool: &HashMap<String, Transaction>) -> Vec<Option<Transaction>> {
+        // pending transactions sharing a short id can't be told apart, both count as missing
+        let mut by_short_id: HashMap<u64, Option<&Transaction>> = HashMap::new();
+        for tx in pool.values() {
+            by_short_id.entry(short_id(&self.header.hash, &tx.id))
+                .and_modify(|found| *found = None)
+                .or_insert(Some(tx));
+        }
+        let mut txs = vec![None; self.tx_count()];
+        for (index, tx) in &self.prefilled {
+            if let Some(slot) = txs.get_mut(*index as usize) {
+                *slot = Some(tx.clone());
+            }
+        }
+        let mut short_ids = self.short_ids.iter();
+        for slot in txs.iter_mut().filter(|slot| slot.is_none()) {
+            *slot = short_ids.next()
+                .and_then(|id| by_short_id.get(id).copied().flatten())
+                .cloned();
+        }
+        txs
+    }
+}
+
+// the first bytes of a hash of the block hash and the txid, salted by the block so
+// a collision in one block doesn't repeat in the next
+pub fn short_id(block_hash: &str, txid: &str) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.input_str(block_hash);
+    hasher.input_str(txid);
+    let mut hash = [0u8; 32];
+    hasher.result(&mut hash);
+    let mut id = [0u8; 8];
+    id[..SHORT_ID_SIZE].copy_from_slice(&hash[..SHORT_ID_SIZE]);
+    u64::from_le_bytes(id)
+}
@@ -1 +1,68 @@
-pub type Result<T> = std::result::Result<T, failure::Error>;
\ No newline at end of file
+// the Fail derive of failure expands to impl blocks nested in constants
+#![allow(non_local_definitions)]
+use failure::Fail;
+
+pub type Result<T> = std::result::Result<T, failure::Error>;
+
+#[derive(Debug, Fail)]
+pub enum TxError {
+    #[fail(display = "output {}:{} does not exist", txid, vout)]
+    UnknownOutput { txid: String, vout: i32 },
+    #[fail(display = "output {}:{} is spent or does not exist, the blocks that would tell are pruned", txid, vout)]
+    MissingOutput { txid: String, vout: i32 },
+    #[fail(display = "output {}:{} is already spent", txid, vout)]
+    SpentOutput { txid: String, vout: i32 },
+    #[fail(display = "output {}:{} is spent more than once", txid, vout)]
+    DuplicateInput { txid: String, vout: i32 },
+    #[fail(display = "output {}:{} is not locked to the input public key", txid, vout)]
+    OwnerMismatch { txid: String, vout: i32 },
+    #[fail(display = "invalid signature for output {}:{}", txid, vout)]
+    InvalidSignature { txid: String, vout: i32 },
+    #[fail(display = "coinbase output {}:{} is not mature yet", txid, vout)]
+    ImmatureCoinbase { txid: String, vout: i32 },
+    #[fail(display = "output {}:{} is already spent by a pending transaction", txid, vout)]
+    MempoolConflict { txid: String, vout: i32 },
+    #[fail(display = "replacement pays fee {} at rate {}, must pay more than {} at rate {}", fee, rate, replaced_fee, replaced_rate)]
+    ReplacementFee { fee: i64, rate: i64, replaced_fee: i64, replaced_rate: i64 },
+    #[fail(display = "output value {} must not be negative", _0)]
+    InvalidOutputValue(i32),
+    #[fail(display = "inputs {} can't cover outputs {}", input, output)]
+    InsufficientInputs { input: i64, output: i64 },
+    #[fail(display = "coinbase {} must be the first transaction of a block", _0)]
+    MisplacedCoinbase(String),
+    #[fail(display = "coinbase pays {}, more than the subsidy and fees {}", value, allowed)]
+    CoinbaseValue { value: i64, allowed: i64 },
+    #[fail(display = "transaction id {} doesn't match its hash {}", id, hash)]
+    IdMismatch { id: String, hash: String },
+}
+
+// a block at fault itself, as opposed to a failure of the local stores
+#[derive(Debug, Fail)]
+pub enum BlockError {
+    #[fail(display = "block {} hash doesn't match its header", _0)]
+    HashMismatch(String),
+    #[fail(display = "block {} doesn't meet the proof of work target", _0)]
+    InsufficientWork(String),
+    #[fail(display = "block {} doesn't match its header", _0)]
+    HeaderMismatch(String),
+    #[fail(display = "block {} doesn't extend the tip {}", hash, tip)]
+    NotOnTip { hash: String, tip: String },
+    #[fail(display = "block {} has height {}, expect {}", hash, height, expected)]
+    WrongHeight { hash: String, height: i32, expected: i32 },
+    #[fail(display = "block {} is not a genesis block", _0)]
+    NotGenesis(String),
+    #[fail(display = "genesis block {} must hold a single coinbase", _0)]
+    GenesisCoinbase(String),
+}
+
+#[derive(Debug, Fail)]
+pub enum WalletError {
+    #[fail(display = "wallet is locked, unlock it with walletpassphrase first")]
+    Locked,
+    #[fail(display = "wallet is not encrypted")]
+    NotEncrypted,
+    #[fail(display = "wallet is already encrypted")]
+    AlreadyEncrypted,
+    #[fail(display = "the wallet passphrase is incorrect")]
+    WrongPassphrase,
+}
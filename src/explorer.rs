@@ -0,0 +1,468 @@
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use bitcoincash_addr::Address;
+use failure::format_err;
+use log::{info, warn};
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::coinselect::Utxo;
+use crate::errors::Result;
+use crate::history::{list_transactions, WalletTx};
+use crate::mempool::Mempool;
+use crate::server::Server;
+use crate::transaction::Transaction;
+use crate::utxoset::{Balance, UTXOSet, UtxoRecord};
+use crate::wallet::Wallet;
+
+const RECENT_BLOCKS: usize = 20;        // blocks listed on the home page
+const TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_CONNECTIONS: usize = 16;      // requests served at once
+const MAX_REQUEST: u64 = 8 * 1024;      // bytes of request line and headers
+
+// a read-only block explorer: a small HTTP server rendering the chain of a running
+// node, its transactions, addresses and UTXO set as HTML pages
+// pages:
+// /                    tip, pending transactions and the last blocks
+// /block/<hash>, /height/<height>, /tx/<txid>, /address/<address>, /utxo
+// /search?q=<hash, txid, address or height>
+#[derive(Clone)]
+pub struct Explorer {
+    server: Server,
+    connections: Arc<AtomicUsize>,
+}
+
+impl Explorer {
+    pub fn new(server: Server) -> Explorer {
+        Explorer { server, connections: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    // listen at address and serve the pages in the background
+    pub fn spawn(self, address: &str) -> Result<()> {
+        let listener = TcpListener::bind(address)?;
+        println!("Explorer at http://{}", address);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("explorer connection failed: {}", e);
+                        continue;
+                    }
+                };
+                if self.connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    self.connections.fetch_sub(1, Ordering::SeqCst);
+                    warn!("explorer serves {} requests already, refuse one", MAX_CONNECTIONS);
+                    continue;
+                }
+                let explorer = self.clone();
+                thread::spawn(move || {
+                    if let Err(e) = explorer.handle(stream) {
+                        warn!("explorer request failed: {}", e);
+                    }
+                    explorer.connections.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        Ok(())
+    }
+
+    fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut reader = BufReader::new((&stream).take(MAX_REQUEST));
+        let mut request = String::new();
+        reader.read_line(&mut request)?;
+        // the headers aren't used, read up to the blank line ending them
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+        }
+        info!("explorer {}", request.trim());
+
+        let mut parts = request.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            _ if !request.ends_with('\n') => ("400 Bad Request", page("Bad request", "<p>The request is incomplete or too large.</p>")),
+            (Some("GET"), Some(target)) => match self.render(target) {
+                Ok(Some(body)) => ("200 OK", body),
+                Ok(None) => ("404 Not Found", page("Not found", "<p>No block, transaction or address matches.</p>")),
+                Err(e) => ("500 Internal Server Error", page("Error", &format!("<p>{}</p>", escape(&e.to_string())))),
+            },
+            _ => ("405 Method Not Allowed", page("Not allowed", "<p>Only GET requests are served.</p>")),
+        };
+        write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+               status, body.len(), body)?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    // the page at target, None if there is no such page. blocks are read through a
+    // handle on the chain store without the node lock; the UTXO set and the mempool,
+    // which the node opens for each use, are read under it. pages render after
+    fn render(&self, target: &str) -> Result<Option<String>> {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let parts: Vec<String> = path.trim_matches('/').split('/').map(percent_decode).collect();
+        let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
+        let bc = match self.server.with_utxo(|utxo| Ok(utxo.blockchain.clone()))? {
+            Some(bc) => bc,
+            None => return Ok(Some(page("Explorer", "<p>The node has no blocks yet.</p>"))),
+        };
+        match parts.as_slice() {
+            [""] => {
+                let pending = self.locked(|_| Mempool{}.get_transactions())?;
+                home(&bc, &pending).map(Some)
+            }
+            ["block", hash] => block_by_hash(&bc, hash),
+            ["height", height] => match height.parse::<usize>() {
+                Ok(height) => match bc.get_block_hashes().get(height) {
+                    Some(hash) => block_by_hash(&bc, hash),
+                    None => Ok(None),
+                },
+                Err(_) => Ok(None),
+            },
+            ["tx", txid] => self.transaction(&bc, txid),
+            ["address", address] => self.address(&bc, address),
+            ["utxo"] => {
+                let entries = self.locked(|utxo| utxo.get_entries())?;
+                utxo_set(entries, bc.get_best_height()?).map(Some)
+            }
+            ["search"] => {
+                let q = query.split('&')
+                    .find_map(|param| param.strip_prefix("q="))
+                    .map(url_decode)
+                    .unwrap_or_default();
+                match self.search(&bc, q.trim())? {
+                    Some(path) => self.render(&path),
+                    None => Ok(None),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // run f on the UTXO set under the node lock, keep it to reading
+    fn locked<T>(&self, f: impl FnOnce(&UTXOSet) -> Result<T>) -> Result<T> {
+        self.server.with_utxo(f)?.ok_or_else(|| format_err!("the node has no blocks yet"))
+    }
+
+    // the path of the page of a height, block hash, txid or address
+    fn search(&self, bc: &Blockchain, q: &str) -> Result<Option<String>> {
+        if q.parse::<i32>().is_ok() {
+            return Ok(Some(format!("/height/{}", percent_encode(q))));
+        }
+        if bc.get_header(q)?.is_some() {
+            return Ok(Some(format!("/block/{}", percent_encode(q))));
+        }
+        if self.locked(|_| (Mempool{}).get_transaction(q))?.is_some() || bc.find_transaction(q).is_some() {
+            return Ok(Some(format!("/tx/{}", percent_encode(q))));
+        }
+        if Address::decode(q).is_ok() {
+            return Ok(Some(format!("/address/{}", percent_encode(q))));
+        }
+        Ok(None)
+    }
+
+    fn transaction(&self, bc: &Blockchain, txid: &str) -> Result<Option<String>> {
+        if let Some(tx) = self.locked(|_| (Mempool{}).get_transaction(txid))? {
+            return transaction_page(&tx, None).map(Some);
+        }
+        let found = bc.iter().find_map(|block| {
+            block.get_transactions().iter().find(|tx| tx.id == txid).cloned().map(|tx| (block, tx))
+        });
+        let (block, tx) = match found {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let unspent = self.locked(|utxo| {
+            (0..tx.vout.len()).map(|idx| Ok(utxo.find_output(&tx.id, idx as i32)?.is_some())).collect::<Result<Vec<bool>>>()
+        })?;
+        transaction_page(&tx, Some((&block, bc.get_best_height()?, &unspent))).map(Some)
+    }
+
+    fn address(&self, bc: &Blockchain, address: &str) -> Result<Option<String>> {
+        let pub_key_hash = match Address::decode(address) {
+            Ok(decoded) => decoded.body,
+            Err(_) => return Ok(None),
+        };
+        let (balance, unspent) = self.locked(|utxo| {
+            let pending = Mempool{}.get_transactions()?;
+            Ok((utxo.get_balance(&pub_key_hash, 1, &pending)?, utxo.find_unspent(&pub_key_hash)?))
+        })?;
+        // a scan of the whole chain
+        let tracked: HashSet<Vec<u8>> = HashSet::from([pub_key_hash]);
+        let history = list_transactions(bc, &tracked);
+        address_page(address, &balance, unspent, bc.get_best_height()?, history).map(Some)
+    }
+}
+
+fn home(bc: &Blockchain, pending: &[Transaction]) -> Result<String> {
+    let mut body = String::new();
+    writeln!(body, "<p>Tip {} at height {}, {} pending transactions.</p>",
+             block_link(&bc.get_tip_hash()), bc.get_best_height()?, pending.len())?;
+    if !pending.is_empty() {
+        body.push_str("<h2>Pending transactions</h2>\n<ul>\n");
+        for tx in pending {
+            writeln!(body, "<li>{}</li>", tx_link(&tx.id))?;
+        }
+        body.push_str("</ul>\n");
+    }
+    body.push_str("<h2>Recent blocks</h2>\n");
+    let blocks: Vec<Block> = bc.iter().take(RECENT_BLOCKS).collect();
+    body.push_str(&block_table(&blocks)?);
+    if let Some(height) = bc.get_pruned_height()? {
+        writeln!(body, "<p>Blocks up to height {} are pruned.</p>", height)?;
+    }
+    Ok(page("Explorer", &body))
+}
+
+fn block_table(blocks: &[Block]) -> Result<String> {
+    let mut table = String::from("<table>\n<tr><th>Height</th><th>Hash</th><th>Transactions</th><th>Size</th><th>Output</th><th>Miner</th><th>Time</th></tr>\n");
+    for block in blocks {
+        writeln!(table, "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                 height_link(block.get_height()), block_link(&block.get_hash()), block.get_transactions().len(),
+                 block.size()?, block.total_output(), block.miner().map(|miner| address_link(&miner)).unwrap_or_default(),
+                 format_time(block.get_timestamp()))?;
+    }
+    table.push_str("</table>\n");
+    Ok(table)
+}
+
+fn block_by_hash(bc: &Blockchain, hash: &str) -> Result<Option<String>> {
+    let header = match bc.get_header(hash)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let next = bc.get_block_hashes().get(header.height as usize + 1).cloned();
+    let mut body = String::from("<table>\n");
+    writeln!(body, "<tr><th>Hash</th><td>{}</td></tr>", escape(&header.hash))?;
+    if !header.prev_block_hash.is_empty() {
+        writeln!(body, "<tr><th>Previous</th><td>{}</td></tr>", block_link(&header.prev_block_hash))?;
+    }
+    if let Some(next) = next {
+        writeln!(body, "<tr><th>Next</th><td>{}</td></tr>", block_link(&next))?;
+    }
+    writeln!(body, "<tr><th>Confirmations</th><td>{}</td></tr>", bc.get_best_height()? - header.height + 1)?;
+    writeln!(body, "<tr><th>Time</th><td>{}</td></tr>", format_time(header.timestamp))?;
+    writeln!(body, "<tr><th>Nonce</th><td>{}</td></tr>", header.nonce)?;
+    writeln!(body, "<tr><th>Merkle root</th><td>{}</td></tr>", hex::encode(&header.merkle_root))?;
+    let block = match bc.get_block(hash)? {
+        Some(block) => block,
+        None => {
+            body.push_str("</table>\n<p>The body of this block is pruned.</p>\n");
+            return Ok(Some(page(&format!("Block {}", header.height), &body)));
+        }
+    };
+    writeln!(body, "<tr><th>Transactions</th><td>{}</td></tr>", block.get_transactions().len())?;
+    writeln!(body, "<tr><th>Size</th><td>{} bytes</td></tr>", block.size()?)?;
+    writeln!(body, "<tr><th>Total output</th><td>{}</td></tr>", block.total_output())?;
+    if let Some(miner) = block.miner() {
+        writeln!(body, "<tr><th>Miner</th><td>{}</td></tr>", address_link(&miner))?;
+    }
+    body.push_str("</table>\n<h2>Transactions</h2>\n");
+    for tx in block.get_transactions() {
+        writeln!(body, "<h3>{}</h3>", tx_link(&tx.id))?;
+        body.push_str(&tx_detail(tx, None)?);
+    }
+    Ok(Some(page(&format!("Block {}", header.height), &body)))
+}
+
+// a pending transaction, or a mined one with its block, the tip height and whether
+// each of its outputs is unspent
+fn transaction_page(tx: &Transaction, mined: Option<(&Block, i32, &[bool])>) -> Result<String> {
+    let mut body = String::new();
+    match mined {
+        None => {
+            body.push_str("<p>Pending in the mempool.</p>\n");
+            body.push_str(&tx_detail(tx, None)?);
+        }
+        Some((block, tip_height, unspent)) => {
+            writeln!(body, "<p>In block {} at height {}, {} confirmations.</p>", block_link(&block.get_hash()),
+                     block.get_height(), tip_height - block.get_height() + 1)?;
+            body.push_str(&tx_detail(tx, Some(unspent))?);
+        }
+    }
+    writeln!(body, "<p>{} bytes{}.</p>", tx.size()?, if tx.replaceable { ", replaceable" } else { "" })?;
+    Ok(page(&format!("Transaction {}", tx.id), &body))
+}
+
+// inputs and outputs of tx, with whether the outputs are unspent when known
+fn tx_detail(tx: &Transaction, unspent: Option<&[bool]>) -> Result<String> {
+    let mut html = String::from("<table>\n<tr><th>Inputs</th><th>Outputs</th></tr>\n<tr><td>\n");
+    if tx.is_coinbase() {
+        writeln!(html, "coinbase {}<br>", hex::encode(&tx.vin[0].pub_key))?;
+    } else {
+        for vin in &tx.vin {
+            let mut pub_key_hash = vin.pub_key.clone();
+            Wallet::hash_pub_key(&mut pub_key_hash);
+            writeln!(html, "{}:{} from {}<br>", tx_link(&vin.txid), vin.vout,
+                     address_link(&Wallet::address_of(pub_key_hash)))?;
+        }
+    }
+    html.push_str("</td><td>\n");
+    for (idx, out) in tx.vout.iter().enumerate() {
+        if out.is_data() {
+            writeln!(html, "{}: data {}<br>", idx, hex::encode(&out.pub_key_hash))?;
+            continue;
+        }
+        let spent = match unspent {
+            Some(unspent) if unspent[idx] => " (unspent)",
+            Some(_) => " (spent)",
+            None => "",
+        };
+        writeln!(html, "{}: {} to {}{}<br>", idx, out.value,
+                 address_link(&Wallet::address_of(out.pub_key_hash.clone())), spent)?;
+    }
+    html.push_str("</td></tr>\n</table>\n");
+    Ok(html)
+}
+
+fn address_page(address: &str, balance: &Balance, mut unspent: Vec<Utxo>, tip_height: i32, history: Result<Vec<WalletTx>>) -> Result<String> {
+    let mut body = String::new();
+    writeln!(body, "<p>Balance {}, unconfirmed {}, immature {}.</p>",
+             balance.confirmed, balance.unconfirmed, balance.immature)?;
+
+    body.push_str("<h2>Unspent outputs</h2>\n<table>\n<tr><th>Output</th><th>Value</th><th>Confirmations</th></tr>\n");
+    unspent.sort_by_key(|u| -u.height);
+    for u in &unspent {
+        writeln!(body, "<tr><td>{}:{}{}</td><td>{}</td><td>{}</td></tr>", tx_link(&u.txid), u.vout,
+                 if u.coinbase { " (coinbase)" } else { "" }, u.output.value, tip_height - u.height + 1)?;
+    }
+    body.push_str("</table>\n<h2>Transactions</h2>\n");
+    match history {
+        Ok(history) => {
+            body.push_str("<table>\n<tr><th>Height</th><th>Transaction</th><th>Category</th><th>Amount</th><th>Time</th></tr>\n");
+            for tx in &history {
+                writeln!(body, "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>", height_link(tx.height),
+                         tx_link(&tx.txid), tx.category, tx.amount, format_time(tx.timestamp))?;
+            }
+            body.push_str("</table>\n");
+        }
+        Err(e) => writeln!(body, "<p>{}</p>", escape(&e.to_string()))?,
+    }
+    Ok(page(&format!("Address {}", address), &body))
+}
+
+fn utxo_set(mut entries: Vec<UtxoRecord>, tip_height: i32) -> Result<String> {
+    entries.sort_by_key(|(_, _, entry)| -entry.height);
+    let total: i64 = entries.iter().map(|(_, _, entry)| entry.output.value as i64).sum();
+    let mut body = String::new();
+    writeln!(body, "<p>{} unspent outputs worth {} at height {}.</p>", entries.len(), total, tip_height)?;
+    body.push_str("<table>\n<tr><th>Output</th><th>Value</th><th>Address</th><th>Confirmations</th></tr>\n");
+    for (txid, vout, entry) in &entries {
+        writeln!(body, "<tr><td>{}:{}{}</td><td>{}</td><td>{}</td><td>{}</td></tr>", tx_link(txid), vout,
+                 if entry.coinbase { " (coinbase)" } else { "" }, entry.output.value,
+                 address_link(&Wallet::address_of(entry.output.pub_key_hash.clone())), tip_height - entry.height + 1)?;
+    }
+    body.push_str("</table>\n");
+    Ok(page("UTXO set", &body))
+}
+
+// body is HTML, title is text
+fn page(title: &str, body: &str) -> String {
+    let title = escape(title);
+    format!(r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; margin-bottom: 1em; }}
+th, td {{ border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; font-family: monospace; vertical-align: top; }}
+th {{ background: #f0f0f0; }}
+td {{ word-break: break-all; }}
+</style>
+</head>
+<body>
+<nav><a href="/">Home</a> | <a href="/utxo">UTXO set</a> |
+<form action="/search" style="display: inline"><input name="q" size="70" placeholder="block hash, txid, address or height"> <button>Search</button></form></nav>
+<h1>{title}</h1>
+{body}
+</body>
+</html>
+"#)
+}
+
+// ids and addresses come from peers and URLs: links escape them and percent-encode them in the href
+fn block_link(hash: &str) -> String {
+    format!("<a href=\"/block/{}\">{}</a>", percent_encode(hash), escape(hash))
+}
+
+fn height_link(height: i32) -> String {
+    format!("<a href=\"/height/{0}\">{0}</a>", height)
+}
+
+fn tx_link(txid: &str) -> String {
+    format!("<a href=\"/tx/{}\">{}</a>", percent_encode(txid), escape(txid))
+}
+
+fn address_link(address: &str) -> String {
+    format!("<a href=\"/address/{}\">{}</a>", percent_encode(address), escape(address))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
+
+// a path segment: every byte but the unreserved ones as %XX
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// a form field: + is a space and %XX an escaped byte
+fn url_decode(value: &str) -> String {
+    percent_decode(&value.replace('+', " "))
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// milliseconds since the epoch as a UTC date and time
+fn format_time(millis: u128) -> String {
+    let secs = (millis / 1000) as i64;
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // civil date from days since 1970-01-01, after Howard Hinnant
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
@@ -0,0 +1,315 @@
+use std::collections::BTreeSet;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::errors::Result;
+use crate::transaction::Transaction;
+
+// Golomb-Rice parameters: remainders of P bits, one false positive in about M queries
+const P: u8 = 19;
+const M: u64 = 784931;
+
+// a Golomb-coded set of the pub_key_hash of every output of a block and of every
+// outpoint it spends, so a light wallet tells whether a block concerns it
+// without downloading it; false positives happen, false negatives don't
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockFilter {
+    pub block_hash: String,
+    pub n: u32,         // items in the set
+    pub data: Vec<u8>,
+}
+
+impl BlockFilter {
+    pub fn new(block: &Block) -> BlockFilter {
+        let items: BTreeSet<Vec<u8>> = block.get_transactions().iter().flat_map(tx_items).collect();
+        let block_hash = block.get_hash();
+        let n = items.len() as u32;
+        let mut values: Vec<u64> = items.iter()
+            .map(|item| hash_to_range(&block_hash, item, n as u64 * M))
+            .collect();
+        values.sort_unstable();
+
+        let mut writer = BitWriter::default();
+        let mut last = 0;
+        for value in values {
+            let delta = value - last;
+            last = value;
+            for _ in 0..delta >> P {
+                writer.write_bit(true);
+            }
+            writer.write_bit(false);
+            writer.write_bits(delta, P);
+        }
+        BlockFilter { block_hash, n, data: writer.bytes }
+    }
+
+    // whether any of items may be in the set
+    pub fn match_any(&self, items: &[Vec<u8>]) -> Result<bool> {
+        if self.n == 0 || items.is_empty() {
+            return Ok(false);
+        }
+        let mut queries: Vec<u64> = items.iter()
+            .map(|item| hash_to_range(&self.block_hash, item, self.n as u64 * M))
+            .collect();
+        queries.sort_unstable();
+
+        let mut reader = BitReader { bytes: &self.data, pos: 0 };
+        let mut value = 0;
+        let mut queries = queries.into_iter().peekable();
+        for _ in 0..self.n {
+            let mut quotient = 0;
+            while reader.read_bit()? {
+                quotient += 1;
+            }
+            value += (quotient << P) + reader.read_bits(P)?;
+            while let Some(query) = queries.peek() {
+                if *query == value {
+                    return Ok(true);
+                }
+                if *query > value {
+                    break;
+                }
+                queries.next();
+            }
+            if queries.peek().is_none() {
+                return Ok(false);
+            }
+        }
+        Ok(false)
+    }
+}
+
+// the filter items of a transaction: the pub_key_hash of its outputs and the outpoints it spends
+pub fn tx_items(tx: &Transaction) -> Vec<Vec<u8>> {
+    let mut items: Vec<Vec<u8>> = tx.vout.iter().map(|out| out.pub_key_hash.clone()).collect();
+    if !tx.is_coinbase() {
+        items.extend(tx.vin.iter().map(|vin| outpoint_item(&vin.txid, vin.vout)));
+    }
+    items
+}
+
+// the filter item of an output spent by a block
+pub fn outpoint_item(txid: &str, vout: i32) -> Vec<u8> {
+    let mut item = txid.as_bytes().to_vec();
+    item.extend_from_slice(&vout.to_le_bytes());
+    item
+}
+
+// the hash of the item keyed by the block, mapped uniformly into [0, range)
+fn hash_to_range(block_hash: &str, item: &[u8], range: u64) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.input_str(block_hash);
+    hasher.input(item);
+    let mut hash = [0u8; 32];
+    hasher.result(&mut hash);
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&hash[..8]);
+    ((u64::from_le_bytes(value) as u128 * range as u128) >> 64) as u64
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u8,   // bits used in the last byte
+}
+
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+        }
+        self.used = (self.used + 1) % 8;
+    }
+
+    fn write_bits(&mut self, value: u64, count: u8) {
+        for i in (0..count).rev() {
+            self.write_bit(value >> i & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,     // in bits
+}
+
+impl BitReader<'_> {
+    fn read_bit(&mut self) -> Result<bool> {
+        let byte = self.bytes.get(self.pos / 8)
+            .ok_or_else(|| format_err!("block filter is truncated"))?;
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Ok(bit)
+    }
+
+    fn read_bits(&mut self, count: u8) -> Result<u64> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = value << 1 | self.read_bit()? as u64;
+        }
+        Ok(value)
+    }
+}
+
+// filters of the connected blocks
+// storage:
+// key: block hash, value: binary of BlockFilter
+pub struct FilterIndex {}
+
+impl FilterIndex {
+    const PATH: &'static str = "data/filters";
+
+    pub fn add(&self, block: &Block) -> Result<()> {
+        let db = sled::open(FilterIndex::PATH)?;
+        db.insert(block.get_hash().as_bytes(), bincode::serialize(&BlockFilter::new(block))?)?;
+        db.flush()?;
+        Ok(())
+    }
+
+    pub fn remove(&self, block_hash: &str) -> Result<()> {
+        let db = sled::open(FilterIndex::PATH)?;
+        db.remove(block_hash)?;
+        db.flush()?;
+        Ok(())
+    }
+
+    pub fn get(&self, block_hash: &str) -> Result<Option<BlockFilter>> {
+        let db = sled::open(FilterIndex::PATH)?;
+        match db.get(block_hash)? {
+            Some(value) => Ok(Some(bincode::deserialize(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    // add the filters of the local blocks that have none, such as those of a chain
+    // stored before filters were kept
+    pub fn catch_up(&self, bc: &Blockchain) -> Result<usize> {
+        let db = sled::open(FilterIndex::PATH)?;
+        let mut added = 0;
+        for block in bc.iter() {
+            if db.contains_key(block.get_hash())? {
+                break;
+            }
+            db.insert(block.get_hash().as_bytes(), bincode::serialize(&BlockFilter::new(&block))?)?;
+            added += 1;
+        }
+        db.flush()?;
+        Ok(added)
+    }
+
+    // rebuild the filters of every block of the chain
+    pub fn reindex(&self, bc: &Blockchain) -> Result<()> {
+        let db = sled::open(FilterIndex::PATH)?;
+        db.clear()?;
+        for block in bc.iter() {
+            db.insert(block.get_hash().as_bytes(), bincode::serialize(&BlockFilter::new(&block))?)?;
+        }
+        db.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::BlockHeader;
+    use crate::tx::{TxInput, TxOutput};
+
+    fn item(seed: u32) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.input(&seed.to_le_bytes());
+        let mut hash = [0u8; 32];
+        hasher.result(&mut hash);
+        hash[..20].to_vec()
+    }
+
+    // a block with one transaction per output, each spending the outpoint
+    // of the previous one; no proof of work is needed for filters
+    fn block(hash: &str, outputs: u32) -> Block {
+        let transactions = (0..outputs).map(|i| Transaction {
+            id: format!("{:064x}", i + 1),
+            vin: vec![TxInput { txid: format!("{:064x}", i), vout: 0, signature: Vec::new(), pub_key: Vec::new() }],
+            vout: vec![TxOutput { value: 10, pub_key_hash: item(i), data: false }],
+            replaceable: false,
+        }).collect();
+        let header = BlockHeader {
+            timestamp: 0,
+            prev_block_hash: String::new(),
+            merkle_root: Vec::new(),
+            hash: hash.to_string(),
+            height: 1,
+            nonce: 0,
+        };
+        Block::from_header(header, transactions)
+    }
+
+    #[test]
+    fn every_item_of_the_block_matches() {
+        for outputs in [1, 2, 50, 500] {
+            let block = block(&format!("block{}", outputs), outputs);
+            let filter = BlockFilter::new(&block);
+            for tx in block.get_transactions() {
+                for item in tx_items(tx) {
+                    assert!(filter.match_any(&[item]).unwrap(), "false negative in a block of {}", outputs);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn items_and_outpoints_are_in_the_set() {
+        let block = block("block", 10);
+        let filter = BlockFilter::new(&block);
+        assert_eq!(filter.n, 20);
+        assert!(filter.match_any(&[item(3)]).unwrap());
+        assert!(filter.match_any(&[outpoint_item(&format!("{:064x}", 4), 0)]).unwrap());
+    }
+
+    #[test]
+    fn other_items_seldom_match() {
+        let filter = BlockFilter::new(&block("block", 100));
+        let false_positives = (1_000..11_000).filter(|&seed| filter.match_any(&[item(seed)]).unwrap()).count();
+        assert!(false_positives <= 2, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn any_of_several_queries_matches() {
+        let filter = BlockFilter::new(&block("block", 100));
+        let mut queries: Vec<Vec<u8>> = (1_000..1_100).map(item).collect();
+        assert!(!filter.match_any(&queries).unwrap());
+        queries.push(item(42));
+        assert!(filter.match_any(&queries).unwrap());
+        assert!(!filter.match_any(&[]).unwrap());
+    }
+
+    #[test]
+    fn filters_are_keyed_by_the_block() {
+        let a = BlockFilter::new(&block("a", 20));
+        let b = BlockFilter::new(&block("b", 20));
+        assert_ne!(a.data, b.data);
+        assert!(b.match_any(&[item(7)]).unwrap());
+    }
+
+    #[test]
+    fn empty_filter_matches_nothing() {
+        let filter = BlockFilter::new(&block("empty", 0));
+        assert_eq!(filter.n, 0);
+        assert!(filter.data.is_empty());
+        assert!(!filter.match_any(&[item(0)]).unwrap());
+    }
+
+    #[test]
+    fn filter_survives_serialization() {
+        let filter = BlockFilter::new(&block("block", 30));
+        let decoded: BlockFilter = bincode::deserialize(&bincode::serialize(&filter).unwrap()).unwrap();
+        for i in 0..30 {
+            assert!(decoded.match_any(&[item(i)]).unwrap());
+        }
+    }
+}
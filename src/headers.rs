@@ -0,0 +1,303 @@
+use std::time::SystemTime;
+use failure::format_err;
+use log::info;
+use crate::block::BlockHeader;
+use crate::blockchain::Blockchain;
+use crate::errors::Result;
+
+// a header must be later than the median time of this many previous headers
+const MEDIAN_TIME_SPAN: usize = 11;
+// and not more than two hours ahead of the local clock
+const MAX_FUTURE_MILLIS: u128 = 2 * 60 * 60 * 1000;
+
+// validated header chain, ahead of the blocks while syncing
+// storage:
+// default tree: hash -> binary of BlockHeader, "BEST" -> hash of the best header
+// tree height: big endian height -> hash on the best chain
+pub struct HeaderChain {}
+
+impl HeaderChain {
+    const PATH: &'static str = "data/headers";
+
+    pub fn get_best(&self) -> Result<Option<BlockHeader>> {
+        let db = sled::open(HeaderChain::PATH)?;
+        match db.get("BEST")? {
+            Some(hash) => Ok(HeaderChain::read(&db, &String::from_utf8(hash.to_vec())?)?),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_best_height(&self) -> Result<i32> {
+        Ok(self.get_best()?.map_or(-1, |header| header.height))
+    }
+
+    pub fn get_header(&self, hash: &str) -> Result<Option<BlockHeader>> {
+        let db = sled::open(HeaderChain::PATH)?;
+        HeaderChain::read(&db, hash)
+    }
+
+    // hash of the best chain header at height
+    pub fn hash_at(&self, height: i32) -> Result<Option<String>> {
+        let db = sled::open(HeaderChain::PATH)?;
+        match db.open_tree("height")?.get(height.to_be_bytes())? {
+            Some(hash) => Ok(Some(String::from_utf8(hash.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    // validate and store a header extending the best chain, false if already known
+    pub fn add(&self, header: &BlockHeader) -> Result<bool> {
+        let db = sled::open(HeaderChain::PATH)?;
+        if db.contains_key(&header.hash)? {
+            return Ok(false);
+        }
+        let best = match db.get("BEST")? {
+            Some(hash) => HeaderChain::read(&db, &String::from_utf8(hash.to_vec())?)?,
+            None => None,
+        };
+        match &best {
+            None if header.height != 0 || !header.prev_block_hash.is_empty() => {
+                return Err(format_err!("header {} doesn't start from a genesis block", header.hash));
+            }
+            None => {}
+            Some(best) if best.hash != header.prev_block_hash => {
+                return Err(format_err!("header {} at height {} doesn't extend the best header {}",
+                    header.hash, header.height, best.hash));
+            }
+            Some(best) if header.height != best.height + 1 => {
+                return Err(format_err!("header {} has height {}, expect {}", header.hash, header.height, best.height + 1));
+            }
+            Some(_) => {
+                let median = HeaderChain::median_time(&db, header.height - 1)?;
+                if header.timestamp <= median {
+                    return Err(format_err!("header {} is not later than the median time of the previous blocks", header.hash));
+                }
+            }
+        }
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis();
+        if header.timestamp > now + MAX_FUTURE_MILLIS {
+            return Err(format_err!("header {} is too far in the future", header.hash));
+        }
+        header.check_pow()?;
+
+        db.insert(header.hash.as_bytes(), bincode::serialize(header)?)?;
+        db.open_tree("height")?.insert(header.height.to_be_bytes(), header.hash.as_bytes())?;
+        db.insert("BEST", header.hash.as_bytes())?;
+        db.flush()?;
+        Ok(true)
+    }
+
+    // drop the best chain headers above height, so headers whose blocks never
+    // connected don't stay the best chain; returns how many were dropped
+    pub fn rewind(&self, height: i32) -> Result<usize> {
+        let db = sled::open(HeaderChain::PATH)?;
+        let heights = db.open_tree("height")?;
+        let mut dropped = 0;
+        for kv in heights.range((height + 1).to_be_bytes()..) {
+            let (key, hash) = kv?;
+            db.remove(&hash)?;
+            heights.remove(key)?;
+            dropped += 1;
+        }
+        match heights.get(height.to_be_bytes())? {
+            Some(hash) => db.insert("BEST", hash)?,
+            None => db.remove("BEST")?,
+        };
+        db.flush()?;
+        Ok(dropped)
+    }
+
+    // store the headers of local blocks the header chain doesn't know yet,
+    // such as locally mined ones
+    pub fn catch_up(&self, bc: &Blockchain) -> Result<()> {
+        let mut missing = Vec::new();
+        for header in bc.headers() {
+            if self.get_header(&header.hash)?.is_some() {
+                break;
+            }
+            missing.push(header);
+        }
+        for header in missing.iter().rev() {
+            self.add(header)?;
+        }
+        if !missing.is_empty() {
+            info!("added {} local headers", missing.len());
+        }
+        Ok(())
+    }
+
+    // hashes of the best chain going back from the tip, densely first then
+    // exponentially, so a peer finds the last header we have in common
+    pub fn locator(&self) -> Result<Vec<String>> {
+        let mut locator = Vec::new();
+        let mut height = self.get_best_height()?;
+        let mut step = 1;
+        while height >= 0 {
+            if let Some(hash) = self.hash_at(height)? {
+                locator.push(hash);
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            if height > 0 && height < step {
+                height = 0;
+            } else {
+                height -= step;
+            }
+        }
+        Ok(locator)
+    }
+
+    fn read(db: &sled::Db, hash: &str) -> Result<Option<BlockHeader>> {
+        match db.get(hash)? {
+            Some(value) => Ok(Some(bincode::deserialize(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn median_time(db: &sled::Db, tip_height: i32) -> Result<u128> {
+        let heights = db.open_tree("height")?;
+        let mut times = Vec::new();
+        let first = (tip_height - MEDIAN_TIME_SPAN as i32 + 1).max(0);
+        for height in first..=tip_height {
+            let hash = heights.get(height.to_be_bytes())?
+                .ok_or_else(|| format_err!("missing header at height {}", height))?;
+            let header = HeaderChain::read(db, &String::from_utf8(hash.to_vec())?)?
+                .ok_or_else(|| format_err!("missing header at height {}", height))?;
+            times.push(header.timestamp);
+        }
+        times.sort();
+        Ok(times[times.len() / 2])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::BlockError;
+    use crate::testutil::enter_temp_dir;
+
+    // a header on top of prev, or a genesis header, with a valid proof of work
+    fn mine(prev: Option<&BlockHeader>, timestamp: u128) -> BlockHeader {
+        let mut header = BlockHeader {
+            timestamp,
+            prev_block_hash: prev.map_or(String::new(), |prev| prev.hash.clone()),
+            merkle_root: vec![timestamp as u8],
+            hash: String::new(),
+            height: prev.map_or(0, |prev| prev.height + 1),
+            nonce: 0,
+        };
+        loop {
+            header.hash = header.compute_hash().unwrap();
+            if header.check_pow().is_ok() {
+                return header;
+            }
+            header.nonce += 1;
+        }
+    }
+
+    // headers a second apart from the genesis one
+    fn mine_chain(len: usize) -> Vec<BlockHeader> {
+        let mut headers = vec![mine(None, 1_000)];
+        while headers.len() < len {
+            let prev = headers.last().unwrap();
+            headers.push(mine(Some(prev), prev.timestamp + 1_000));
+        }
+        headers
+    }
+
+    fn add_error(header: &BlockHeader) -> String {
+        HeaderChain{}.add(header).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn header_must_extend_the_best_header() {
+        let _dir = enter_temp_dir();
+        let headers = mine_chain(3);
+        assert!(add_error(&headers[1]).contains("doesn't start from a genesis block"));
+        assert!(HeaderChain{}.add(&headers[0]).unwrap());
+        assert!(!HeaderChain{}.add(&headers[0]).unwrap());
+        assert!(add_error(&headers[2]).contains("doesn't extend the best header"));
+        let mut skipping = mine(Some(&headers[0]), 2_000);
+        skipping.height = 2;
+        assert!(add_error(&skipping).contains("has height 2, expect 1"));
+        assert!(HeaderChain{}.add(&headers[1]).unwrap());
+        assert_eq!(HeaderChain{}.get_best().unwrap().unwrap().hash, headers[1].hash);
+    }
+
+    #[test]
+    fn header_must_carry_its_proof_of_work() {
+        let _dir = enter_temp_dir();
+        let genesis = mine(None, 1_000);
+        HeaderChain{}.add(&genesis).unwrap();
+        let header = mine(Some(&genesis), 2_000);
+
+        let mut altered = header.clone();
+        altered.nonce += 1;
+        let err = HeaderChain{}.add(&altered).unwrap_err();
+        assert!(matches!(err.downcast_ref::<BlockError>(), Some(BlockError::HashMismatch(_))), "{}", err);
+
+        // hashed right but missing the target
+        let mut weak = altered;
+        loop {
+            weak.hash = weak.compute_hash().unwrap();
+            if weak.check_pow().is_err() {
+                break;
+            }
+            weak.nonce += 1;
+        }
+        let err = HeaderChain{}.add(&weak).unwrap_err();
+        assert!(matches!(err.downcast_ref::<BlockError>(), Some(BlockError::InsufficientWork(_))), "{}", err);
+        assert!(HeaderChain{}.add(&header).unwrap());
+    }
+
+    #[test]
+    fn header_must_be_later_than_the_median_time() {
+        let _dir = enter_temp_dir();
+        let genesis = mine(None, 10_000);
+        let first = mine(Some(&genesis), 20_000);
+        HeaderChain{}.add(&genesis).unwrap();
+        HeaderChain{}.add(&first).unwrap();
+        // the median of 10000 and 20000 is 20000
+        for timestamp in [15_000, 20_000] {
+            assert!(add_error(&mine(Some(&first), timestamp)).contains("median time"));
+        }
+        let second = mine(Some(&first), 20_001);
+        HeaderChain{}.add(&second).unwrap();
+        // the median of 10000, 20000 and 20001 is 20000: no later than its parent is enough
+        assert!(HeaderChain{}.add(&mine(Some(&second), 20_001)).unwrap());
+    }
+
+    #[test]
+    fn header_must_not_be_more_than_two_hours_ahead() {
+        let _dir = enter_temp_dir();
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        assert!(add_error(&mine(None, now + MAX_FUTURE_MILLIS + 60_000)).contains("too far in the future"));
+        assert!(HeaderChain{}.add(&mine(None, now + MAX_FUTURE_MILLIS - 60_000)).unwrap());
+    }
+
+    #[test]
+    fn rewind_then_fork_updates_the_locator() {
+        let _dir = enter_temp_dir();
+        let headers = mine_chain(6);
+        for header in &headers {
+            HeaderChain{}.add(header).unwrap();
+        }
+        let hashes: Vec<String> = headers.iter().map(|header| header.hash.clone()).collect();
+        assert_eq!(HeaderChain{}.locator().unwrap(), hashes.iter().rev().cloned().collect::<Vec<_>>());
+
+        assert_eq!(HeaderChain{}.rewind(2).unwrap(), 3);
+        assert_eq!(HeaderChain{}.get_best().unwrap().unwrap().hash, hashes[2]);
+        assert_eq!(HeaderChain{}.hash_at(3).unwrap(), None);
+        assert!(HeaderChain{}.get_header(&hashes[3]).unwrap().is_none());
+
+        // another branch grows from height 2, the old one no longer connects
+        let fork = mine(Some(&headers[2]), headers[2].timestamp + 500);
+        let fork_tip = mine(Some(&fork), fork.timestamp + 500);
+        HeaderChain{}.add(&fork).unwrap();
+        HeaderChain{}.add(&fork_tip).unwrap();
+        assert!(HeaderChain{}.add(&headers[4]).is_err());
+        assert_eq!(HeaderChain{}.locator().unwrap(),
+            vec![fork_tip.hash.clone(), fork.hash.clone(), hashes[2].clone(), hashes[1].clone(), hashes[0].clone()]);
+    }
+}
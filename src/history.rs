@@ -0,0 +1,117 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use serde::Serialize;
+use crate::blockchain::Blockchain;
+use crate::errors::Result;
+use crate::tx::TxOutput;
+use crate::wallet::Wallet;
+
+// a transaction seen from the wallet: amount is the change of balance of the
+// tracked addresses, fee is known when the wallet paid for the transaction
+#[derive(Serialize, Debug, Clone)]
+pub struct WalletTx {
+    pub txid: String,
+    pub block_hash: String,
+    pub height: i32,
+    pub confirmations: i32,
+    pub timestamp: u128,
+    pub category: String,
+    pub amount: i64,
+    pub fee: Option<i64>,
+    pub addresses: Vec<String>,
+    pub counterparties: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,     // address -> label or contact name
+}
+
+// scan the chain from genesis for transactions touching the tracked pub_key_hashes,
+// most recent first
+pub fn list_transactions(bc: &Blockchain, tracked: &HashSet<Vec<u8>>) -> Result<Vec<WalletTx>> {
+    bc.check_unpruned("the wallet history")?;
+    let mut blocks: Vec<_> = bc.iter().collect();
+    blocks.reverse();
+    let tip_height = blocks.last().map_or(0, |block| block.get_height());
+
+    let mut outputs = HashMap::<String, Vec<TxOutput>>::new();
+    let mut history = Vec::new();
+    for block in &blocks {
+        for tx in block.get_transactions() {
+            outputs.insert(tx.id.clone(), tx.vout.clone());
+
+            let mut debit: i64 = 0;
+            let mut input_total: Option<i64> = Some(0);
+            let mut addresses = Vec::new();
+            let mut senders = Vec::new();
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    let prev = outputs.get(&vin.txid)
+                        .and_then(|vout| usize::try_from(vin.vout).ok().and_then(|idx| vout.get(idx)));
+                    let mut pub_key_hash = vin.pub_key.clone();
+                    Wallet::hash_pub_key(&mut pub_key_hash);
+                    match prev {
+                        Some(out) => {
+                            input_total = input_total.map(|total| total + out.value as i64);
+                            if tracked.contains(&out.pub_key_hash) {
+                                debit += out.value as i64;
+                                push_unique(&mut addresses, Wallet::address_of(out.pub_key_hash.clone()));
+                            } else {
+                                push_unique(&mut senders, Wallet::address_of(pub_key_hash));
+                            }
+                        }
+                        None => {
+                            input_total = None;
+                            push_unique(&mut senders, Wallet::address_of(pub_key_hash));
+                        }
+                    }
+                }
+            }
+
+            let mut credit: i64 = 0;
+            let mut output_total: i64 = 0;
+            let mut recipients = Vec::new();
+            for out in tx.vout.iter().filter(|out| !out.is_data()) {
+                output_total += out.value as i64;
+                let address = Wallet::address_of(out.pub_key_hash.clone());
+                if tracked.contains(&out.pub_key_hash) {
+                    credit += out.value as i64;
+                    push_unique(&mut addresses, address);
+                } else {
+                    push_unique(&mut recipients, address);
+                }
+            }
+            if debit == 0 && credit == 0 {
+                continue;
+            }
+
+            let (category, counterparties, fee) = if tx.is_coinbase() {
+                ("coinbase", Vec::new(), None)
+            } else if debit > 0 {
+                let fee = input_total.map(|total| total - output_total);
+                let category = if recipients.is_empty() { "self" } else { "send" };
+                (category, recipients, fee)
+            } else {
+                ("receive", senders, None)
+            };
+            history.push(WalletTx {
+                txid: tx.id.clone(),
+                block_hash: block.get_hash(),
+                height: block.get_height(),
+                confirmations: tip_height - block.get_height() + 1,
+                timestamp: block.get_timestamp(),
+                category: category.to_string(),
+                amount: credit - debit,
+                fee,
+                addresses,
+                counterparties,
+                labels: BTreeMap::new(),
+            });
+        }
+    }
+    history.reverse();
+    Ok(history)
+}
+
+fn push_unique(list: &mut Vec<String>, address: String) {
+    if !list.contains(&address) {
+        list.push(address);
+    }
+}
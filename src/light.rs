@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use crate::block::{Block, BlockHeader};
+use crate::errors::Result;
+use crate::filters::{outpoint_item, BlockFilter};
+use crate::headers::HeaderChain;
+use crate::server::{rpc, Message};
+use crate::transaction::Transaction;
+
+// headers and filters asked at once
+const BATCH_SIZE: usize = 500;
+
+// a wallet keeping no chain: it checks the headers served by a node, finds the blocks
+// paying or spent by its addresses through their filters and downloads only those
+pub struct LightClient {
+    node: String,
+}
+
+#[derive(Default)]
+pub struct Scan {
+    pub blocks: usize,                          // blocks in the chain
+    pub matched: Vec<(BlockHeader, bool)>,      // blocks downloaded, false for a false positive
+    pub utxos: HashMap<(String, i32), i32>,     // unspent outputs found, (txid, vout) -> value
+}
+
+// a coin of the wallet found in SPV mode
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpvCoin {
+    pub txid: String,
+    pub vout: i32,
+    pub value: i32,
+    pub pub_key_hash: Vec<u8>,
+    pub height: i32,
+}
+
+#[derive(Default)]
+pub struct SpvUpdate {
+    pub received: Vec<SpvCoin>,
+    pub spent: Vec<SpvCoin>,
+}
+
+// state of the SPV mode besides the header chain: the unspent coins of the wallet,
+// each proved by a merkle proof against a stored header, and how far blocks were scanned
+// storage:
+// key: txid-vout, value: binary of SpvCoin
+// key: "SCANNED", value: big endian height of the last scanned block
+pub struct SpvWallet {}
+
+impl SpvWallet {
+    const PATH: &'static str = "data/spv";
+
+    pub fn get_coins(&self) -> Result<Vec<SpvCoin>> {
+        let db = sled::open(SpvWallet::PATH)?;
+        let mut coins = Vec::new();
+        for kv in db.iter() {
+            let (key, value) = kv?;
+            if &key[..] != b"SCANNED" {
+                coins.push(bincode::deserialize(&value)?);
+            }
+        }
+        Ok(coins)
+    }
+
+    pub fn get_scanned(&self) -> Result<i32> {
+        let db = sled::open(SpvWallet::PATH)?;
+        match db.get("SCANNED")? {
+            Some(height) => Ok(i32::from_be_bytes(height.as_ref().try_into()?)),
+            None => Ok(-1),
+        }
+    }
+
+    // apply the scan of the blocks up to height
+    fn save(&self, update: &SpvUpdate, height: i32) -> Result<()> {
+        let db = sled::open(SpvWallet::PATH)?;
+        let mut batch = sled::Batch::default();
+        for coin in &update.received {
+            batch.insert(format!("{}-{}", coin.txid, coin.vout).as_bytes(), bincode::serialize(coin)?);
+        }
+        // after the insertions, so a coin received and spent in the same blocks goes away
+        for coin in &update.spent {
+            batch.remove(format!("{}-{}", coin.txid, coin.vout).as_bytes());
+        }
+        batch.insert("SCANNED", &height.to_be_bytes());
+        db.apply_batch(batch)?;
+        db.flush()?;
+        Ok(())
+    }
+}
+
+impl LightClient {
+    pub fn new(node: &str) -> LightClient {
+        LightClient { node: node.to_string() }
+    }
+
+    // the header chain of the node from the genesis, linkage and proof of work checked
+    pub fn get_headers(&self) -> Result<Vec<BlockHeader>> {
+        let mut headers: Vec<BlockHeader> = Vec::new();
+        loop {
+            let locator = headers.last().map(|header| vec![header.hash.clone()]).unwrap_or_default();
+            let batch = match rpc(&self.node, &Message::GetHeaders(locator))? {
+                Message::Headers(batch) => batch,
+                msg => return Err(format_err!("expect headers from {}, got {:?}", self.node, msg)),
+            };
+            if batch.is_empty() {
+                return Ok(headers);
+            }
+            for header in batch {
+                let (prev_hash, height) = match headers.last() {
+                    Some(prev) => (prev.hash.clone(), prev.height + 1),
+                    None => (String::new(), 0),
+                };
+                if header.prev_block_hash != prev_hash || header.height != height {
+                    return Err(format_err!("header {} doesn't extend the chain at height {}", header.hash, height));
+                }
+                header.check_pow()?;
+                headers.push(header);
+            }
+        }
+    }
+
+    // find the outputs paying pub_key_hashes that are still unspent; the outpoints
+    // found are watched too, so the filters reveal the blocks spending them
+    pub fn scan(&self, pub_key_hashes: &[Vec<u8>]) -> Result<Scan> {
+        let headers = self.get_headers()?;
+        let mut watched = pub_key_hashes.to_vec();
+        let mut scan = Scan { blocks: headers.len(), ..Scan::default() };
+        for chunk in headers.chunks(BATCH_SIZE) {
+            let hashes: Vec<String> = chunk.iter().map(|header| header.hash.clone()).collect();
+            for (header, filter) in chunk.iter().zip(self.get_filters(&hashes)?) {
+                if !filter.match_any(&watched)? {
+                    continue;
+                }
+                let block = self.get_block(header)?;
+                let relevant = Self::apply_block(&block, pub_key_hashes, &mut watched, &mut scan.utxos);
+                scan.matched.push((header.clone(), relevant));
+            }
+        }
+        Ok(scan)
+    }
+
+    // the filters of the blocks, in the same order
+    fn get_filters(&self, hashes: &[String]) -> Result<Vec<BlockFilter>> {
+        let filters = match rpc(&self.node, &Message::GetCFilters(hashes.to_vec()))? {
+            Message::CFilters(filters) if filters.len() == hashes.len() => filters,
+            msg => return Err(format_err!("expect {} filters from {}, got {:?}", hashes.len(), self.node, msg)),
+        };
+        for (hash, filter) in hashes.iter().zip(&filters) {
+            if filter.block_hash != *hash {
+                return Err(format_err!("expect the filter of block {}, got {}", hash, filter.block_hash));
+            }
+        }
+        Ok(filters)
+    }
+
+    // a block of the node, checked against its header
+    pub fn get_block(&self, header: &BlockHeader) -> Result<Block> {
+        match rpc(&self.node, &Message::GetBlocks(vec![header.hash.clone()]))? {
+            Message::Block(block) if block.get_header()? == *header => Ok(block),
+            Message::Block(_) => Err(format_err!("block {} doesn't match its header", header.hash)),
+            msg => Err(format_err!("expect block {}, got {:?}", header.hash, msg)),
+        }
+    }
+
+    // record the outputs of block paying pub_key_hashes and drop those it spends,
+    // false if the block has none
+    fn apply_block(block: &Block, pub_key_hashes: &[Vec<u8>], watched: &mut Vec<Vec<u8>>,
+                   utxos: &mut HashMap<(String, i32), i32>) -> bool {
+        let mut relevant = false;
+        for tx in block.get_transactions() {
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    relevant |= utxos.remove(&(vin.txid.clone(), vin.vout)).is_some();
+                }
+            }
+            for (vout, out) in tx.vout.iter().enumerate() {
+                if pub_key_hashes.contains(&out.pub_key_hash) {
+                    utxos.insert((tx.id.clone(), vout as i32), out.value);
+                    watched.push(outpoint_item(&tx.id, vout as i32));
+                    relevant = true;
+                }
+            }
+        }
+        relevant
+    }
+
+    // SPV mode: store the validated header chain of the node, then look for payments
+    // to pub_key_hashes and spends of the known coins in the blocks not scanned yet,
+    // asking the node for the matching transactions with a merkle proof against
+    // the stored header instead of the whole block
+    pub fn sync_spv(&self, pub_key_hashes: &[Vec<u8>]) -> Result<SpvUpdate> {
+        let chain = HeaderChain{};
+        loop {
+            let headers = match rpc(&self.node, &Message::GetHeaders(chain.locator()?))? {
+                Message::Headers(headers) => headers,
+                msg => return Err(format_err!("expect headers from {}, got {:?}", self.node, msg)),
+            };
+            let mut added = 0;
+            for header in &headers {
+                if chain.add(header)? {
+                    added += 1;
+                }
+            }
+            if added == 0 {
+                break;
+            }
+        }
+
+        let wallet = SpvWallet{};
+        let mut coins: HashMap<(String, i32), SpvCoin> = wallet.get_coins()?.into_iter()
+            .map(|coin| ((coin.txid.clone(), coin.vout), coin))
+            .collect();
+        let mut watched = pub_key_hashes.to_vec();
+        watched.extend(coins.keys().map(|(txid, vout)| outpoint_item(txid, *vout)));
+        let mut update = SpvUpdate::default();
+        let best = chain.get_best_height()?;
+        let mut height = wallet.get_scanned()? + 1;
+        while height <= best {
+            let last = best.min(height + BATCH_SIZE as i32 - 1);
+            let mut hashes = Vec::new();
+            for h in height..=last {
+                hashes.push(chain.hash_at(h)?.ok_or_else(|| format_err!("missing header at height {}", h))?);
+            }
+            let mut batch = SpvUpdate::default();
+            for filter in self.get_filters(&hashes)? {
+                if !filter.match_any(&watched)? {
+                    continue;
+                }
+                let header = chain.get_header(&filter.block_hash)?
+                    .ok_or_else(|| format_err!("missing header {}", filter.block_hash))?;
+                for tx in self.get_proved_transactions(&header, &watched)? {
+                    if !tx.is_coinbase() {
+                        for vin in &tx.vin {
+                            if let Some(coin) = coins.remove(&(vin.txid.clone(), vin.vout)) {
+                                batch.spent.push(coin);
+                            }
+                        }
+                    }
+                    for (vout, out) in tx.vout.iter().enumerate() {
+                        if pub_key_hashes.contains(&out.pub_key_hash) {
+                            let coin = SpvCoin {
+                                txid: tx.id.clone(),
+                                vout: vout as i32,
+                                value: out.value,
+                                pub_key_hash: out.pub_key_hash.clone(),
+                                height: header.height,
+                            };
+                            watched.push(outpoint_item(&coin.txid, coin.vout));
+                            coins.insert((coin.txid.clone(), coin.vout), coin.clone());
+                            batch.received.push(coin);
+                        }
+                    }
+                }
+            }
+            wallet.save(&batch, last)?;
+            update.received.extend(batch.received);
+            update.spent.extend(batch.spent);
+            height = last + 1;
+        }
+        Ok(update)
+    }
+
+    // the transactions of a block matching items, checked against the merkle root of its header
+    fn get_proved_transactions(&self, header: &BlockHeader, items: &[Vec<u8>]) -> Result<Vec<Transaction>> {
+        let msg = Message::GetMerkleBlock { hash: header.hash.clone(), items: items.to_vec() };
+        let (txs, proof) = match rpc(&self.node, &msg)? {
+            Message::MerkleBlock { hash, txs, proof } if hash == header.hash => (txs, proof),
+            msg => return Err(format_err!("expect the transactions of block {}, got {:?}", header.hash, msg)),
+        };
+        match proof {
+            Some(proof) if header.verify_merkle_proof(&txs, &proof)? => Ok(txs),
+            None if txs.is_empty() => Ok(txs),
+            _ => Err(format_err!("invalid merkle proof for block {} from {}", header.hash, self.node)),
+        }
+    }
+}
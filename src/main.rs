@@ -7,10 +7,25 @@ mod transaction;
 mod tx;
 mod wallet;
 mod utxoset;
+mod psbt;
+mod coinselect;
+mod history;
+mod mempool;
+mod headers;
+mod peers;
+mod compact;
+mod filters;
+mod light;
+mod snapshot;
+mod explorer;
+mod server;
+#[cfg(test)]
+mod testutil;
 
 use crate::errors::Result;
 
 fn main() -> Result<()>{
+    env_logger::init();
     let mut cli = cli::Cli::new()?;
     cli.run()?;
 
@@ -0,0 +1,391 @@
+use std::collections::{HashMap, HashSet};
+use failure::format_err;
+use log::info;
+use crate::block::Block;
+use crate::errors::{Result, TxError};
+use crate::transaction::Transaction;
+use crate::utxoset::UTXOSet;
+
+// size budget of the pending transactions picked for a block, in bytes
+pub const MAX_BLOCK_SIZE: usize = 100_000;
+
+// transactions waiting to be mined
+// storage:
+// key: txid, value: binary of Transaction
+pub struct Mempool {}
+
+impl Mempool {
+    const PATH: &'static str = "data/mempool";
+
+    // accept a transaction spending confirmed outputs or outputs of pending transactions
+    // that nothing else in the pool spends yet, or replacing the replaceable pending
+    // transactions it conflicts with, together with their descendants
+    pub fn add(&self, tx: &Transaction, utxo_set: &UTXOSet) -> Result<()> {
+        if tx.is_coinbase() {
+            return Err(format_err!("coinbase transaction {} can only be mined", tx.id));
+        }
+        tx.check_id()?;
+        let pool = self.load()?;
+        let mut replaced = HashSet::new();
+        for pending in pool.values() {
+            let conflict = tx.vin.iter()
+                .find(|vin| pending.vin.iter().any(|p| p.txid == vin.txid && p.vout == vin.vout));
+            if let Some(vin) = conflict {
+                if !pending.replaceable {
+                    return Err(TxError::MempoolConflict { txid: vin.txid.clone(), vout: vin.vout }.into());
+                }
+                replaced.insert(pending.id.clone());
+                replaced.extend(descendants(&pool, &pending.id));
+            }
+        }
+
+        // the transaction is checked after its pending ancestors, as if mined with them
+        let mut package = Vec::new();
+        for id in ancestors(&pool, tx) {
+            if replaced.contains(&id) {
+                return Err(format_err!("transaction {} spends outputs of {} which it replaces", tx.id, id));
+            }
+            package.push(pool[&id].clone());
+        }
+        package.push(tx.clone());
+        utxo_set.verify_transactions(&package)?;
+        if !replaced.is_empty() {
+            let replaced: Vec<&Transaction> = replaced.iter().map(|id| &pool[id]).collect();
+            Mempool::check_replacement(tx, &replaced, &pool, utxo_set)?;
+        }
+
+        let db = sled::open(Mempool::PATH)?;
+        for id in &replaced {
+            db.remove(id.as_bytes())?;
+            info!("transaction {} replaced by {}", id, tx.id);
+        }
+        db.insert(tx.id.as_bytes(), bincode::serialize(tx)?)?;
+        db.flush()?;
+        info!("add transaction {} to mempool", tx.id);
+        Ok(())
+    }
+
+    // a replacement must pay more than all the transactions it evicts together,
+    // and at a higher fee rate than each of them
+    fn check_replacement(tx: &Transaction, replaced: &[&Transaction], pool: &HashMap<String, Transaction>, utxo_set: &UTXOSet) -> Result<()> {
+        let fee = pool_fee(pool, tx, utxo_set)?;
+        let size = tx.size()? as i64;
+        let mut replaced_fee = 0;
+        let mut higher_rate = true;
+        let mut replaced_rate = 0;
+        for old in replaced {
+            let old_fee = pool_fee(pool, old, utxo_set)?;
+            let old_size = old.size()? as i64;
+            replaced_fee += old_fee;
+            higher_rate &= fee * old_size > old_fee * size;
+            replaced_rate = replaced_rate.max(fee_rate(old_fee, old_size));
+        }
+        if fee <= replaced_fee || !higher_rate {
+            return Err(TxError::ReplacementFee {
+                fee,
+                rate: fee_rate(fee, size),
+                replaced_fee,
+                replaced_rate,
+            }.into());
+        }
+        Ok(())
+    }
+
+    pub fn get_transactions(&self) -> Result<Vec<Transaction>> {
+        let db = sled::open(Mempool::PATH)?;
+        let mut txs = Vec::new();
+        for kv in db.iter() {
+            let (_, v) = kv?;
+            txs.push(bincode::deserialize::<Transaction>(&v)?);
+        }
+        Ok(txs)
+    }
+
+    // the pending transactions by txid
+    pub fn load(&self) -> Result<HashMap<String, Transaction>> {
+        Ok(self.get_transactions()?.into_iter().map(|tx| (tx.id.clone(), tx)).collect())
+    }
+
+    pub fn get_transaction(&self, txid: &str) -> Result<Option<Transaction>> {
+        let db = sled::open(Mempool::PATH)?;
+        match db.get(txid.as_bytes())? {
+            Some(v) => Ok(Some(bincode::deserialize(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    // fee of a transaction whose inputs may spend outputs of pending transactions
+    pub fn get_fee(&self, tx: &Transaction, utxo_set: &UTXOSet) -> Result<i64> {
+        pool_fee(&self.load()?, tx, utxo_set)
+    }
+
+    // outputs spent by pending transactions, as (txid, vout)
+    pub fn spent_outpoints(&self) -> Result<HashSet<(String, i32)>> {
+        let mut spent = HashSet::new();
+        for tx in self.get_transactions()? {
+            for vin in &tx.vin {
+                spent.insert((vin.txid.clone(), vin.vout));
+            }
+        }
+        Ok(spent)
+    }
+
+    // pick pending transactions for a block by ancestor package fee rate, so a child
+    // paying a high fee brings its low fee parents along; parents come before their
+    // children and the total fee is returned for the coinbase
+    pub fn select_for_block(&self, utxo_set: &UTXOSet, max_size: usize) -> Result<(Vec<Transaction>, i64)> {
+        let pool = self.load()?;
+        let mut fees = HashMap::new();
+        let mut sizes = HashMap::new();
+        for tx in pool.values() {
+            fees.insert(tx.id.clone(), pool_fee(&pool, tx, utxo_set)?);
+            sizes.insert(tx.id.clone(), tx.size()? as i64);
+        }
+
+        let mut selected = Vec::new();
+        let mut included = HashSet::new();
+        let mut skipped = HashSet::new();
+        let mut block_size = 0;
+        let mut total_fee = 0;
+        loop {
+            // best package: a transaction and its ancestors not in the block yet
+            let mut best: Option<(Vec<String>, i64, i64)> = None;
+            for tx in pool.values() {
+                if included.contains(&tx.id) || skipped.contains(&tx.id) {
+                    continue;
+                }
+                let mut package: Vec<String> = ancestors(&pool, tx).into_iter()
+                    .filter(|id| !included.contains(id))
+                    .collect();
+                package.push(tx.id.clone());
+                let fee: i64 = package.iter().map(|id| fees[id]).sum();
+                let size: i64 = package.iter().map(|id| sizes[id]).sum();
+                let better = match &best {
+                    Some((_, best_fee, best_size)) => fee * best_size > best_fee * size,
+                    None => true,
+                };
+                if better {
+                    best = Some((package, fee, size));
+                }
+            }
+            let (package, fee, size) = match best {
+                Some(best) => best,
+                None => break,
+            };
+            if block_size + size > max_size as i64 {
+                skipped.insert(package[package.len() - 1].clone());
+                continue;
+            }
+            for id in package {
+                selected.push(pool[&id].clone());
+                included.insert(id);
+            }
+            block_size += size;
+            total_fee += fee;
+        }
+        Ok((selected, total_fee))
+    }
+
+    // drop the transactions mined in block, those now conflicting with it and
+    // the descendants of the conflicting ones
+    pub fn remove_block(&self, block: &Block) -> Result<()> {
+        let mut spent = HashSet::new();
+        let mut mined = HashSet::new();
+        for tx in block.get_transactions() {
+            mined.insert(tx.id.clone());
+            for vin in &tx.vin {
+                spent.insert((vin.txid.clone(), vin.vout));
+            }
+        }
+        let pool = self.load()?;
+        let mut removed = HashSet::new();
+        for tx in pool.values() {
+            if mined.contains(&tx.id) {
+                removed.insert(tx.id.clone());
+            } else if tx.vin.iter().any(|vin| spent.contains(&(vin.txid.clone(), vin.vout))) {
+                removed.insert(tx.id.clone());
+                removed.extend(descendants(&pool, &tx.id));
+            }
+        }
+        let db = sled::open(Mempool::PATH)?;
+        for id in &removed {
+            db.remove(id.as_bytes())?;
+        }
+        db.flush()?;
+        Ok(())
+    }
+}
+
+// pending transactions tx depends on, directly or not, parents before children
+pub fn ancestors(pool: &HashMap<String, Transaction>, tx: &Transaction) -> Vec<String> {
+    let mut ordered = Vec::new();
+    let mut visited = HashSet::new();
+    visit_parents(pool, tx, &mut visited, &mut ordered);
+    ordered
+}
+
+fn visit_parents(pool: &HashMap<String, Transaction>, tx: &Transaction, visited: &mut HashSet<String>, ordered: &mut Vec<String>) {
+    for vin in &tx.vin {
+        if let Some(parent) = pool.get(&vin.txid) {
+            if visited.insert(parent.id.clone()) {
+                visit_parents(pool, parent, visited, ordered);
+                ordered.push(parent.id.clone());
+            }
+        }
+    }
+}
+
+// pending transactions spending outputs of txid, directly or not
+pub fn descendants(pool: &HashMap<String, Transaction>, txid: &str) -> HashSet<String> {
+    let mut found = HashSet::new();
+    let mut queue = vec![txid.to_string()];
+    while let Some(id) = queue.pop() {
+        for tx in pool.values() {
+            if !found.contains(&tx.id) && tx.vin.iter().any(|vin| vin.txid == id) {
+                found.insert(tx.id.clone());
+                queue.push(tx.id.clone());
+            }
+        }
+    }
+    found
+}
+
+fn pool_fee(pool: &HashMap<String, Transaction>, tx: &Transaction, utxo_set: &UTXOSet) -> Result<i64> {
+    let mut fee: i64 = 0;
+    for vin in &tx.vin {
+        let value = match pool.get(&vin.txid) {
+            Some(parent) => usize::try_from(vin.vout).ok()
+                .and_then(|idx| parent.vout.get(idx))
+                .map(|out| out.value),
+            None => utxo_set.find_output(&vin.txid, vin.vout)?.map(|out| out.value),
+        };
+        fee += value.ok_or_else(|| TxError::UnknownOutput { txid: vin.txid.clone(), vout: vin.vout })? as i64;
+    }
+    for out in &tx.vout {
+        fee -= out.value as i64;
+    }
+    Ok(fee)
+}
+
+// fee per 1000 bytes
+pub fn fee_rate(fee: i64, size: i64) -> i64 {
+    fee * 1000 / size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{enter_temp_dir, new_chain, spend, tip_reward, wallet};
+    use crate::wallet::Wallet;
+
+    // a chain whose second block splits the genesis reward of miner in two
+    // confirmed coins of 50, returned as their outpoints
+    fn two_coins(miner: &Wallet) -> (UTXOSet, (String, i32), (String, i32)) {
+        let mut utxo_set = new_chain(miner);
+        let reward = tip_reward(&utxo_set);
+        let split = spend(miner, &[(reward, 0)], &[(miner.get_address(), 50), (miner.get_address(), 50)], false);
+        utxo_set.add_block(vec![split.clone()]).unwrap();
+        (utxo_set, (split.id.clone(), 0), (split.id, 1))
+    }
+
+    fn pool_ids() -> HashSet<String> {
+        Mempool{}.load().unwrap().into_keys().collect()
+    }
+
+    fn is_replacement_fee_error(result: Result<()>) -> bool {
+        matches!(result.unwrap_err().downcast_ref::<TxError>(), Some(TxError::ReplacementFee { .. }))
+    }
+
+    #[test]
+    fn replacement_must_pay_more_than_all_conflicts_at_a_higher_rate_than_each() {
+        let _dir = enter_temp_dir();
+        let (miner, payee) = (wallet(1), wallet(2).get_address());
+        let (utxo_set, first, second) = two_coins(&miner);
+        let both = [first, second];
+        let low = spend(&miner, &both[..1], &[(payee.clone(), 49)], true);
+        let high = spend(&miner, &both[1..], &[(payee.clone(), 20)], true);
+        Mempool{}.add(&low, &utxo_set).unwrap();
+        Mempool{}.add(&high, &utxo_set).unwrap();
+
+        // the same fee as the one conflicting transaction
+        let same_fee = spend(&miner, &both[1..], &[(miner.get_address(), 20)], true);
+        assert!(is_replacement_fee_error(Mempool{}.add(&same_fee, &utxo_set)));
+
+        // fee 32 is more than 1 + 30 together, but at a lower rate than high pays
+        let low_rate = spend(&miner, &both, &[(payee.clone(), 68)], true);
+        assert!(32 * (high.size().unwrap() as i64) < 30 * low_rate.size().unwrap() as i64);
+        assert!(is_replacement_fee_error(Mempool{}.add(&low_rate, &utxo_set)));
+        assert_eq!(pool_ids(), HashSet::from([low.id.clone(), high.id.clone()]));
+
+        let replacement = spend(&miner, &both, &[(payee, 20)], true);
+        Mempool{}.add(&replacement, &utxo_set).unwrap();
+        assert_eq!(pool_ids(), HashSet::from([replacement.id]));
+    }
+
+    #[test]
+    fn non_replaceable_transaction_is_not_replaced() {
+        let _dir = enter_temp_dir();
+        let (miner, payee) = (wallet(1), wallet(2).get_address());
+        let (utxo_set, first, _) = two_coins(&miner);
+        let coin = [first];
+        let original = spend(&miner, &coin, &[(payee.clone(), 49)], false);
+        Mempool{}.add(&original, &utxo_set).unwrap();
+        let replacement = spend(&miner, &coin, &[(payee, 10)], true);
+        let err = Mempool{}.add(&replacement, &utxo_set).unwrap_err();
+        assert!(matches!(err.downcast_ref::<TxError>(), Some(TxError::MempoolConflict { .. })), "{}", err);
+        assert_eq!(pool_ids(), HashSet::from([original.id]));
+    }
+
+    // a parent paying 1, its child paying 30 and an unrelated transaction paying 8
+    fn packages(miner: &Wallet) -> (UTXOSet, Vec<Transaction>) {
+        let payee = wallet(2);
+        let (utxo_set, first, second) = two_coins(miner);
+        let both = [first, second];
+        let parent = spend(miner, &both[..1], &[(payee.get_address(), 49)], false);
+        let child = spend(&payee, &[(parent.id.clone(), 0)], &[(miner.get_address(), 19)], false);
+        let mid = spend(miner, &both[1..], &[(payee.get_address(), 42)], false);
+        for tx in [&parent, &child, &mid] {
+            Mempool{}.add(tx, &utxo_set).unwrap();
+        }
+        (utxo_set, vec![parent, child, mid])
+    }
+
+    fn ids(txs: &[Transaction]) -> Vec<String> {
+        txs.iter().map(|tx| tx.id.clone()).collect()
+    }
+
+    #[test]
+    fn child_paying_a_high_fee_brings_its_parent_first() {
+        let _dir = enter_temp_dir();
+        let (utxo_set, txs) = packages(&wallet(1));
+        let (parent, child, mid) = (&txs[0], &txs[1], &txs[2]);
+        let size = |tx: &Transaction| tx.size().unwrap() as i64;
+        // alone the parent pays less than mid, with its child it pays more
+        assert!(size(mid) < 8 * size(parent));
+        assert!(8 * (size(parent) + size(child)) < 31 * size(mid));
+
+        let (selected, fee) = Mempool{}.select_for_block(&utxo_set, MAX_BLOCK_SIZE).unwrap();
+        assert_eq!(ids(&selected), ids(&[parent.clone(), child.clone(), mid.clone()]));
+        assert_eq!(fee, 39);
+    }
+
+    #[test]
+    fn packages_over_the_block_size_are_left_out() {
+        let _dir = enter_temp_dir();
+        let (utxo_set, txs) = packages(&wallet(1));
+        let sizes: Vec<usize> = txs.iter().map(|tx| tx.size().unwrap()).collect();
+
+        let (selected, fee) = Mempool{}.select_for_block(&utxo_set, sizes[0] + sizes[1] + sizes[2] - 1).unwrap();
+        assert_eq!(ids(&selected), ids(&txs[..2]));
+        assert_eq!(fee, 31);
+
+        // the package doesn't fit, the parent alone doesn't fit after mid
+        let (selected, fee) = Mempool{}.select_for_block(&utxo_set, sizes[0] + sizes[1] - 1).unwrap();
+        assert_eq!(ids(&selected), ids(&txs[2..]));
+        assert_eq!(fee, 8);
+
+        let (selected, fee) = Mempool{}.select_for_block(&utxo_set, 0).unwrap();
+        assert!(selected.is_empty());
+        assert_eq!(fee, 0);
+    }
+}
@@ -0,0 +1,232 @@
+use std::time::SystemTime;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use crate::errors::Result;
+
+// misbehavior score at which a peer gets banned
+pub const BAN_THRESHOLD: i32 = 100;
+pub const DEFAULT_BAN_SECONDS: u64 = 24 * 60 * 60;
+pub const MAX_ADDRS: usize = 1000;      // addresses per Addr message
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: String,
+    pub last_seen: u128,            // unix millis, 0 if never connected
+    pub score: i32,                 // misbehavior score
+    pub banned_until: Option<u128>, // unix millis
+    pub connected: bool,            // only meaningful in getpeerinfo replies
+}
+
+// addresses of the peers the node heard of, with their misbehavior and bans
+// storage:
+// key: peer address (host:port, or a bare host for bans), value: binary of PeerInfo
+pub struct PeerManager {
+    db: sled::Db,
+}
+
+impl PeerManager {
+    const PATH: &'static str = "data/peers";
+
+    pub fn open() -> Result<PeerManager> {
+        Ok(PeerManager { db: sled::open(PeerManager::PATH)? })
+    }
+
+    pub fn get(&self, addr: &str) -> Result<Option<PeerInfo>> {
+        match self.db.get(addr)? {
+            Some(value) => Ok(Some(bincode::deserialize(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_all(&self) -> Result<Vec<PeerInfo>> {
+        let mut peers = Vec::new();
+        for kv in self.db.iter() {
+            let (_, value) = kv?;
+            peers.push(bincode::deserialize(&value)?);
+        }
+        Ok(peers)
+    }
+
+    // remember a peer address, true if it was unknown
+    pub fn add(&self, addr: &str) -> Result<bool> {
+        if self.db.contains_key(addr)? {
+            return Ok(false);
+        }
+        self.save(&PeerInfo {
+            addr: addr.to_string(),
+            last_seen: 0,
+            score: 0,
+            banned_until: None,
+            connected: false,
+        })?;
+        Ok(true)
+    }
+
+    // remember the addresses of an Addr message but the node's own, at most MAX_ADDRS
+    // of them; returns the ones that were unknown, to gossip further
+    pub fn add_gossiped(&self, addrs: Vec<String>, own: &str) -> Result<Vec<String>> {
+        let mut learned = Vec::new();
+        for addr in addrs.into_iter().take(MAX_ADDRS) {
+            if addr != own && self.add(&addr)? {
+                learned.push(addr);
+            }
+        }
+        Ok(learned)
+    }
+
+    pub fn seen(&self, addr: &str) -> Result<()> {
+        self.add(addr)?;
+        if let Some(mut peer) = self.get(addr)? {
+            peer.last_seen = now_millis()?;
+            self.save(&peer)?;
+        }
+        Ok(())
+    }
+
+    // addresses to connect to or to gossip, most recently seen first
+    pub fn get_addrs(&self, max: usize) -> Result<Vec<String>> {
+        let mut peers = Vec::new();
+        for peer in self.get_all()? {
+            if !peer.addr.contains(':') || self.is_banned(&peer.addr)? {
+                continue;
+            }
+            peers.push(peer);
+        }
+        peers.sort_by_key(|peer| std::cmp::Reverse(peer.last_seen));
+        Ok(peers.into_iter().take(max).map(|peer| peer.addr).collect())
+    }
+
+    // raise the misbehavior score of a peer, banning it at the threshold;
+    // true if the peer is now banned
+    pub fn misbehaving(&self, addr: &str, howmuch: i32, reason: &str) -> Result<bool> {
+        self.add(addr)?;
+        let mut peer = match self.get(addr)? {
+            Some(peer) => peer,
+            None => return Ok(false),
+        };
+        peer.score += howmuch;
+        warn!("peer {} misbehaving ({}): score {}", addr, reason, peer.score);
+        let banned = peer.score >= BAN_THRESHOLD;
+        if banned {
+            peer.banned_until = Some(now_millis()? + DEFAULT_BAN_SECONDS as u128 * 1000);
+            warn!("ban peer {}", addr);
+        }
+        self.save(&peer)?;
+        Ok(banned)
+    }
+
+    // ban addr for seconds, 0 lifts the ban and clears the score
+    pub fn set_ban(&self, addr: &str, seconds: u64) -> Result<PeerInfo> {
+        self.add(addr)?;
+        let mut peer = self.get(addr)?.unwrap();
+        if seconds == 0 {
+            peer.banned_until = None;
+            peer.score = 0;
+        } else {
+            peer.banned_until = Some(now_millis()? + seconds as u128 * 1000);
+        }
+        self.save(&peer)?;
+        Ok(peer)
+    }
+
+    // a ban applies to the address and, for a bare host, to every port of it
+    pub fn is_banned(&self, addr: &str) -> Result<bool> {
+        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        let now = now_millis()?;
+        for key in [addr, host] {
+            if let Some(peer) = self.get(key)? {
+                if peer.banned_until.is_some_and(|until| until > now) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    fn save(&self, peer: &PeerInfo) -> Result<()> {
+        self.db.insert(peer.addr.as_bytes(), bincode::serialize(peer)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+fn now_millis() -> Result<u128> {
+    Ok(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::enter_temp_dir;
+
+    #[test]
+    fn misbehaving_peer_is_banned_at_the_threshold() {
+        let _dir = enter_temp_dir();
+        let peers = PeerManager::open().unwrap();
+        for _ in 0..9 {
+            assert!(!peers.misbehaving("10.0.0.1:3000", 10, "invalid transaction").unwrap());
+        }
+        assert_eq!(peers.get("10.0.0.1:3000").unwrap().unwrap().score, 90);
+        assert!(!peers.is_banned("10.0.0.1:3000").unwrap());
+        assert!(peers.misbehaving("10.0.0.1:3000", 10, "invalid transaction").unwrap());
+        assert!(peers.is_banned("10.0.0.1:3000").unwrap());
+        // the ban of an address leaves the other ports of the host alone
+        assert!(!peers.is_banned("10.0.0.1:3001").unwrap());
+
+        // the ban of a bare host covers all its ports
+        assert!(peers.misbehaving("10.0.0.2", BAN_THRESHOLD, "invalid block").unwrap());
+        assert!(peers.is_banned("10.0.0.2:3000").unwrap());
+        peers.add("10.0.0.2:3000").unwrap();
+        peers.add("10.0.0.3:3000").unwrap();
+        assert_eq!(peers.get_addrs(10).unwrap(), vec!["10.0.0.3:3000".to_string()]);
+    }
+
+    #[test]
+    fn ban_expires_or_is_lifted() {
+        let _dir = enter_temp_dir();
+        let peers = PeerManager::open().unwrap();
+        peers.misbehaving("10.0.0.1:3000", BAN_THRESHOLD, "invalid block").unwrap();
+        let mut peer = peers.get("10.0.0.1:3000").unwrap().unwrap();
+        peer.banned_until = Some(now_millis().unwrap() - 1);
+        peers.save(&peer).unwrap();
+        assert!(!peers.is_banned("10.0.0.1:3000").unwrap());
+        assert_eq!(peers.get_addrs(10).unwrap(), vec!["10.0.0.1:3000".to_string()]);
+
+        assert!(peers.set_ban("10.0.0.1:3000", 60).unwrap().banned_until.is_some());
+        assert!(peers.is_banned("10.0.0.1:3000").unwrap());
+        let lifted = peers.set_ban("10.0.0.1:3000", 0).unwrap();
+        assert_eq!((lifted.banned_until, lifted.score), (None, 0));
+        assert!(!peers.is_banned("10.0.0.1:3000").unwrap());
+    }
+
+    #[test]
+    fn gossiped_addresses_are_limited_to_the_most_recently_seen() {
+        let _dir = enter_temp_dir();
+        let peers = PeerManager::open().unwrap();
+        for idx in 0..20 {
+            let addr = format!("10.0.0.{}:3000", idx);
+            peers.add(&addr).unwrap();
+            let mut peer = peers.get(&addr).unwrap().unwrap();
+            peer.last_seen = idx as u128;
+            peers.save(&peer).unwrap();
+        }
+        assert!(!peers.add("10.0.0.5:3000").unwrap());
+        assert_eq!(peers.get_addrs(3).unwrap(), vec!["10.0.0.19:3000", "10.0.0.18:3000", "10.0.0.17:3000"]);
+        assert_eq!(peers.get_addrs(100).unwrap().len(), 20);
+    }
+
+    #[test]
+    fn addr_message_adds_a_bounded_number_of_new_addresses() {
+        let _dir = enter_temp_dir();
+        let peers = PeerManager::open().unwrap();
+        peers.add("10.0.0.1:3000").unwrap();
+        let mut addrs: Vec<String> = (0..MAX_ADDRS + 10).map(|idx| format!("10.1.{}.{}:3000", idx / 256, idx % 256)).collect();
+        addrs.insert(0, "10.0.0.1:3000".to_string());
+        addrs.insert(1, "localhost:3000".to_string());
+        let learned = peers.add_gossiped(addrs.clone(), "localhost:3000").unwrap();
+        // the known address, the own one and the ones past the limit are left out
+        assert_eq!(learned, addrs[2..MAX_ADDRS].to_vec());
+        assert_eq!(peers.get_all().unwrap().len(), MAX_ADDRS - 1);
+        assert!(peers.add_gossiped(addrs, "localhost:3000").unwrap().is_empty());
+    }
+}
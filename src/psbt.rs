@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+use crypto::ed25519;
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use crate::errors::{Result, WalletError};
+use crate::transaction::Transaction;
+use crate::tx::TxOutput;
+use crate::wallet::Wallet;
+
+const MAGIC: &[u8] = b"psbt";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PsbtInput {
+    pub spent_output: TxOutput,
+    pub partial_sigs: BTreeMap<Vec<u8>, Vec<u8>>,  // public key -> signature
+}
+
+// partially signed transaction: the unsigned transaction plus everything each
+// party needs to sign its own inputs without access to the chain
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Psbt {
+    pub tx: Transaction,
+    pub inputs: Vec<PsbtInput>,
+}
+
+impl Psbt {
+    pub fn new(tx: Transaction, spent_outputs: Vec<TxOutput>) -> Result<Psbt> {
+        if tx.is_coinbase() {
+            return Err(format_err!("coinbase transaction can't be signed"));
+        }
+        if spent_outputs.len() != tx.vin.len() {
+            return Err(format_err!("expect {} spent outputs, got {}", tx.vin.len(), spent_outputs.len()));
+        }
+        let mut tx = tx;
+        for vin in &mut tx.vin {
+            vin.signature.clear();
+            vin.pub_key.clear();
+        }
+        tx.id = tx.compute_id()?;
+        let inputs = spent_outputs.into_iter()
+            .map(|spent_output| PsbtInput { spent_output, partial_sigs: BTreeMap::new() })
+            .collect();
+        Ok(Psbt { tx, inputs })
+    }
+
+    // add the wallet signature to every input spending an output locked to it
+    pub fn sign(&mut self, wallet: &Wallet) -> Result<usize> {
+        if !wallet.can_sign() {
+            return Err(WalletError::Locked.into());
+        }
+        let hashes = self.tx.signature_hashes(&self.prev_pub_key_hashes())?;
+        let pub_key_hash = wallet.get_pub_key_hash();
+        let mut signed = 0;
+        for (input, hash) in self.inputs.iter_mut().zip(hashes.iter()) {
+            if !input.spent_output.can_be_unlock_with(&pub_key_hash) {
+                continue;
+            }
+            let sig = ed25519::signature(hash.as_bytes(), &wallet.private_key);
+            input.partial_sigs.insert(wallet.public_key.clone(), sig.to_vec());
+            signed += 1;
+        }
+        Ok(signed)
+    }
+
+    // merge the signatures collected by another party on the same transaction,
+    // the id commits to all of it but the signatures
+    pub fn combine(&mut self, other: &Psbt) -> Result<()> {
+        if self.tx.id != other.tx.id {
+            return Err(format_err!("can't combine psbt of different transactions {} and {}",
+                self.tx.id, other.tx.id));
+        }
+        for (input, other) in self.inputs.iter_mut().zip(other.inputs.iter()) {
+            for (pub_key, sig) in &other.partial_sigs {
+                input.partial_sigs.insert(pub_key.clone(), sig.clone());
+            }
+        }
+        Ok(())
+    }
+
+    // move a valid signature of the output owner into each input of the transaction
+    pub fn finalize(&mut self) -> Result<bool> {
+        let hashes = self.tx.signature_hashes(&self.prev_pub_key_hashes())?;
+        for (idx, input) in self.inputs.iter().enumerate() {
+            let vin = &mut self.tx.vin[idx];
+            if !vin.signature.is_empty() {
+                continue;
+            }
+            for (pub_key, sig) in &input.partial_sigs {
+                let mut pub_key_hash = pub_key.clone();
+                Wallet::hash_pub_key(&mut pub_key_hash);
+                if input.spent_output.can_be_unlock_with(&pub_key_hash)
+                    && Wallet::verify_signature(hashes[idx].as_bytes(), pub_key, sig) {
+                    vin.pub_key = pub_key.clone();
+                    vin.signature = sig.clone();
+                    break;
+                }
+            }
+        }
+        Ok(self.is_finalized())
+    }
+
+    pub fn is_finalized(&self) -> bool {
+        self.tx.is_fully_signed()
+    }
+
+    pub fn extract(&self) -> Result<Transaction> {
+        if !self.is_finalized() {
+            let missing = self.tx.vin.iter().filter(|vin| vin.signature.is_empty()).count();
+            return Err(format_err!("psbt is missing signatures for {} inputs", missing));
+        }
+        self.tx.verify_signatures(&self.prev_pub_key_hashes())?;
+        Ok(self.tx.clone())
+    }
+
+    fn prev_pub_key_hashes(&self) -> Vec<Vec<u8>> {
+        self.inputs.iter().map(|input| input.spent_output.pub_key_hash.clone()).collect()
+    }
+
+    pub fn to_hex(&self) -> Result<String> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.append(&mut bincode::serialize(self)?);
+        Ok(hex::encode(bytes))
+    }
+
+    pub fn from_hex(data: &str) -> Result<Psbt> {
+        let bytes = hex::decode(data.trim())?;
+        if !bytes.starts_with(MAGIC) {
+            return Err(format_err!("not a psbt"));
+        }
+        let mut psbt: Psbt = bincode::deserialize(&bytes[MAGIC.len()..])?;
+        if psbt.inputs.len() != psbt.tx.vin.len() {
+            return Err(format_err!("psbt has {} inputs for {} transaction inputs",
+                psbt.inputs.len(), psbt.tx.vin.len()));
+        }
+        psbt.tx.id = psbt.tx.compute_id()?;
+        Ok(psbt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{enter_temp_dir, new_chain, spend, tip_reward, wallet};
+    use crate::utxoset::UTXOSet;
+
+    // a chain where miner and cosigner each own a coin of 50, and the hex of a
+    // psbt spending both to pay 90 to payee
+    fn shared_payment(miner: &Wallet, cosigner: &Wallet, payee: &Wallet) -> (UTXOSet, String) {
+        let mut utxo_set = new_chain(miner);
+        let reward = tip_reward(&utxo_set);
+        let split = spend(miner, &[(reward, 0)], &[(cosigner.get_address(), 50), (miner.get_address(), 50)], false);
+        utxo_set.add_block(vec![split.clone()]).unwrap();
+        let tx = Transaction::new_raw(&[(split.id.clone(), 0), (split.id.clone(), 1)], &[(payee.get_address(), 90)]).unwrap();
+        let spent_outputs = tx.vin.iter()
+            .map(|vin| utxo_set.find_output(&vin.txid, vin.vout).unwrap().unwrap())
+            .collect();
+        let hex = Psbt::new(tx, spent_outputs).unwrap().to_hex().unwrap();
+        (utxo_set, hex)
+    }
+
+    #[test]
+    fn signers_of_each_input_combine_into_a_valid_transaction() {
+        let _dir = enter_temp_dir();
+        let (miner, cosigner, payee) = (wallet(1), wallet(2), wallet(3));
+        let (utxo_set, hex) = shared_payment(&miner, &cosigner, &payee);
+
+        // each party signs its own input only
+        let mut first = Psbt::from_hex(&hex).unwrap();
+        assert_eq!(first.sign(&miner).unwrap(), 1);
+        assert_eq!(first.sign(&payee).unwrap(), 0);
+        let mut second = Psbt::from_hex(&hex).unwrap();
+        assert_eq!(second.sign(&cosigner).unwrap(), 1);
+        assert!(!first.clone().finalize().unwrap());
+        assert!(first.extract().is_err());
+
+        let mut combined = Psbt::from_hex(&first.to_hex().unwrap()).unwrap();
+        combined.combine(&Psbt::from_hex(&second.to_hex().unwrap()).unwrap()).unwrap();
+        assert!(combined.finalize().unwrap());
+        let tx = combined.extract().unwrap();
+        assert_eq!(tx.id, Psbt::from_hex(&hex).unwrap().tx.id);
+        assert_eq!(tx.vin[0].pub_key, cosigner.public_key);
+        assert_eq!(tx.vin[1].pub_key, miner.public_key);
+        utxo_set.verify_transactions(&[tx]).unwrap();
+    }
+
+    #[test]
+    fn psbt_of_another_transaction_is_not_combined() {
+        let _dir = enter_temp_dir();
+        let (miner, cosigner, payee) = (wallet(1), wallet(2), wallet(3));
+        let (_, hex) = shared_payment(&miner, &cosigner, &payee);
+        let mut psbt = Psbt::from_hex(&hex).unwrap();
+
+        let mut other = psbt.clone();
+        other.tx.vout[0].value = 80;
+        let mut other = Psbt::from_hex(&other.to_hex().unwrap()).unwrap();
+        other.sign(&cosigner).unwrap();
+        assert!(psbt.combine(&other).is_err());
+        assert!(psbt.inputs.iter().all(|input| input.partial_sigs.is_empty()));
+
+        // the id is recomputed when decoding, a forged one doesn't make them the same
+        other.tx.id = psbt.tx.id.clone();
+        let forged = Psbt::from_hex(&other.to_hex().unwrap()).unwrap();
+        assert_ne!(forged.tx.id, psbt.tx.id);
+        assert!(psbt.combine(&forged).is_err());
+    }
+}
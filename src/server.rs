@@ -0,0 +1,796 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+use failure::format_err;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use crate::block::{Block, BlockHeader, MerkleProof};
+use crate::blockchain::Blockchain;
+use crate::compact::CompactBlock;
+use crate::errors::{BlockError, Result, TxError};
+use crate::filters::{tx_items, BlockFilter, FilterIndex};
+use crate::headers::HeaderChain;
+use crate::mempool::Mempool;
+use crate::peers::{PeerInfo, PeerManager, BAN_THRESHOLD, MAX_ADDRS};
+use crate::transaction::Transaction;
+use crate::utxoset::UTXOSet;
+
+const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+const MAX_HEADERS: usize = 500;         // headers per Headers message
+const BLOCKS_PER_REQUEST: usize = 16;   // bodies asked to a peer at once
+const MAX_INBOUND: usize = 16;          // connections served at once
+const MAX_OUTBOUND: usize = 8;          // peers synced from or relayed to
+const INVALID_TX_SCORE: i32 = 10;
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// messages are sent as a big endian u32 length followed by the bincode of Message;
+// the connecting side asks, the listening side answers on the same connection
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Message {
+    Version { addr_from: String, best_height: i32 },
+    GetAddr,
+    Addr(Vec<String>),
+    GetHeaders(Vec<String>),        // block locator
+    Headers(Vec<BlockHeader>),
+    GetBlocks(Vec<String>),         // block hashes
+    Block(Block),
+    NotFound(String),
+    Tx(Transaction),
+    CmpctBlock(CompactBlock),       // a new block, pushed to the peers
+    GetBlockTxn { hash: String, indexes: Vec<u32> },
+    BlockTxn(Vec<Transaction>),
+    GetCFilters(Vec<String>),       // block hashes
+    CFilters(Vec<BlockFilter>),
+    // transactions of a block matching filter items, with their merkle proof
+    GetMerkleBlock { hash: String, items: Vec<Vec<u8>> },
+    MerkleBlock { hash: String, txs: Vec<Transaction>, proof: Option<MerkleProof> },
+    // administration, only from the local host
+    GetPeerInfo,
+    PeerInfo(Vec<PeerInfo>),
+    SetBan { addr: String, seconds: u64 },
+    SendTx(Transaction),
+    Mine(String),                   // reward address
+    Mined { block: Block, fees: i64 },
+    Rejected(String),
+}
+
+#[derive(Clone)]
+pub struct Server {
+    node_address: String,
+    inner: Arc<Mutex<ServerInner>>,
+}
+
+// the databases are opened per call, so every access goes through this lock
+struct ServerInner {
+    peers: PeerManager,
+    connected: HashSet<String>,
+    inbound: usize,
+    utxo: Option<UTXOSet>,      // None until the genesis block is downloaded
+    syncing: bool,
+}
+
+impl Server {
+    pub fn new(port: &str, seeds: &[String]) -> Result<Server> {
+        let utxo = Blockchain::open()?.map(|blockchain| UTXOSet { blockchain });
+        if let Some(utxo) = &utxo {
+            HeaderChain{}.catch_up(&utxo.blockchain)?;
+            let added = FilterIndex{}.catch_up(&utxo.blockchain)?;
+            if added > 0 {
+                info!("built {} missing block filters", added);
+            }
+        }
+        let peers = PeerManager::open()?;
+        for seed in seeds {
+            peers.add(seed)?;
+        }
+        Ok(Server {
+            node_address: format!("localhost:{}", port),
+            inner: Arc::new(Mutex::new(ServerInner {
+                peers,
+                connected: HashSet::new(),
+                inbound: 0,
+                utxo,
+                syncing: false,
+            })),
+        })
+    }
+
+    // catch up with the known peers in the background and answer their requests
+    pub fn start(&self) -> Result<()> {
+        self.spawn_sync(self.get_peer_addrs()?);
+        let listener = TcpListener::bind(&self.node_address)?;
+        info!("node listening at {}", self.node_address);
+        // a failing connection must not stop the listener
+        for stream in listener.incoming() {
+            let (stream, remote) = match stream.and_then(|stream| stream.peer_addr().map(|remote| (stream, remote))) {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!("can't accept a connection: {}", e);
+                    continue;
+                }
+            };
+            {
+                let mut inner = self.lock();
+                let banned = match inner.peers.is_banned(&remote.ip().to_string()) {
+                    Ok(banned) => banned,
+                    Err(e) => {
+                        warn!("can't check the ban of {}: {}", remote, e);
+                        continue;
+                    }
+                };
+                if inner.inbound >= MAX_INBOUND || banned {
+                    info!("refuse connection from {}", remote);
+                    continue;
+                }
+                inner.inbound += 1;
+            }
+            let server = self.clone();
+            thread::spawn(move || {
+                let mut peer = remote.to_string();
+                if let Err(e) = server.handle_connection(stream, remote, &mut peer) {
+                    warn!("connection with {} failed: {}", peer, e);
+                }
+                let mut inner = server.lock();
+                inner.inbound -= 1;
+                inner.connected.remove(&peer);
+            });
+        }
+        Ok(())
+    }
+
+    // peer is the socket address until the remote node announces its own, which is
+    // only used to reach it back: scores and bans go to the host it connects from.
+    // nodes on this machine share the loopback host, so they are scored by the
+    // address they announce, which a local process could change to evade a ban
+    fn handle_connection(&self, mut stream: TcpStream, remote: SocketAddr, peer: &mut String) -> Result<()> {
+        let mut host = if remote.ip().is_loopback() { remote.to_string() } else { remote.ip().to_string() };
+        let mut peer_height = -1;
+        loop {
+            let msg = match read_message(&mut stream) {
+                Ok(msg) => msg,
+                Err(_) => break,            // peer closed the connection
+            };
+            match msg {
+                Message::Version { addr_from, best_height } => {
+                    if remote.ip().is_loopback() {
+                        if self.lock().peers.is_banned(&addr_from)? {
+                            info!("refuse banned peer {}", addr_from);
+                            return Ok(());
+                        }
+                        host = addr_from.clone();
+                    }
+                    let is_new = {
+                        let mut inner = self.lock();
+                        let is_new = inner.peers.add(&addr_from)?;
+                        inner.peers.seen(&addr_from)?;
+                        inner.connected.insert(addr_from.clone());
+                        is_new
+                    };
+                    *peer = addr_from.clone();
+                    if is_new {
+                        self.broadcast(Message::Addr(vec![addr_from.clone()]), &addr_from)?;
+                    }
+                    send_message(&mut stream, &Message::Version {
+                        addr_from: self.node_address.clone(),
+                        best_height: self.get_best_height()?,
+                    })?;
+                    peer_height = best_height;
+                }
+                Message::GetAddr => {
+                    let addrs = self.lock().peers.get_addrs(MAX_ADDRS)?;
+                    send_message(&mut stream, &Message::Addr(addrs))?;
+                }
+                Message::Addr(addrs) => {
+                    let learned = self.lock().peers.add_gossiped(addrs, &self.node_address)?;
+                    // gossip only new addresses, so the relay stops once every node knows them
+                    if !learned.is_empty() {
+                        self.broadcast(Message::Addr(learned), peer)?;
+                    }
+                }
+                Message::GetHeaders(locator) => {
+                    send_message(&mut stream, &Message::Headers(self.headers_after(&locator)?))?;
+                }
+                Message::GetBlocks(hashes) => {
+                    for hash in hashes {
+                        let block = self.with_chain(|bc| bc.get_block(&hash))?.flatten();
+                        let reply = match block {
+                            Some(block) => Message::Block(block),
+                            None => Message::NotFound(hash),
+                        };
+                        send_message(&mut stream, &reply)?;
+                    }
+                }
+                Message::Tx(tx) => {
+                    if !self.receive_transaction(peer, &host, tx)? {
+                        return Ok(());
+                    }
+                }
+                Message::CmpctBlock(compact) => {
+                    if !self.receive_compact_block(peer, &host, compact)? {
+                        return Ok(());
+                    }
+                }
+                Message::GetBlockTxn { hash, indexes } => {
+                    let block = self.with_chain(|bc| bc.get_block(&hash))?.flatten();
+                    let reply = match block {
+                        Some(block) => Message::BlockTxn(indexes.iter()
+                            .filter_map(|index| block.get_transactions().get(*index as usize).cloned())
+                            .collect()),
+                        None => Message::NotFound(hash),
+                    };
+                    send_message(&mut stream, &reply)?;
+                }
+                Message::GetCFilters(hashes) => {
+                    send_message(&mut stream, &self.get_filters(&hashes)?)?;
+                }
+                Message::GetMerkleBlock { hash, items } => {
+                    let block = self.with_chain(|bc| bc.get_block(&hash))?.flatten();
+                    let reply = match block {
+                        Some(block) => {
+                            let txs: Vec<Transaction> = block.get_transactions().iter()
+                                .filter(|tx| tx_items(tx).iter().any(|item| items.contains(item)))
+                                .cloned()
+                                .collect();
+                            let txids: Vec<String> = txs.iter().map(|tx| tx.id.clone()).collect();
+                            Message::MerkleBlock { proof: block.merkle_proof(&txids)?, hash, txs }
+                        }
+                        None => Message::NotFound(hash),
+                    };
+                    send_message(&mut stream, &reply)?;
+                }
+                Message::GetPeerInfo if remote.ip().is_loopback() => {
+                    let inner = self.lock();
+                    let mut peers = inner.peers.get_all()?;
+                    for info in &mut peers {
+                        info.connected = inner.connected.contains(&info.addr);
+                    }
+                    send_message(&mut stream, &Message::PeerInfo(peers))?;
+                }
+                Message::SetBan { addr, seconds } if remote.ip().is_loopback() => {
+                    let info = self.lock().peers.set_ban(&addr, seconds)?;
+                    send_message(&mut stream, &Message::PeerInfo(vec![info]))?;
+                }
+                Message::SendTx(tx) if remote.ip().is_loopback() => {
+                    let reply = match self.submit_transaction(&tx) {
+                        Ok(()) => Message::Tx(tx),
+                        Err(e) => Message::Rejected(e.to_string()),
+                    };
+                    send_message(&mut stream, &reply)?;
+                }
+                Message::Mine(address) if remote.ip().is_loopback() => {
+                    let reply = match self.mine(&address) {
+                        Ok((block, fees)) => Message::Mined { block, fees },
+                        Err(e) => Message::Rejected(e.to_string()),
+                    };
+                    send_message(&mut stream, &reply)?;
+                }
+                msg => warn!("unexpected message from {}: {:?}", peer, msg),
+            }
+        }
+        // a peer announcing a longer chain is synced from once it is done sending,
+        // a block it pushed on this connection may have caught us up already
+        if peer_height > self.get_best_height()? {
+            self.spawn_sync(vec![peer.clone()]);
+        }
+        Ok(())
+    }
+
+    // accept a relayed transaction into the mempool and relay it further;
+    // false if the host of the peer got banned for it
+    fn receive_transaction(&self, peer: &str, host: &str, tx: Transaction) -> Result<bool> {
+        let inner = self.lock();
+        let utxo = match &inner.utxo {
+            Some(utxo) => utxo,
+            None => return Ok(true),
+        };
+        let pool = Mempool{}.load()?;
+        if pool.contains_key(&tx.id) {
+            return Ok(true);
+        }
+        let valid = !tx.is_coinbase() && match utxo.verify_transaction(&tx, &pool) {
+            Ok(valid) => valid,
+            Err(e) => {
+                // an unknown parent doesn't make the peer dishonest
+                info!("ignore transaction {} from {}: {}", tx.id, peer, e);
+                return Ok(true);
+            }
+        };
+        if !valid {
+            return Ok(!inner.peers.misbehaving(host, INVALID_TX_SCORE, "invalid transaction")?);
+        }
+        if let Err(e) = (Mempool{}).add(&tx, utxo) {
+            info!("reject transaction {} from {}: {}", tx.id, peer, e);
+            return Ok(true);
+        }
+        drop(inner);
+        self.broadcast(Message::Tx(tx), peer)?;
+        Ok(true)
+    }
+
+    // verify a transaction handed by the local user, add it to the mempool and relay it
+    fn submit_transaction(&self, tx: &Transaction) -> Result<()> {
+        {
+            let inner = self.lock();
+            let utxo = inner.utxo.as_ref().ok_or_else(|| format_err!("the node has no blockchain yet"))?;
+            let pool = Mempool{}.load()?;
+            if tx.is_coinbase() || !utxo.verify_transaction(tx, &pool)? {
+                return Err(format_err!("transaction {} is invalid", tx.id));
+            }
+            Mempool{}.add(tx, utxo)?;
+        }
+        self.broadcast(Message::Tx(tx.clone()), "")
+    }
+
+    // mine the pending transactions and push the block to the peers
+    fn mine(&self, address: &str) -> Result<(Block, i64)> {
+        let (block, fees) = {
+            let mut inner = self.lock();
+            let utxo = inner.utxo.as_mut().ok_or_else(|| format_err!("the node has no blockchain yet"))?;
+            let mined = utxo.mine_pending(address)?;
+            HeaderChain{}.add(&mined.0.get_header()?)?;
+            mined
+        };
+        info!("mined block {} at height {}", block.get_hash(), block.get_height());
+        self.broadcast(Message::CmpctBlock(CompactBlock::new(&block)?), "")?;
+        Ok((block, fees))
+    }
+
+    // connect a block pushed by peer, rebuilt from the header, the mempool and a
+    // round trip for the transactions missing here, and push it further;
+    // false if the host of the peer got banned for it
+    fn receive_compact_block(&self, peer: &str, host: &str, compact: CompactBlock) -> Result<bool> {
+        let header = compact.header.clone();
+        if self.with_headers(|headers| headers.get_header(&header.hash))?.is_some() {
+            return Ok(true);
+        }
+        // a block not extending our tip is left to the sync
+        let tip = self.with_chain(|bc| Ok(bc.get_tip_hash()))?;
+        if tip.as_ref() != Some(&header.prev_block_hash) {
+            return Ok(true);
+        }
+        if let Err(e) = header.check_pow() {
+            info!("invalid block {} from {}: {}", header.hash, peer, e);
+            return Ok(!self.lock().peers.misbehaving(host, BAN_THRESHOLD, "invalid proof of work")?);
+        }
+
+        let pool = {
+            let _inner = self.lock();
+            Mempool{}.load()?
+        };
+        let mut txs = compact.reconstruct(&pool);
+        let missing: Vec<u32> = (0..txs.len() as u32).filter(|index| txs[*index as usize].is_none()).collect();
+        if !missing.is_empty() {
+            let mut stream = connect(peer)?;
+            send_message(&mut stream, &Message::GetBlockTxn { hash: header.hash.clone(), indexes: missing.clone() })?;
+            let fetched = match read_message(&mut stream)? {
+                Message::BlockTxn(fetched) if fetched.len() == missing.len() => fetched,
+                msg => return Err(format_err!("expect {} transactions of block {}, got {:?}", missing.len(), header.hash, msg)),
+            };
+            for (index, tx) in missing.iter().zip(fetched) {
+                txs[*index as usize] = Some(tx);
+            }
+        }
+        let mut block = Block::from_header(header.clone(), txs.into_iter().flatten().collect());
+        if block.get_header()? != header {
+            // a short id matched the wrong pending transaction
+            info!("block {} rebuilt from the mempool doesn't match its header, download it", header.hash);
+            let mut stream = connect(peer)?;
+            send_message(&mut stream, &Message::GetBlocks(vec![header.hash.clone()]))?;
+            block = match read_message(&mut stream)? {
+                Message::Block(block) => block,
+                msg => return Err(format_err!("expect block {}, got {:?}", header.hash, msg)),
+            };
+        }
+
+        if block.get_header()? != header {
+            info!("block {} from {} doesn't match its header", header.hash, peer);
+            return Ok(!self.lock().peers.misbehaving(host, BAN_THRESHOLD, "invalid block")?);
+        }
+        match self.connect_tip(&block) {
+            Ok(true) => {}
+            // another relay of the block got there first
+            Ok(false) => return Ok(true),
+            Err(e) if is_invalid_block(&e) => {
+                info!("invalid block {} from {}: {}", header.hash, peer, e);
+                return Ok(!self.lock().peers.misbehaving(host, BAN_THRESHOLD, "invalid block")?);
+            }
+            Err(e) => {
+                warn!("can't connect block {} from {}: {}", header.hash, peer, e);
+                return Ok(true);
+            }
+        }
+        println!("Block {} at height {}: {} of {} transactions from the mempool, {} requested, {} bytes instead of {}",
+                 header.hash, header.height, compact.short_ids.len().saturating_sub(missing.len()),
+                 compact.tx_count(), missing.len(), bincode::serialized_size(&compact)?, bincode::serialized_size(&block)?);
+        self.broadcast(Message::CmpctBlock(compact), peer)?;
+        Ok(true)
+    }
+
+    // filters of the blocks, built from the local block when missing,
+    // NotFound for the first one the node can't serve
+    fn get_filters(&self, hashes: &[String]) -> Result<Message> {
+        let inner = self.lock();
+        let index = FilterIndex{};
+        let mut filters = Vec::new();
+        for hash in hashes.iter().take(MAX_HEADERS) {
+            if let Some(filter) = index.get(hash)? {
+                filters.push(filter);
+                continue;
+            }
+            let block = match &inner.utxo {
+                Some(utxo) => utxo.blockchain.get_block(hash)?,
+                None => None,
+            };
+            match block {
+                Some(block) => {
+                    index.add(&block)?;
+                    filters.push(BlockFilter::new(&block));
+                }
+                None => return Ok(Message::NotFound(hash.clone())),
+            }
+        }
+        Ok(Message::CFilters(filters))
+    }
+
+    // send msg to the known peers but one, each from its own thread
+    fn broadcast(&self, msg: Message, except: &str) -> Result<()> {
+        let data = bincode::serialize(&msg)?;
+        for addr in self.get_peer_addrs()? {
+            if addr == except {
+                continue;
+            }
+            let data = data.clone();
+            let version = self.version()?;
+            thread::spawn(move || {
+                // the peer answers the version, which must be read before moving on
+                let sent = connect(&addr).and_then(|mut stream| {
+                    send_message(&mut stream, &version)?;
+                    read_message(&mut stream)?;
+                    send_message(&mut stream, &bincode::deserialize(&data)?)
+                });
+                if let Err(e) = sent {
+                    info!("can't relay to {}: {}", addr, e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    // headers of our chain after the last locator hash we know, from genesis if none
+    fn headers_after(&self, locator: &[String]) -> Result<Vec<BlockHeader>> {
+        let inner = self.lock();
+        let bc = match &inner.utxo {
+            Some(utxo) => &utxo.blockchain,
+            None => return Ok(Vec::new()),
+        };
+        let hashes = bc.get_block_hashes();
+        let start = locator.iter()
+            .find_map(|hash| hashes.iter().position(|h| h == hash))
+            .map_or(0, |pos| pos + 1);
+        let mut headers = Vec::new();
+        for hash in hashes.iter().skip(start).take(MAX_HEADERS) {
+            headers.push(bc.get_header(hash)?.ok_or_else(|| format_err!("missing header {}", hash))?);
+        }
+        Ok(headers)
+    }
+
+    fn spawn_sync(&self, peers: Vec<String>) {
+        if peers.is_empty() {
+            return;
+        }
+        {
+            let mut inner = self.lock();
+            if inner.syncing {
+                return;
+            }
+            inner.syncing = true;
+        }
+        let server = self.clone();
+        thread::spawn(move || {
+            if let Err(e) = server.sync(&peers) {
+                warn!("sync failed: {}", e);
+                println!("Sync failed: {}", e);
+            }
+            server.lock().syncing = false;
+        });
+    }
+
+    // headers first: download and validate the header chain from the best peer,
+    // then fetch the bodies from every peer in parallel and connect them in order
+    pub fn sync(&self, peers: &[String]) -> Result<()> {
+        let mut heights = Vec::new();
+        for peer in peers {
+            match self.handshake(peer) {
+                Ok(height) => heights.push((peer.clone(), height)),
+                Err(e) => warn!("can't reach {}: {}", peer, e),
+            }
+        }
+        let (best_peer, best_height) = match heights.iter().max_by_key(|(_, height)| *height) {
+            Some(best) => best.clone(),
+            None => return Err(format_err!("no peer to sync from")),
+        };
+        self.sync_headers(&best_peer, best_height)?;
+
+        let target = self.with_headers(|headers| headers.get_best_height())?;
+        let tip = self.get_best_height()?;
+        if target <= tip {
+            println!("Synced at height {}", tip);
+            return Ok(());
+        }
+        let peers: Vec<String> = heights.into_iter()
+            .filter(|(_, height)| *height > tip)
+            .map(|(peer, _)| peer)
+            .collect();
+        if let Err(e) = self.sync_blocks(&peers, tip, target) {
+            // the headers of the blocks that didn't connect would stay the best chain,
+            // only headers extending it would be accepted from then on
+            let inner = self.lock();
+            let height = match &inner.utxo {
+                Some(utxo) => utxo.blockchain.get_best_height()?,
+                None => -1,
+            };
+            let dropped = HeaderChain{}.rewind(height)?;
+            info!("dropped {} headers above height {}", dropped, height);
+            return Err(e);
+        }
+        println!("Synced at height {}", target);
+        Ok(())
+    }
+
+    // exchange versions and addresses, return the height of the peer
+    fn handshake(&self, peer: &str) -> Result<i32> {
+        let mut stream = connect(peer)?;
+        send_message(&mut stream, &self.version()?)?;
+        let height = match read_message(&mut stream)? {
+            Message::Version { best_height, .. } => best_height,
+            msg => return Err(format_err!("expect version from {}, got {:?}", peer, msg)),
+        };
+        send_message(&mut stream, &Message::GetAddr)?;
+        if let Message::Addr(addrs) = read_message(&mut stream)? {
+            self.lock().peers.add_gossiped(addrs, &self.node_address)?;
+        }
+        self.lock().peers.seen(peer)?;
+        Ok(height)
+    }
+
+    fn sync_headers(&self, peer: &str, peer_height: i32) -> Result<()> {
+        let mut stream = connect(peer)?;
+        loop {
+            let locator = self.with_headers(|headers| headers.locator())?;
+            send_message(&mut stream, &Message::GetHeaders(locator))?;
+            let headers = match read_message(&mut stream)? {
+                Message::Headers(headers) => headers,
+                msg => return Err(format_err!("expect headers from {}, got {:?}", peer, msg)),
+            };
+            let mut added = 0;
+            for header in &headers {
+                if let Err(e) = header.check_pow() {
+                    self.lock().peers.misbehaving(peer, BAN_THRESHOLD, "invalid proof of work")?;
+                    return Err(e);
+                }
+                if self.with_headers(|chain| chain.add(header))? {
+                    added += 1;
+                }
+            }
+            let height = self.with_headers(|headers| headers.get_best_height())?;
+            println!("Headers {}/{}", height, peer_height);
+            if added == 0 || headers.len() < MAX_HEADERS {
+                return Ok(());
+            }
+        }
+    }
+
+    fn sync_blocks(&self, peers: &[String], tip: i32, target: i32) -> Result<()> {
+        // the connected chain must be a prefix of the header chain
+        if tip >= 0 {
+            let tip_hash = self.with_chain(|bc| Ok(bc.get_tip_hash()))?;
+            if self.with_headers(|headers| headers.hash_at(tip))?.as_ref() != tip_hash.as_ref() {
+                return Err(format_err!("local chain forks from the header chain at height {}, reorganisation is not supported", tip));
+            }
+        }
+        let mut hashes = Vec::new();
+        for height in tip + 1..=target {
+            let hash = self.with_headers(|headers| headers.hash_at(height))?
+                .ok_or_else(|| format_err!("missing header at height {}", height))?;
+            hashes.push(hash);
+        }
+        let queue: VecDeque<Vec<String>> = hashes.chunks(BLOCKS_PER_REQUEST).map(|chunk| chunk.to_vec()).collect();
+        let queue = Arc::new(Mutex::new(queue));
+
+        let (sender, receiver) = mpsc::channel();
+        for peer in peers.iter().take(MAX_OUTBOUND) {
+            let queue = Arc::clone(&queue);
+            let sender = sender.clone();
+            let peer = peer.clone();
+            thread::spawn(move || {
+                if let Err(e) = download_blocks(&peer, &queue, &sender) {
+                    warn!("stop downloading from {}: {}", peer, e);
+                }
+            });
+        }
+        drop(sender);
+
+        // bodies arrive in any order, they are connected by height
+        let mut downloaded = HashMap::new();
+        let mut next = tip + 1;
+        while next <= target {
+            let (peer, block): (String, Block) = match receiver.recv() {
+                Ok(received) => received,
+                Err(_) => return Err(format_err!("no peer could send block at height {}", next)),
+            };
+            downloaded.insert(block.get_hash(), (peer, block));
+            while let Some((peer, block)) = downloaded.remove(&hashes[(next - tip - 1) as usize]) {
+                match self.connect_block(&block) {
+                    Ok(true) => {}
+                    // connected meanwhile from a relay
+                    Ok(false) if self.with_chain(|bc| bc.get_header(&block.get_hash()))?.flatten().is_some() => {}
+                    Ok(false) => return Err(format_err!("block {} no longer extends the local chain", block.get_hash())),
+                    Err(e) => {
+                        if is_invalid_block(&e) {
+                            self.lock().peers.misbehaving(&peer, BAN_THRESHOLD, "invalid block")?;
+                        }
+                        return Err(e);
+                    }
+                }
+                println!("Blocks {}/{}", next, target);
+                next += 1;
+                if next > target {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // connect a downloaded block, which must match its header in the header chain
+    fn connect_block(&self, block: &Block) -> Result<bool> {
+        let header = self.with_headers(|headers| headers.get_header(&block.get_hash()))?
+            .ok_or_else(|| format_err!("block {} has no header", block.get_hash()))?;
+        if block.get_header()? != header {
+            return Err(BlockError::HeaderMismatch(block.get_hash()).into());
+        }
+        self.connect_tip(block)
+    }
+
+    // connect a block on top of the chain and add its header to the header chain;
+    // false if it no longer extends the tip, as a block of the same height was
+    // connected since it was received
+    fn connect_tip(&self, block: &Block) -> Result<bool> {
+        let mut inner = self.lock();
+        match &mut inner.utxo {
+            Some(utxo) if utxo.blockchain.get_tip_hash() != block.get_prev_hash() => return Ok(false),
+            Some(utxo) => utxo.connect_block(block)?,
+            None => {
+                let utxo = UTXOSet { blockchain: Blockchain::create_from_genesis(block)? };
+                utxo.reindex()?;
+                inner.utxo = Some(utxo);
+            }
+        }
+        // known already when synced headers first
+        if let Err(e) = (HeaderChain{}).add(&block.get_header()?) {
+            info!("header of block {} not added: {}", block.get_hash(), e);
+        }
+        Ok(true)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ServerInner> {
+        self.inner.lock().unwrap()
+    }
+
+    fn with_chain<T>(&self, f: impl FnOnce(&Blockchain) -> Result<T>) -> Result<Option<T>> {
+        let inner = self.lock();
+        match &inner.utxo {
+            Some(utxo) => Ok(Some(f(&utxo.blockchain)?)),
+            None => Ok(None),
+        }
+    }
+
+    // run f on the chain and UTXO set of the node, None before the genesis block is known
+    pub fn with_utxo<T>(&self, f: impl FnOnce(&UTXOSet) -> Result<T>) -> Result<Option<T>> {
+        let inner = self.lock();
+        match &inner.utxo {
+            Some(utxo) => Ok(Some(f(utxo)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn with_headers<T>(&self, f: impl FnOnce(&HeaderChain) -> Result<T>) -> Result<T> {
+        let _inner = self.lock();
+        f(&HeaderChain{})
+    }
+
+    fn get_best_height(&self) -> Result<i32> {
+        Ok(self.with_chain(|bc| bc.get_best_height())?.unwrap_or(-1))
+    }
+
+    fn version(&self) -> Result<Message> {
+        Ok(Message::Version {
+            addr_from: self.node_address.clone(),
+            best_height: self.get_best_height()?,
+        })
+    }
+
+    fn get_peer_addrs(&self) -> Result<Vec<String>> {
+        let addrs = self.lock().peers.get_addrs(MAX_OUTBOUND)?;
+        Ok(addrs.into_iter().filter(|addr| *addr != self.node_address).collect())
+    }
+}
+
+// take chunks of hashes from the queue until it is empty, a chunk the peer can't
+// serve goes back to the queue for the other peers
+fn download_blocks(peer: &str, queue: &Mutex<VecDeque<Vec<String>>>, sender: &mpsc::Sender<(String, Block)>) -> Result<()> {
+    let mut stream = connect(peer)?;
+    loop {
+        let chunk = match queue.lock().unwrap().pop_front() {
+            Some(chunk) => chunk,
+            None => return Ok(()),
+        };
+        if let Err(e) = request_blocks(peer, &mut stream, &chunk, sender) {
+            queue.lock().unwrap().push_back(chunk);
+            return Err(e);
+        }
+    }
+}
+
+fn request_blocks(peer: &str, stream: &mut TcpStream, hashes: &[String], sender: &mpsc::Sender<(String, Block)>) -> Result<()> {
+    send_message(stream, &Message::GetBlocks(hashes.to_vec()))?;
+    for hash in hashes {
+        match read_message(stream)? {
+            Message::Block(block) if block.get_hash() == *hash => {
+                // the receiver is gone once the sync is over or failed
+                if sender.send((peer.to_string(), block)).is_err() {
+                    return Ok(());
+                }
+            }
+            Message::NotFound(hash) => return Err(format_err!("peer doesn't have block {}", hash)),
+            msg => return Err(format_err!("expect block {}, got {:?}", hash, msg)),
+        }
+    }
+    Ok(())
+}
+
+// send an administration request to a running node and wait for its answer
+pub fn rpc(node: &str, msg: &Message) -> Result<Message> {
+    let mut stream = connect(node)?;
+    send_message(&mut stream, msg)?;
+    match read_message(&mut stream)? {
+        Message::Rejected(reason) => Err(format_err!("{}", reason)),
+        reply => Ok(reply),
+    }
+}
+
+// a block failing validation is the fault of the peer sending it, a failing
+// store of this node is not
+fn is_invalid_block(e: &failure::Error) -> bool {
+    e.downcast_ref::<BlockError>().is_some() || e.downcast_ref::<TxError>().is_some()
+}
+
+fn connect(addr: &str) -> Result<TcpStream> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    Ok(stream)
+}
+
+pub fn send_message(stream: &mut TcpStream, msg: &Message) -> Result<()> {
+    let data = bincode::serialize(msg)?;
+    stream.write_all(&(data.len() as u32).to_be_bytes())?;
+    stream.write_all(&data)?;
+    stream.flush()?;
+    Ok(())
+}
+
+pub fn read_message(stream: &mut TcpStream) -> Result<Message> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(format_err!("message of {} bytes is too large", len));
+    }
+    let mut data = vec![0u8; len];
+    stream.read_exact(&mut data)?;
+    Ok(bincode::deserialize(&data)?)
+}
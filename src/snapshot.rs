@@ -0,0 +1,293 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use failure::format_err;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::errors::Result;
+use crate::filters::FilterIndex;
+use crate::utxoset::{UTXOSet, UtxoRecord};
+
+// files are a sequence of records, each a big endian u32 length followed by bincode
+// no larger than a network message, which every block of the chain fits in
+const MAX_RECORD_SIZE: usize = 32 * 1024 * 1024;
+
+// first record of a UTXO snapshot, followed by count records of (txid, vout, UtxoEntry)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UtxoSnapshot {
+    pub block_hash: String,     // tip of the chain the set corresponds to
+    pub height: i32,
+    pub count: u64,
+    pub digest: String,         // sha256 of the entry records
+}
+
+// write the blocks of the chain in height order, returns how many
+pub fn dump_chain(bc: &Blockchain, path: &str) -> Result<usize> {
+    bc.check_unpruned("dumpchain")?;
+    let mut file = BufWriter::new(File::create(path)?);
+    let hashes = bc.get_block_hashes();
+    for hash in &hashes {
+        let block = bc.get_block(hash)?.ok_or_else(|| format_err!("missing block {}", hash))?;
+        write_record(&mut file, &block)?;
+    }
+    file.flush()?;
+    Ok(hashes.len())
+}
+
+// connect the blocks of a dump after the local chain, each validated as if received
+// from a peer; the blocks the chain already has are skipped. with a UTXO snapshot of
+// the last block, the transactions aren't replayed: the blocks are only checked for
+// linkage and proof of work and the UTXO set is taken from the snapshot.
+// returns the numbers of blocks connected and skipped
+pub fn load_chain(path: &str, utxo_path: Option<&str>) -> Result<(usize, usize)> {
+    // the snapshot is checked before touching the chain
+    let snapshot = match utxo_path {
+        Some(utxo_path) => Some(read_utxo(utxo_path)?),
+        None => None,
+    };
+    if let Some((snapshot, _)) = &snapshot {
+        let last = last_block_hash(path)?;
+        if last.as_deref() != Some(snapshot.block_hash.as_str()) {
+            return Err(format_err!("snapshot is of block {}, the dump ends at {}",
+                snapshot.block_hash, last.unwrap_or_else(|| "no block".to_string())));
+        }
+    }
+    let mut file = BufReader::new(File::open(path)?);
+    let genesis = match read_record::<Block>(&mut file)? {
+        Some(genesis) => genesis,
+        None => return Ok((0, 0)),
+    };
+    let (blockchain, created) = Blockchain::open_or_create_from_genesis(&genesis)?;
+    let mut utxo_set = UTXOSet { blockchain };
+    let mut loaded = 0;
+    let mut skipped = 0;
+    let mut next = None;
+    if created {
+        match snapshot {
+            Some(_) => FilterIndex{}.add(&genesis)?,
+            None => utxo_set.reindex()?,
+        }
+        loaded += 1;
+    } else {
+        next = Some(genesis);
+    }
+    loop {
+        let block = match next.take() {
+            Some(block) => block,
+            None => match read_record::<Block>(&mut file)? {
+                Some(block) => block,
+                None => break,
+            },
+        };
+        if utxo_set.blockchain.get_header(&block.get_hash())?.is_some() {
+            skipped += 1;
+        } else if snapshot.is_some() {
+            utxo_set.blockchain.connect_block(&block)?;
+            FilterIndex{}.add(&block)?;
+            loaded += 1;
+        } else {
+            utxo_set.connect_block(&block)?;
+            loaded += 1;
+        }
+    }
+    if let Some((snapshot, entries)) = snapshot {
+        let tip = utxo_set.blockchain.get_tip_hash();
+        if snapshot.block_hash != tip {
+            return Err(format_err!("snapshot is of block {}, the chain tip is {}: run reindex to rebuild the UTXO set from the blocks",
+                snapshot.block_hash, tip));
+        }
+        utxo_set.replace(&entries)?;
+    }
+    Ok((loaded, skipped))
+}
+
+// hash of the last block of a dump, read through without storing anything
+fn last_block_hash(path: &str) -> Result<Option<String>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut last = None;
+    while let Some(block) = read_record::<Block>(&mut file)? {
+        last = Some(block.get_hash());
+    }
+    Ok(last)
+}
+
+pub fn dump_utxo(utxo_set: &UTXOSet, path: &str) -> Result<UtxoSnapshot> {
+    let entries = utxo_set.get_entries()?;
+    let snapshot = UtxoSnapshot {
+        block_hash: utxo_set.blockchain.get_tip_hash(),
+        height: utxo_set.blockchain.get_best_height()?,
+        count: entries.len() as u64,
+        digest: digest(&entries)?,
+    };
+    let mut file = BufWriter::new(File::create(path)?);
+    write_record(&mut file, &snapshot)?;
+    for entry in &entries {
+        write_record(&mut file, entry)?;
+    }
+    file.flush()?;
+    Ok(snapshot)
+}
+
+// replace the UTXO set by a snapshot taken at the tip of the local chain
+pub fn load_utxo(utxo_set: &UTXOSet, path: &str) -> Result<UtxoSnapshot> {
+    let (snapshot, entries) = read_utxo(path)?;
+    let tip = utxo_set.blockchain.get_tip_hash();
+    if snapshot.block_hash != tip {
+        return Err(format_err!("snapshot is of block {} at height {}, the chain tip is {}",
+            snapshot.block_hash, snapshot.height, tip));
+    }
+    utxo_set.replace(&entries)?;
+    Ok(snapshot)
+}
+
+// a UTXO snapshot and its outputs, checked against its digest
+fn read_utxo(path: &str) -> Result<(UtxoSnapshot, Vec<UtxoRecord>)> {
+    let mut file = BufReader::new(File::open(path)?);
+    let snapshot: UtxoSnapshot = read_record(&mut file)?
+        .ok_or_else(|| format_err!("{} is empty", path))?;
+    let mut entries = Vec::new();
+    for _ in 0..snapshot.count {
+        let entry: UtxoRecord = read_record(&mut file)?
+            .ok_or_else(|| format_err!("snapshot is truncated after {} outputs", entries.len()))?;
+        entries.push(entry);
+    }
+    if digest(&entries)? != snapshot.digest {
+        return Err(format_err!("snapshot digest doesn't match its outputs"));
+    }
+    Ok((snapshot, entries))
+}
+
+fn digest(entries: &[UtxoRecord]) -> Result<String> {
+    let mut hasher = Sha256::new();
+    for entry in entries {
+        hasher.input(&bincode::serialize(entry)?);
+    }
+    Ok(hasher.result_str())
+}
+
+fn write_record<T: Serialize>(file: &mut impl Write, value: &T) -> Result<()> {
+    let data = bincode::serialize(value)?;
+    file.write_all(&(data.len() as u32).to_be_bytes())?;
+    file.write_all(&data)?;
+    Ok(())
+}
+
+// the next record, None at the end of the file
+fn read_record<T: DeserializeOwned>(file: &mut impl Read) -> Result<Option<T>> {
+    let mut len = [0u8; 4];
+    match file.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_RECORD_SIZE {
+        return Err(format_err!("record of {} bytes exceeds the limit of {}", len, MAX_RECORD_SIZE));
+    }
+    let mut data = Vec::new();
+    file.take(len as u64).read_to_end(&mut data)?;
+    if data.len() != len {
+        return Err(format_err!("record is truncated after {} of {} bytes", data.len(), len));
+    }
+    Ok(Some(bincode::deserialize(&data)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coinselect::LargestFirst;
+    use crate::transaction::{SendOptions, Transaction};
+    use crate::testutil::{enter_temp_dir, mine, new_chain, utxo_bytes, wallet};
+
+    // a chain of four blocks, one of them spending the genesis reward; the UTXO
+    // snapshot is taken one block before the tip
+    fn build_chain() -> (String, Vec<u8>) {
+        let (miner, payee) = (wallet(1), wallet(2));
+        let mut utxo_set = new_chain(&miner);
+        let options = SendOptions {
+            selector: &LargestFirst,
+            fee_rate: 0,
+            min_conf: 1,
+            change_address: None,
+            data: Some(b"invoice".to_vec()),
+            replaceable: false,
+            replaces: None,
+        };
+        let tx = Transaction::new_utxo(&miner, 30, &payee.get_address(), &utxo_set, &options).unwrap();
+        utxo_set.add_block(vec![tx]).unwrap();
+        mine(&mut utxo_set, &payee);
+        dump_utxo(&utxo_set, "old_utxo.bin").unwrap();
+        mine(&mut utxo_set, &miner);
+        assert_eq!(dump_chain(&utxo_set.blockchain, "chain.bin").unwrap(), 4);
+        dump_utxo(&utxo_set, "utxo.bin").unwrap();
+        (utxo_set.blockchain.get_tip_hash(), utxo_bytes(&utxo_set))
+    }
+
+    fn reset_data() {
+        std::fs::remove_dir_all("data").unwrap();
+    }
+
+    #[test]
+    fn chain_dump_loads_back() {
+        let _dir = enter_temp_dir();
+        let (tip, utxo) = build_chain();
+        reset_data();
+        assert_eq!(load_chain("chain.bin", None).unwrap(), (4, 0));
+        let utxo_set = UTXOSet { blockchain: Blockchain::new().unwrap() };
+        assert_eq!(utxo_set.blockchain.get_tip_hash(), tip);
+        assert_eq!(utxo_bytes(&utxo_set), utxo);
+        drop(utxo_set);
+        assert_eq!(load_chain("chain.bin", None).unwrap(), (0, 4));
+    }
+
+    #[test]
+    fn chain_dump_loads_with_a_utxo_snapshot() {
+        let _dir = enter_temp_dir();
+        let (tip, utxo) = build_chain();
+        reset_data();
+        assert_eq!(load_chain("chain.bin", Some("utxo.bin")).unwrap(), (4, 0));
+        let utxo_set = UTXOSet { blockchain: Blockchain::new().unwrap() };
+        assert_eq!(utxo_set.blockchain.get_tip_hash(), tip);
+        assert_eq!(utxo_bytes(&utxo_set), utxo);
+        assert!(FilterIndex{}.get(&tip).unwrap().is_some());
+    }
+
+    #[test]
+    fn snapshot_of_another_block_is_refused_before_loading() {
+        let _dir = enter_temp_dir();
+        build_chain();
+        reset_data();
+        assert!(load_chain("chain.bin", Some("old_utxo.bin")).is_err());
+        assert!(!std::path::Path::new("data/blocks").exists());
+    }
+
+    #[test]
+    fn snapshot_must_match_the_tip() {
+        let _dir = enter_temp_dir();
+        let (_, utxo) = build_chain();
+        let utxo_set = UTXOSet { blockchain: Blockchain::new().unwrap() };
+        assert!(load_utxo(&utxo_set, "old_utxo.bin").is_err());
+        assert_eq!(utxo_bytes(&utxo_set), utxo);
+        assert_eq!(load_utxo(&utxo_set, "utxo.bin").unwrap().height, 3);
+        assert_eq!(utxo_bytes(&utxo_set), utxo);
+    }
+
+    #[test]
+    fn damaged_records_are_errors() {
+        let _dir = enter_temp_dir();
+        build_chain();
+        let data = std::fs::read("chain.bin").unwrap();
+        std::fs::write("truncated.bin", &data[..data.len() - 1]).unwrap();
+        assert!(last_block_hash("truncated.bin").is_err());
+        std::fs::write("oversized.bin", u32::MAX.to_be_bytes()).unwrap();
+        assert!(last_block_hash("oversized.bin").is_err());
+        let mut utxo = std::fs::read("utxo.bin").unwrap();
+        let last = utxo.len() - 1;
+        utxo[last] ^= 1;
+        std::fs::write("tampered.bin", utxo).unwrap();
+        assert!(read_utxo("tampered.bin").is_err());
+    }
+}
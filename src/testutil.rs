@@ -0,0 +1,77 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use crypto::ed25519;
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::transaction::Transaction;
+use crate::utxoset::UTXOSet;
+use crate::wallet::Wallet;
+
+// the stores open data/ relative to the working directory, so the tests using
+// them run one at a time, each in a fresh directory removed afterwards
+static WORKING_DIR: Mutex<()> = Mutex::new(());
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+pub struct TempDir {
+    path: PathBuf,
+    previous: PathBuf,
+    _lock: MutexGuard<'static, ()>,
+}
+
+pub fn enter_temp_dir() -> TempDir {
+    // a failed test leaves the lock poisoned, the next one still gets a fresh directory
+    let lock = WORKING_DIR.lock().unwrap_or_else(|e| e.into_inner());
+    let path = std::env::temp_dir().join(format!("simple-blockchain-test-{}-{}",
+        std::process::id(), NEXT_DIR.fetch_add(1, Ordering::SeqCst)));
+    std::fs::create_dir_all(&path).unwrap();
+    let previous = std::env::current_dir().unwrap();
+    std::env::set_current_dir(&path).unwrap();
+    TempDir { path, previous, _lock: lock }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::env::set_current_dir(&self.previous);
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+// a wallet always the same for a seed
+pub fn wallet(seed: u8) -> Wallet {
+    let (secret, _) = ed25519::keypair(&[seed; 32]);
+    Wallet::from_private_key(&secret).unwrap()
+}
+
+// a new chain whose genesis reward goes to miner, with its UTXO set
+pub fn new_chain(miner: &Wallet) -> UTXOSet {
+    let utxo_set = UTXOSet { blockchain: Blockchain::create_blockchain(miner.get_address()).unwrap() };
+    utxo_set.reindex().unwrap();
+    utxo_set
+}
+
+// mine a block holding only a coinbase to miner
+pub fn mine(utxo_set: &mut UTXOSet, miner: &Wallet) -> Block {
+    let coinbase = Transaction::new_coinbase(miner.get_address(), String::new()).unwrap();
+    utxo_set.add_block(vec![coinbase]).unwrap()
+}
+
+// the id of the coinbase of the tip block
+pub fn tip_reward(utxo_set: &UTXOSet) -> String {
+    utxo_set.blockchain.iter().next().unwrap().get_transactions()[0].id.clone()
+}
+
+// the UTXO set in a comparable form
+pub fn utxo_bytes(utxo_set: &UTXOSet) -> Vec<u8> {
+    bincode::serialize(&utxo_set.get_entries().unwrap()).unwrap()
+}
+
+// a transaction of owner spending the (txid, vout) inputs, the value they don't pay
+// to the (address, value) outputs is its fee
+pub fn spend(owner: &Wallet, inputs: &[(String, i32)], outputs: &[(String, i32)], replaceable: bool) -> Transaction {
+    let mut tx = Transaction::new_raw(inputs, outputs).unwrap();
+    tx.replaceable = replaceable;
+    tx.id = tx.compute_id().unwrap();
+    tx.sign_inputs(owner, &vec![owner.get_pub_key_hash(); inputs.len()]).unwrap();
+    tx
+}
@@ -3,33 +3,57 @@ use crypto::digest::Digest;
 use crypto::ed25519;
 use crypto::sha2::Sha256;
 use failure::format_err;
-use log::error;
+use log::{error, info};
 use rand::RngCore;
 use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
-use crate::errors::Result;
+use serde_json::json;
+use crate::coinselect::{CoinSelector, SelectionParams};
+use crate::errors::{Result, TxError, WalletError};
 use crate::tx::{TxInput, TxOutput};
 use crate::utxoset::UTXOSet;
 use crate::wallet::{Wallet};
 
+// subsidy of a mined block, the coinbase also collects the fees of its transactions
+pub const SUBSIDY: i32 = 100;
+
+// how a payment picks its coins: selection strategy, fee per 1000 bytes and
+// confirmations required; change goes to change_address or back to the sender,
+// data is attached as an unspendable output; replaces is a pending transaction
+// of the sender whose inputs are spent again to bump its fee
+pub struct SendOptions<'a> {
+    pub selector: &'a dyn CoinSelector,
+    pub fee_rate: i32,
+    pub min_conf: i32,
+    pub change_address: Option<&'a str>,
+    pub data: Option<Vec<u8>>,
+    pub replaceable: bool,
+    pub replaces: Option<&'a Transaction>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     pub id: String,
     pub vin: Vec<TxInput>,
     pub vout: Vec<TxOutput>,
+    pub replaceable: bool,      // opt-in: a pending conflicting transaction paying more may replace it
 }
 
 impl Transaction {
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
+    pub fn new_coinbase(to: String, data: String) -> Result<Transaction> {
+        Transaction::new_coinbase_with_fees(to, data, 0)
+    }
+
+    pub fn new_coinbase_with_fees(to: String, mut data: String, fees: i32) -> Result<Transaction> {
         let mut key = [0u8; 32];
         if data.is_empty() {
-            OsRng::default().fill_bytes(&mut key);
+            OsRng.fill_bytes(&mut key);
             data += &format!("Reward to '{}'", to);
         }
         let mut pub_key = Vec::from(data.as_bytes());
         pub_key.append(&mut Vec::from(key));
 
-        let output = TxOutput::new(100, to)?;
+        let output = TxOutput::new(SUBSIDY + fees, to)?;
         let mut transaction = Transaction {
             id: String::new(),
             vin: vec![TxInput {
@@ -39,103 +63,268 @@ impl Transaction {
                 pub_key
             }],
             vout: vec![output],
+            replaceable: false,
         };
-        transaction.id = transaction.hash()?;
+        transaction.id = transaction.compute_id()?;
         Ok(transaction)
     }
 
-    pub fn new_utxo(from:&Wallet, amount:i32, to:&Wallet, utxo_set:&UTXOSet) -> Result<Transaction> {
-        let mut pub_key_hash = from.public_key.clone();
-        Wallet::hash_pub_key(&mut pub_key_hash);
+    pub fn new_utxo(from:&Wallet, amount:i32, to:&str, utxo_set:&UTXOSet, options:&SendOptions) -> Result<Transaction> {
+        Transaction::new_send_many(from, &[(to.to_string(), amount)], utxo_set, options)
+    }
 
-        let (accum,utxos) = utxo_set.find_spendable_outputs(&pub_key_hash, amount)?;
-        if accum < amount {
-            error!("can't fulfill the transaction");
-            return Err(format_err!("not enough outputs to fulfill transaction, spentable:{accum} < {amount}"));
+    // pay several addresses at once
+    pub fn new_send_many(from:&Wallet, recipients:&[(String, i32)], utxo_set:&UTXOSet, options:&SendOptions) -> Result<Transaction> {
+        if !from.can_sign() {
+            return Err(WalletError::Locked.into());
+        }
+        if recipients.is_empty() {
+            return Err(format_err!("no recipient to send to"));
+        }
+        let mut vout = Vec::new();
+        let mut amount: i32 = 0;
+        for (address, value) in recipients {
+            amount = amount.checked_add(*value).ok_or_else(|| format_err!("total amount overflows"))?;
+            vout.push(TxOutput::new(*value, address.clone())?);
+        }
+        if let Some(data) = &options.data {
+            vout.push(TxOutput::new_data(data.clone())?);
         }
 
-        let mut vin = Vec::new();
-        for (txid, vout) in utxos {
-            for out in vout {
-                let input = TxInput{
-                    txid: txid.clone(),
-                    vout: out,
-                    signature: Vec::new(),
-                    pub_key: from.public_key.clone(),
-                };
-                vin.push(input);
+        let pub_key_hash = from.get_pub_key_hash();
+        let params = SelectionParams::new(amount, vout.len(), options.fee_rate, options.min_conf);
+        let found = match options.replaces {
+            Some(original) => utxo_set.find_replacement_outputs(&pub_key_hash, &params, original),
+            None => utxo_set.find_spendable_outputs(&pub_key_hash, &params, options.selector),
+        };
+        let selection = match found {
+            Ok(selection) => selection,
+            Err(e) => {
+                error!("can't fulfill the transaction");
+                return Err(e);
             }
+        };
+
+        info!("selected {} coins, fee {}, change {}", selection.utxos.len(), selection.fee, selection.change);
+        let mut vin = Vec::new();
+        for utxo in &selection.utxos {
+            let input = TxInput{
+                txid: utxo.txid.clone(),
+                vout: utxo.vout,
+                signature: Vec::new(),
+                pub_key: from.public_key.clone(),
+            };
+            vin.push(input);
         }
-        let mut vout = Vec::new();
-        vout.push(TxOutput::new(amount, to.get_address())?);
-        if accum > amount {
-           vout.push(TxOutput::new(accum - amount, from.get_address())?);
+        if selection.change > 0 {
+            let change_address = match options.change_address {
+                Some(address) => address.to_string(),
+                None => from.get_address(),
+            };
+            let change = i32::try_from(selection.change)
+                .map_err(|_| format_err!("change {} is too large for an output", selection.change))?;
+            vout.push(TxOutput::new(change, change_address)?);
         }
         let mut tx = Transaction{
             id: String::new(),
             vin,
-            vout
+            vout,
+            replaceable: options.replaceable || options.replaces.is_some(),
+        };
+        tx.id = tx.compute_id()?;
+        let prev_pub_key_hashes = utxo_set.find_prev_pub_key_hashes(&tx, &HashMap::new())?;
+        tx.sign(&from.private_key, &prev_pub_key_hashes)?;
+        Ok(tx)
+    }
+
+    // build an unsigned transaction from explicit outpoints and (address, amount) pairs
+    pub fn new_raw(inputs: &[(String, i32)], outputs: &[(String, i32)]) -> Result<Transaction> {
+        let mut vin = Vec::new();
+        for (txid, vout) in inputs {
+            vin.push(TxInput {
+                txid: txid.clone(),
+                vout: *vout,
+                signature: Vec::new(),
+                pub_key: Vec::new(),
+            });
+        }
+        let mut vout = Vec::new();
+        for (address, amount) in outputs {
+            vout.push(TxOutput::new(*amount, address.clone())?);
+        }
+        let mut tx = Transaction {
+            id: String::new(),
+            vin,
+            vout,
+            replaceable: false,
         };
-        tx.id = tx.hash()?;
-        utxo_set.blockchain.sign_transaction(&mut tx, &from.private_key)?;
+        tx.id = tx.compute_id()?;
+        Ok(tx)
+    }
+
+    pub fn to_hex(&self) -> Result<String> {
+        Ok(hex::encode(bincode::serialize(self)?))
+    }
+
+    pub fn from_hex(data: &str) -> Result<Transaction> {
+        let bytes = hex::decode(data.trim())?;
+        let mut tx: Transaction = bincode::deserialize(&bytes)?;
+        tx.id = tx.compute_id()?;
         Ok(tx)
     }
 
+    // sign the inputs spending outputs locked to the wallet, leave the others untouched,
+    // return the number of inputs signed
+    pub fn sign_inputs(&mut self, wallet: &Wallet, prev_pub_key_hashes: &[Vec<u8>]) -> Result<usize> {
+        if !wallet.can_sign() {
+            return Err(WalletError::Locked.into());
+        }
+        let hashes = self.signature_hashes(prev_pub_key_hashes)?;
+        let pub_key_hash = wallet.get_pub_key_hash();
+        let mut signed = 0;
+        for (idx, hash) in hashes.iter().enumerate() {
+            if prev_pub_key_hashes[idx] != pub_key_hash {
+                continue;
+            }
+            let sig = ed25519::signature(hash.as_bytes(), &wallet.private_key);
+            self.vin[idx].signature = sig.to_vec();
+            self.vin[idx].pub_key = wallet.public_key.clone();
+            signed += 1;
+        }
+        Ok(signed)
+    }
+
+    pub fn is_fully_signed(&self) -> bool {
+        self.vin.iter().all(|vin| !vin.signature.is_empty() && !vin.pub_key.is_empty())
+    }
+
+    // human readable form with byte fields hex encoded and outputs shown as addresses
+    pub fn to_json(&self) -> serde_json::Value {
+        let vin: Vec<serde_json::Value> = self.vin.iter().map(|vin| json!({
+            "txid": vin.txid,
+            "vout": vin.vout,
+            "signature": hex::encode(&vin.signature),
+            "pub_key": hex::encode(&vin.pub_key),
+        })).collect();
+        let vout: Vec<serde_json::Value> = self.vout.iter().map(|out| if out.is_data() {
+            json!({
+                "value": out.value,
+                "data": hex::encode(&out.pub_key_hash),
+            })
+        } else {
+            json!({
+                "value": out.value,
+                "address": Wallet::address_of(out.pub_key_hash.clone()),
+            })
+        }).collect();
+        json!({
+            "txid": self.id,
+            "coinbase": self.is_coinbase(),
+            "replaceable": self.replaceable,
+            "vin": vin,
+            "vout": vout,
+        })
+    }
+
+    pub fn output_value(&self) -> Result<i64> {
+        let mut value: i64 = 0;
+        for out in &self.vout {
+            if out.value < 0 {
+                return Err(TxError::InvalidOutputValue(out.value).into());
+            }
+            value += out.value as i64;
+        }
+        Ok(value)
+    }
+
+    // serialized size in bytes, the base of fee rates
+    pub fn size(&self) -> Result<usize> {
+        Ok(bincode::serialized_size(self)? as usize)
+    }
+
     pub fn hash(&self) -> Result<String> {
         let mut hasher = Sha256::new();
         hasher.input(&bincode::serialize(&self)?);
         Ok(hasher.result_str())
     }
 
+    // the id commits to everything but the signatures and the input public keys, so
+    // signing doesn't change it; the input of a coinbase is kept as it holds its data
+    pub fn compute_id(&self) -> Result<String> {
+        let mut tx_copy = if self.is_coinbase() { self.clone() } else { self.trim_copy() };
+        tx_copy.id = String::new();
+        tx_copy.hash()
+    }
+
+    // a transaction relayed or decoded with another id than its own is forged
+    pub fn check_id(&self) -> Result<()> {
+        let hash = self.compute_id()?;
+        if self.id != hash {
+            return Err(TxError::IdMismatch { id: self.id.clone(), hash }.into());
+        }
+        Ok(())
+    }
+
     pub fn is_coinbase(&self) -> bool {
         self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
     }
 
-    pub fn sign(&mut self, private_key: &[u8], prev_tx: HashMap<String, Transaction>) -> Result<()> {
+    pub fn sign(&mut self, private_key: &[u8], prev_pub_key_hashes: &[Vec<u8>]) -> Result<()> {
         if self.is_coinbase() {
             return Ok(());
         }
-        for vin in &self.vin {
-            if prev_tx.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("can't find transaction with id:{}",vin.txid));
-            }
-        }
-        let mut tx_copy = self.trim_copy();
-        for idx in 0..tx_copy.vin.len() {
-            let prev_tx = prev_tx.get(&tx_copy.vin[idx].txid).unwrap();
-            tx_copy.vin[idx].signature.clear();
-            tx_copy.vin[idx].pub_key = prev_tx.vout[tx_copy.vin[idx].vout as usize]
-                .pub_key_hash.clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[idx].pub_key = Vec::new();
-            let sig = ed25519::signature(tx_copy.id.as_bytes(), private_key);
+        let hashes = self.signature_hashes(prev_pub_key_hashes)?;
+        for (idx, hash) in hashes.iter().enumerate() {
+            let sig = ed25519::signature(hash.as_bytes(), private_key);
             self.vin[idx].signature = sig.to_vec();
         }
         Ok(())
     }
 
-    pub fn verify(&self, prev_tx: HashMap<String, Transaction>) -> Result<bool> {
+    pub fn verify(&self, prev_pub_key_hashes: &[Vec<u8>]) -> Result<bool> {
         if self.is_coinbase() {
             return Ok(true);
         }
-        for vin in &self.vin {
-            if prev_tx.get(&vin.txid).unwrap().id.is_empty() {
-                return Err(format_err!("can't find transaction with id:{}", vin.txid));
+        match self.verify_signatures(prev_pub_key_hashes) {
+            Ok(()) => Ok(true),
+            Err(e) => match e.downcast::<TxError>()? {
+                TxError::InvalidSignature { .. } => Ok(false),
+                e => Err(e.into()),
+            },
+        }
+    }
+
+    // verify every input signature given the pub_key_hash of the output it spends
+    pub fn verify_signatures(&self, prev_pub_key_hashes: &[Vec<u8>]) -> Result<()> {
+        let hashes = self.signature_hashes(prev_pub_key_hashes)?;
+        for (vin, hash) in self.vin.iter().zip(hashes.iter()) {
+            if !Wallet::verify_signature(hash.as_bytes(), &vin.pub_key, &vin.signature) {
+                return Err(TxError::InvalidSignature {
+                    txid: vin.txid.clone(),
+                    vout: vin.vout,
+                }.into());
             }
         }
+        Ok(())
+    }
+
+    // the message signed by each input: a trimmed copy of the transaction with the
+    // spent pub_key_hash in place of the input pub_key, chained from input to input
+    pub fn signature_hashes(&self, prev_pub_key_hashes: &[Vec<u8>]) -> Result<Vec<String>> {
+        if prev_pub_key_hashes.len() != self.vin.len() {
+            return Err(format_err!("expect {} previous outputs, got {}",
+                self.vin.len(), prev_pub_key_hashes.len()));
+        }
+        let mut hashes = Vec::new();
         let mut tx_copy = self.trim_copy();
-        for idx in 0..tx_copy.vin.len() {
-            let prev_tx = prev_tx.get(&tx_copy.vin[idx].txid).unwrap();
+        for (idx, pub_key_hash) in prev_pub_key_hashes.iter().enumerate() {
             tx_copy.vin[idx].signature.clear();
-            tx_copy.vin[idx].pub_key = prev_tx.vout[tx_copy.vin[idx].vout as usize]
-                .pub_key_hash.clone();
+            tx_copy.vin[idx].pub_key = pub_key_hash.clone();
             tx_copy.id = tx_copy.hash()?;
             tx_copy.vin[idx].pub_key = Vec::new();
-            if !ed25519::verify(tx_copy.id.as_bytes(), &self.vin[idx].pub_key, &self.vin[idx].signature) {
-                return Ok(false);
-            }
+            hashes.push(tx_copy.id.clone());
         }
-        Ok(true)
+        Ok(hashes)
     }
 
     fn trim_copy(&self) -> Transaction {
@@ -153,7 +342,8 @@ impl Transaction {
         for item in &self.vout {
             let out = TxOutput{
                 value: item.value,
-                pub_key_hash: item.pub_key_hash.clone()
+                pub_key_hash: item.pub_key_hash.clone(),
+                data: item.data,
             };
             vout.push(out);
         }
@@ -161,7 +351,82 @@ impl Transaction {
         Transaction{
             id: self.id.clone(),
             vin,
-            vout
+            vout,
+            replaceable: self.replaceable,
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mempool::Mempool;
+    use crate::testutil::{enter_temp_dir, new_chain, tip_reward, wallet};
+
+    fn tx_error(result: Result<()>) -> TxError {
+        result.unwrap_err().downcast::<TxError>().unwrap()
+    }
+
+    // the unsigned hex of a payment of 60 to payee out of the genesis reward of
+    // miner, and the hex once miner signed it, each step going through hex as on
+    // the command line
+    fn raw_payment(utxo_set: &UTXOSet, miner: &Wallet, payee: &Wallet) -> (String, String) {
+        let outputs = [(payee.get_address(), 60), (miner.get_address(), 40)];
+        let unsigned = Transaction::new_raw(&[(tip_reward(utxo_set), 0)], &outputs).unwrap().to_hex().unwrap();
+        let mut tx = Transaction::from_hex(&unsigned).unwrap();
+        let prev_pub_key_hashes = utxo_set.find_prev_pub_key_hashes(&tx, &HashMap::new()).unwrap();
+        assert_eq!(tx.sign_inputs(payee, &prev_pub_key_hashes).unwrap(), 0);
+        assert_eq!(tx.sign_inputs(miner, &prev_pub_key_hashes).unwrap(), 1);
+        (unsigned, tx.to_hex().unwrap())
+    }
+
+    #[test]
+    fn raw_transaction_is_created_signed_decoded_and_sent() {
+        let _dir = enter_temp_dir();
+        let (miner, payee) = (wallet(1), wallet(2));
+        let mut utxo_set = new_chain(&miner);
+        let (unsigned, signed) = raw_payment(&utxo_set, &miner, &payee);
+        assert!(!Transaction::from_hex(&unsigned).unwrap().is_fully_signed());
+
+        // signing leaves the id as created
+        let tx = Transaction::from_hex(&signed).unwrap();
+        assert!(tx.is_fully_signed());
+        let decoded = tx.to_json();
+        assert_eq!(decoded["txid"], Transaction::from_hex(&unsigned).unwrap().id);
+        assert_eq!(decoded["coinbase"], false);
+        assert_eq!(decoded["vin"][0]["pub_key"], hex::encode(&miner.public_key));
+        assert_eq!(decoded["vout"][0]["address"], payee.get_address());
+        assert_eq!(decoded["vout"][0]["value"], 60);
+
+        Mempool{}.add(&tx, &utxo_set).unwrap();
+        let (block, fees) = utxo_set.mine_pending(&miner.get_address()).unwrap();
+        assert_eq!(fees, 0);
+        assert_eq!(block.get_transactions()[1].id, tx.id);
+        assert_eq!(utxo_set.find_output(&tx.id, 0).unwrap().unwrap().value, 60);
+        assert!(utxo_set.find_output(&tx.vin[0].txid, 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn tampered_raw_transaction_is_rejected() {
+        let _dir = enter_temp_dir();
+        let (miner, payee) = (wallet(1), wallet(2));
+        let utxo_set = new_chain(&miner);
+        let (_, signed) = raw_payment(&utxo_set, &miner, &payee);
+        let tx = Transaction::from_hex(&signed).unwrap();
+
+        // a forged id is replaced by the hash when decoding, and refused as is
+        let mut forged = tx.clone();
+        forged.id = tip_reward(&utxo_set);
+        assert_eq!(Transaction::from_hex(&forged.to_hex().unwrap()).unwrap().id, tx.id);
+        assert!(matches!(tx_error(forged.check_id()), TxError::IdMismatch { .. }));
+        assert!(matches!(tx_error(Mempool{}.add(&forged, &utxo_set)), TxError::IdMismatch { .. }));
+        assert!(matches!(tx_error(utxo_set.verify_transactions(&[forged])), TxError::IdMismatch { .. }));
+
+        // changing an output gives another id, the signature no longer matches
+        let mut altered = tx.clone();
+        altered.vout[0].value = 50;
+        let altered = Transaction::from_hex(&altered.to_hex().unwrap()).unwrap();
+        assert_ne!(altered.id, tx.id);
+        assert!(matches!(tx_error(Mempool{}.add(&altered, &utxo_set)), TxError::InvalidSignature { .. }));
+        assert!(Mempool{}.get_transactions().unwrap().is_empty());
+    }
+}
@@ -1,11 +1,9 @@
 use bitcoincash_addr::Address;
+use failure::format_err;
 use serde::{Deserialize, Serialize};
 use crate::errors::Result;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct TxOutputs {
-    pub outputs: Vec<TxOutput>,
-}
+pub const MAX_DATA_SIZE: usize = 80;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TxInput {
@@ -26,7 +24,8 @@ impl TxInput {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TxOutput {
     pub value: i32,         // amount
-    pub pub_key_hash: Vec<u8>,    // recipient address
+    pub pub_key_hash: Vec<u8>,    // recipient address, or the data of a data output
+    pub data: bool,         // unspendable output carrying data
 }
 
 impl TxOutput {
@@ -34,21 +33,58 @@ impl TxOutput {
         self.pub_key_hash == unlock_data
     }
 
+    // unspendable output carrying arbitrary data, like OP_RETURN in bitcoin;
+    // the data takes the place of the pub_key_hash and the value is always 0
+    pub fn new_data(data: Vec<u8>) -> Result<Self> {
+        let output = TxOutput {
+            value: 0,
+            pub_key_hash: data,
+            data: true,
+        };
+        output.check()?;
+        Ok(output)
+    }
+
+    pub fn is_data(&self) -> bool {
+        self.data
+    }
+
+    // a data output holds no value and at most MAX_DATA_SIZE bytes,
+    // any other output a positive value
+    pub fn check(&self) -> Result<()> {
+        if self.data {
+            if self.value != 0 {
+                return Err(format_err!("data output must hold no value, got {}", self.value));
+            }
+            if self.pub_key_hash.is_empty() || self.pub_key_hash.len() > MAX_DATA_SIZE {
+                return Err(format_err!("data output must hold 1 to {} bytes, got {}", MAX_DATA_SIZE, self.pub_key_hash.len()));
+            }
+        } else if self.value <= 0 {
+            return Err(format_err!("output value must be positive, got {}", self.value));
+        }
+        Ok(())
+    }
+
     pub fn get_value(&self) -> i32 {
         self.value
     }
 
     pub fn new(value: i32, address: String) -> Result<Self> {
+        if value <= 0 {
+            return Err(format_err!("output value must be positive, got {}", value));
+        }
         let mut output = TxOutput{
             value,
             pub_key_hash: Vec::new(),
+            data: false,
         };
         output.lock(&address)?;
         Ok(output)
     }
 
     fn lock(&mut self, address: &str) -> Result<()> {
-        let pub_key_hash = Address::decode(address).unwrap().body;
+        let pub_key_hash = Address::decode(address)
+            .map_err(|e| format_err!("invalid address {}: {:?}", address, e))?.body;
         self.pub_key_hash = pub_key_hash;
         Ok(())
     }
@@ -1,17 +1,46 @@
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fmt::Debug;
+use failure::format_err;
 use log::info;
+use serde::{Deserialize, Serialize};
 use crate::block::Block;
+use crate::coinselect::{select_replacement, CoinSelector, Selection, SelectionParams, Utxo};
 use crate::blockchain::Blockchain;
-use crate::errors::Result;
+use crate::errors::{Result, TxError};
+use crate::filters::FilterIndex;
+use crate::headers::HeaderChain;
+use crate::mempool::{Mempool, MAX_BLOCK_SIZE};
+use crate::transaction::{Transaction, SUBSIDY};
 use crate::tx::TxOutput;
+use crate::wallet::Wallet;
 // use crate::tx::TxOutputs;
 
+// coins from a coinbase can't be spent before this many confirmations,
+// except the genesis reward which funds a new chain
+pub const COINBASE_MATURITY: i32 = 10;
+
 pub struct UTXOSet {
     pub blockchain: Blockchain,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UtxoEntry {
+    pub output: TxOutput,
+    pub height: i32,
+    pub coinbase: bool,
+}
+
+// an unspent output as (txid, vout, entry)
+pub type UtxoRecord = (String, i32, UtxoEntry);
+
+#[derive(Debug, Default)]
+pub struct Balance {
+    pub confirmed: i64,
+    pub unconfirmed: i64,
+    pub immature: i64,
+}
+
 impl Debug for UTXOSet {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let db = sled::open(UTXOSet::PATH).map_err(|_| std::fmt::Error)?;
@@ -20,8 +49,9 @@ impl Debug for UTXOSet {
             let (k,v) = kv.map_err(|_| std::fmt::Error)?;
             let key = String::from_utf8_lossy(&k);
             writeln!(f, "  {}: [", key)?;
-            let value:TxOutput = bincode::deserialize(&v).map_err(|_|fmt::Error)?;
-            writeln!(f, "    value:{}, pub_key:{:?}", value.value, value.pub_key_hash)?;
+            let entry:UtxoEntry = bincode::deserialize(&v).map_err(|_|fmt::Error)?;
+            writeln!(f, "    value:{}, pub_key:{:?}, height:{}, coinbase:{}", entry.output.value,
+                     entry.output.pub_key_hash, entry.height, entry.coinbase)?;
             writeln!(f, "]")?;
         }
         write!(f, "}}")
@@ -32,48 +62,280 @@ impl UTXOSet {
     const PATH: &'static str = "data/utxos";
     // rebuild the UTXO set
     pub fn reindex(&self) -> Result<()> {
+        self.blockchain.check_unpruned("reindex")?;
         // storage:
-        // key: txid-index, value: binary of TxOutput
-        if let Err(_) = std::fs::remove_dir_all(UTXOSet::PATH) {
+        // key: txid-index, value: binary of UtxoEntry
+        if std::fs::remove_dir_all(UTXOSet::PATH).is_err() {
             info!("directory not found: {}", UTXOSet::PATH);
         }
         let db = sled::open(UTXOSet::PATH)?;
         let utxos = self.blockchain.find_all_utxos();
         for (txid, outs) in &utxos {
-            for (idx, out) in outs.outputs.iter().enumerate() {
-                let key = Self::construct_key(txid, idx);
-                db.insert(key.as_bytes(), bincode::serialize(out)?)?;
+            for (idx, entry) in outs {
+                let key = Self::construct_key(txid, *idx);
+                db.insert(key.as_bytes(), bincode::serialize(entry)?)?;
             }
         }
+        FilterIndex{}.reindex(&self.blockchain)?;
         Ok(())
     }
 
+    // whether an output created at height can be spent in a block at spend_height
+    pub fn is_mature(coinbase: bool, height: i32, spend_height: i32) -> bool {
+        !coinbase || height == 0 || spend_height - height >= COINBASE_MATURITY
+    }
+
     fn construct_key(txid:&str, index:usize) -> String {
         format!("{txid}-{index}")
     }
 
+    // apply the block in transaction order: each transaction first spends its inputs,
+    // which may be outputs created earlier in the block, then adds its outputs
+    // the outputs spent from the set are kept as undo data of the block
     pub fn update(&self, block: &Block) -> Result<()> {
         let db = sled::open(UTXOSet::PATH)?;
+        let mut batch = sled::Batch::default();
+        let mut spent = Vec::new();
         for tx in block.get_transactions() {
-            // add TxOutput from block.vout
+            if !tx.is_coinbase() {
+                for item in &tx.vin {
+                    let key = Self::construct_key(item.txid.as_str(), item.vout as usize);
+                    // not there when created earlier in the block
+                    if let Some(value) = db.get(key.as_bytes())? {
+                        spent.push((item.txid.clone(), item.vout, bincode::deserialize::<UtxoEntry>(&value)?));
+                    }
+                    batch.remove(key.as_bytes());
+                }
+            }
+
             for (idx, item) in tx.vout.iter().enumerate() {
+                if item.is_data() {
+                    continue;
+                }
                 let key = Self::construct_key(tx.id.as_str(), idx);
-                db.insert(key.as_bytes(), bincode::serialize(item)?)?;
+                let entry = UtxoEntry {
+                    output: item.clone(),
+                    height: block.get_height(),
+                    coinbase: tx.is_coinbase(),
+                };
+                batch.insert(key.as_bytes(), bincode::serialize(&entry)?);
             }
+        }
+        db.apply_batch(batch)?;
+        db.flush()?;
+        self.blockchain.put_undo(&block.get_hash(), &spent)
+    }
+
+    // remove the tip block from the chain: its outputs leave the set, the ones
+    // it spent come back from its undo data and its transactions return to the mempool;
+    // the set is restored before the block goes so a failure leaves the tip in place
+    pub fn disconnect_tip(&mut self) -> Result<Block> {
+        let hash = self.blockchain.get_tip_hash();
+        let block = self.blockchain.get_block(&hash)?
+            .ok_or_else(|| format_err!("the body of tip block {} is pruned", hash))?;
+        if block.get_height() == 0 {
+            return Err(format_err!("can't disconnect the genesis block"));
+        }
+        let spent = self.blockchain.get_undo(&hash)?
+            .ok_or_else(|| format_err!("no undo data for block {}: it is pruned, was loaded with a UTXO snapshot or stored before undo data was kept", hash))?;
+        let db = sled::open(UTXOSet::PATH)?;
+        let mut batch = sled::Batch::default();
+        for tx in block.get_transactions() {
+            for idx in 0..tx.vout.len() {
+                batch.remove(Self::construct_key(&tx.id, idx).as_bytes());
+            }
+        }
+        for (txid, vout, entry) in &spent {
+            batch.insert(Self::construct_key(txid, *vout as usize).as_bytes(), bincode::serialize(entry)?);
+        }
+        db.apply_batch(batch)?;
+        db.flush()?;
+        drop(db);
+        self.blockchain.remove_tip()?;
+        FilterIndex{}.remove(&hash)?;
+        if (HeaderChain{}).hash_at(block.get_height())?.as_deref() == Some(hash.as_str()) {
+            HeaderChain{}.rewind(block.get_height() - 1)?;
+        }
+        for tx in block.get_transactions().iter().filter(|tx| !tx.is_coinbase()) {
+            if let Err(e) = (Mempool{}).add(tx, self) {
+                info!("drop transaction {} of block {}: {}", tx.id, hash, e);
+            }
+        }
+        Ok(block)
+    }
+
+    // apply a block mined by a peer, checked like a locally mined one, coinbase included
+    pub fn connect_block(&mut self, block: &Block) -> Result<()> {
+        self.verify_transactions(block.get_transactions())?;
+        self.blockchain.connect_block(block)?;
+        self.update(block)?;
+        FilterIndex{}.add(block)?;
+        Mempool{}.remove_block(block)?;
+        Ok(())
+    }
 
+    // verify the transactions, mine them into a new block and apply it to the UTXO set
+    pub fn add_block(&mut self, txs: Vec<Transaction>) -> Result<Block> {
+        self.verify_transactions(&txs)?;
+        let block = self.blockchain.add_block(txs)?;
+        self.update(&block)?;
+        FilterIndex{}.add(&block)?;
+        Mempool{}.remove_block(&block)?;
+        Ok(block)
+    }
+
+    // mine the pending transactions picked by fee rate, the coinbase paying the
+    // reward and their fees to address; returns the block and the fees
+    pub fn mine_pending(&mut self, address: &str) -> Result<(Block, i64)> {
+        let (txs, fees) = Mempool{}.select_for_block(self, MAX_BLOCK_SIZE)?;
+        let coinbase = Transaction::new_coinbase_with_fees(address.to_string(), String::new(), fees as i32)?;
+        let mut block_txs = vec![coinbase];
+        block_txs.extend(txs);
+        Ok((self.add_block(block_txs)?, fees))
+    }
+
+    // contextual validation of transactions that go into the same block:
+    // every input refers to an unspent output owned by the input's public key,
+    // no output is spent twice, and inputs cover outputs; outputs created by a
+    // transaction are staged so the ones after it in txs can spend them. only the
+    // first transaction may be a coinbase, paying at most the subsidy and the fees
+    pub fn verify_transactions(&self, txs: &[Transaction]) -> Result<()> {
+        // the transactions are checked for inclusion in the next block
+        let next_height = self.blockchain.get_best_height()? + 1;
+        let db = sled::open(UTXOSet::PATH)?;
+        let mut spent = HashSet::new();
+        let mut staged = HashMap::<(String, i32), UtxoEntry>::new();
+        let mut fees: i64 = 0;
+        for (idx, tx) in txs.iter().enumerate() {
+            tx.check_id()?;
+            for out in &tx.vout {
+                out.check()?;
+            }
             if tx.is_coinbase() {
+                if idx != 0 {
+                    return Err(TxError::MisplacedCoinbase(tx.id.clone()).into());
+                }
+                Self::stage_outputs(&mut staged, tx, next_height);
                 continue;
             }
+            let mut input_value: i64 = 0;
+            let mut prev_pub_key_hashes = Vec::new();
+            for vin in &tx.vin {
+                if !spent.insert((vin.txid.clone(), vin.vout)) {
+                    return Err(TxError::DuplicateInput { txid: vin.txid.clone(), vout: vin.vout }.into());
+                }
+                let entry: UtxoEntry = match staged.remove(&(vin.txid.clone(), vin.vout)) {
+                    Some(entry) => entry,
+                    None => match db.get(Self::construct_key(&vin.txid, vin.vout as usize))? {
+                        Some(value) if vin.vout >= 0 => bincode::deserialize(&value)?,
+                        _ => return Err(self.missing_output_error(&vin.txid, vin.vout)),
+                    },
+                };
+                if !Self::is_mature(entry.coinbase, entry.height, next_height) {
+                    return Err(TxError::ImmatureCoinbase { txid: vin.txid.clone(), vout: vin.vout }.into());
+                }
+                let out = entry.output;
+                let mut pub_key_hash = vin.pub_key.clone();
+                Wallet::hash_pub_key(&mut pub_key_hash);
+                if !out.can_be_unlock_with(&pub_key_hash) {
+                    return Err(TxError::OwnerMismatch { txid: vin.txid.clone(), vout: vin.vout }.into());
+                }
+                input_value += out.value as i64;
+                prev_pub_key_hashes.push(out.pub_key_hash);
+            }
+
+            let output_value = tx.output_value()?;
+            if input_value < output_value {
+                return Err(TxError::InsufficientInputs { input: input_value, output: output_value }.into());
+            }
+            fees += input_value - output_value;
+
+            tx.verify_signatures(&prev_pub_key_hashes)?;
+            Self::stage_outputs(&mut staged, tx, next_height);
+        }
 
-            // prev transactions in block.vin are now spent, so remove them
-            for item in &tx.vin {
-                let key = Self::construct_key(item.txid.as_str(), item.vout as usize);
-                db.remove(key.as_bytes())?;
+        if let Some(coinbase) = txs.first().filter(|tx| tx.is_coinbase()) {
+            let value = coinbase.output_value()?;
+            let allowed = SUBSIDY as i64 + fees;
+            if value > allowed {
+                return Err(TxError::CoinbaseValue { value, allowed }.into());
             }
         }
         Ok(())
     }
 
+    fn stage_outputs(staged: &mut HashMap<(String, i32), UtxoEntry>, tx: &Transaction, height: i32) {
+        for (idx, out) in tx.vout.iter().enumerate() {
+            if out.is_data() {
+                continue;
+            }
+            staged.insert((tx.id.clone(), idx as i32), UtxoEntry {
+                output: out.clone(),
+                height,
+                coinbase: tx.is_coinbase(),
+            });
+        }
+    }
+
+    // an output missing from the UTXO set was either spent or never existed
+    fn missing_output_error(&self, txid: &str, vout: i32) -> failure::Error {
+        match self.blockchain.find_transaction(txid) {
+            Some(tx) if vout >= 0 && (vout as usize) < tx.vout.len() => {
+                TxError::SpentOutput { txid: txid.to_string(), vout }.into()
+            }
+            None if matches!(self.blockchain.get_pruned_height(), Ok(Some(_))) => {
+                TxError::MissingOutput { txid: txid.to_string(), vout }.into()
+            }
+            _ => TxError::UnknownOutput { txid: txid.to_string(), vout }.into(),
+        }
+    }
+
+    // pub_key_hash of the output spent by each input of tx, looked up in the pending
+    // transactions then in the set
+    pub fn find_prev_pub_key_hashes(&self, tx: &Transaction, pending: &HashMap<String, Transaction>) -> Result<Vec<Vec<u8>>> {
+        let mut hashes = Vec::new();
+        for vin in &tx.vin {
+            let out = match pending.get(&vin.txid) {
+                Some(prev) => usize::try_from(vin.vout).ok().and_then(|idx| prev.vout.get(idx)).cloned(),
+                None => self.find_output(&vin.txid, vin.vout)?,
+            };
+            let out = out.ok_or_else(|| self.missing_output_error(&vin.txid, vin.vout))?;
+            hashes.push(out.pub_key_hash);
+        }
+        Ok(hashes)
+    }
+
+    // check the signatures of tx, whose inputs may spend outputs of pending transactions
+    pub fn verify_transaction(&self, tx: &Transaction, pending: &HashMap<String, Transaction>) -> Result<bool> {
+        let prev_pub_key_hashes = self.find_prev_pub_key_hashes(tx, pending)?;
+        tx.verify(&prev_pub_key_hashes)
+    }
+
+    pub fn get_entries(&self) -> Result<Vec<UtxoRecord>> {
+        let db = sled::open(UTXOSet::PATH)?;
+        let mut entries = Vec::new();
+        for kv in db.iter() {
+            let (k, v) = kv?;
+            let key = String::from_utf8(k.to_vec())?;
+            let itr = key.split('-').collect::<Vec<&str>>();
+            entries.push((itr[0].to_string(), itr[1].parse::<i32>()?, bincode::deserialize(&v)?));
+        }
+        Ok(entries)
+    }
+
+    // replace the whole set, as when loading a snapshot
+    pub fn replace(&self, entries: &[UtxoRecord]) -> Result<()> {
+        let db = sled::open(UTXOSet::PATH)?;
+        db.clear()?;
+        let mut batch = sled::Batch::default();
+        for (txid, vout, entry) in entries {
+            batch.insert(Self::construct_key(txid, *vout as usize).as_bytes(), bincode::serialize(entry)?);
+        }
+        db.apply_batch(batch)?;
+        db.flush()?;
+        Ok(())
+    }
+
     pub fn count_transactions(&self) -> Result<usize> {
         let db = sled::open(UTXOSet::PATH)?;
         let mut set = HashSet::new();
@@ -82,33 +344,106 @@ impl UTXOSet {
             let txid = String::from_utf8(k.to_vec())?.split('-').next().unwrap().to_string();
             set.insert(txid);
         }
-        Ok(set.iter().count())
+        Ok(set.len())
     }
 
-    pub fn find_spendable_outputs(&self, address:&[u8], amount:i32) -> Result<(i32,HashMap<String, Vec<i32>>)> {
-        let mut accum = 0;
-        let mut spent_map = HashMap::<String,Vec<i32>>::new();
+    // pick the outputs locked to pub_key_hash that pay for the payment and its fee,
+    // leaving out outputs with fewer than params.min_conf confirmations, immature
+    // coinbase outputs and outputs already spent by pending transactions
+    pub fn find_spendable_outputs(&self, pub_key_hash: &[u8], params: &SelectionParams, selector: &dyn CoinSelector) -> Result<Selection> {
+        let tip_height = self.blockchain.get_best_height()?;
+        let pending = Mempool{}.spent_outpoints()?;
+        let utxos: Vec<Utxo> = self.find_unspent(pub_key_hash)?.into_iter()
+            .filter(|u| tip_height - u.height + 1 >= params.min_conf)
+            .filter(|u| Self::is_mature(u.coinbase, u.height, tip_height + 1))
+            .filter(|u| !pending.contains(&(u.txid.clone(), u.vout)))
+            .collect();
+        selector.select(&utxos, params)
+    }
+
+    // coins for a replacement of the pending transaction original: all of its inputs
+    // plus spendable coins of pub_key_hash if the new fee needs more
+    pub fn find_replacement_outputs(&self, pub_key_hash: &[u8], params: &SelectionParams, original: &Transaction) -> Result<Selection> {
+        let tip_height = self.blockchain.get_best_height()?;
+        let pending = Mempool{}.spent_outpoints()?;
+        let replaced: HashSet<(String, i32)> = original.vin.iter()
+            .map(|vin| (vin.txid.clone(), vin.vout))
+            .collect();
+        let (required, others): (Vec<Utxo>, Vec<Utxo>) = self.find_unspent(pub_key_hash)?.into_iter()
+            .filter(|u| !pending.contains(&(u.txid.clone(), u.vout)) || replaced.contains(&(u.txid.clone(), u.vout)))
+            .partition(|u| replaced.contains(&(u.txid.clone(), u.vout)));
+        if required.len() != replaced.len() {
+            return Err(format_err!("transaction {} spends outputs the wallet can't spend again", original.id));
+        }
+        let others: Vec<Utxo> = others.into_iter()
+            .filter(|u| tip_height - u.height + 1 >= params.min_conf)
+            .filter(|u| Self::is_mature(u.coinbase, u.height, tip_height + 1))
+            .collect();
+        select_replacement(required, &others, params)
+    }
+
+    pub fn find_unspent(&self, pub_key_hash: &[u8]) -> Result<Vec<Utxo>> {
+        let mut utxos = Vec::new();
         let db = sled::open(UTXOSet::PATH)?;
         for kv in db.iter() {
             let (k ,v) = kv?;
             let key = String::from_utf8(k.to_vec())?;
             let itr = key.split('-').collect::<Vec<&str>>();
-            let txid = itr[0];
-            let idx = itr[1].parse::<i32>()?;
-            let out: TxOutput = bincode::deserialize(&v)?;
-
-            if out.can_be_unlock_with(address) && accum < amount {
-                accum += out.value;
+            let entry: UtxoEntry = bincode::deserialize(&v)?;
+            if entry.output.can_be_unlock_with(pub_key_hash) {
+                utxos.push(Utxo {
+                    txid: itr[0].to_string(),
+                    vout: itr[1].parse::<i32>()?,
+                    output: entry.output,
+                    height: entry.height,
+                    coinbase: entry.coinbase,
+                });
+            }
+        }
+        Ok(utxos)
+    }
 
-                match spent_map.get_mut(txid) {
-                    Some(val) => val.push(idx),
-                    None => {
-                        spent_map.insert(txid.to_string(), vec![idx]);
-                    }
-                }
+    // split the coins of pub_key_hash by depth: confirmed with at least min_conf confirmations,
+    // unconfirmed from pending transactions or shallower blocks, and immature coinbase
+    pub fn get_balance(&self, pub_key_hash: &[u8], min_conf: i32, pending: &[Transaction]) -> Result<Balance> {
+        let tip_height = self.blockchain.get_best_height()?;
+        let mut spent = HashSet::new();
+        for tx in pending {
+            for vin in &tx.vin {
+                spent.insert((vin.txid.clone(), vin.vout));
+            }
+        }
+        let mut balance = Balance::default();
+        for utxo in self.find_unspent(pub_key_hash)? {
+            let value = utxo.output.value as i64;
+            if spent.contains(&(utxo.txid.clone(), utxo.vout)) {
+                continue;
             }
+            if !Self::is_mature(utxo.coinbase, utxo.height, tip_height + 1) {
+                balance.immature += value;
+            } else if tip_height - utxo.height + 1 >= min_conf {
+                balance.confirmed += value;
+            } else {
+                balance.unconfirmed += value;
+            }
+        }
+        for tx in pending {
+            for out in tx.vout.iter().filter(|out| out.can_be_unlock_with(pub_key_hash)) {
+                balance.unconfirmed += out.value as i64;
+            }
+        }
+        Ok(balance)
+    }
+
+    pub fn find_output(&self, txid: &str, vout: i32) -> Result<Option<TxOutput>> {
+        if vout < 0 {
+            return Ok(None);
+        }
+        let db = sled::open(UTXOSet::PATH)?;
+        match db.get(Self::construct_key(txid, vout as usize))? {
+            Some(value) => Ok(Some(bincode::deserialize::<UtxoEntry>(&value)?.output)),
+            None => Ok(None),
         }
-        Ok((accum, spent_map))
     }
 
     pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Result<Vec<TxOutput>> {
@@ -117,11 +452,97 @@ impl UTXOSet {
         for kv in db.iter() {
             let (_ ,v) = kv?;
             // let txid = String::from_utf8(k.to_vec())?;
-            let out: TxOutput = bincode::deserialize(&v)?;
-            if out.can_be_unlock_with(pub_key_hash) {
-                utxos.push(out);
+            let entry: UtxoEntry = bincode::deserialize(&v)?;
+            if entry.output.can_be_unlock_with(pub_key_hash) {
+                utxos.push(entry.output);
             }
         }
         Ok(utxos)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{enter_temp_dir, new_chain, spend, tip_reward, wallet};
+
+    fn tx_error(result: Result<()>) -> TxError {
+        result.unwrap_err().downcast::<TxError>().unwrap()
+    }
+
+    #[test]
+    fn input_must_be_signed_by_the_owner_of_the_output() {
+        let _dir = enter_temp_dir();
+        let (miner, thief) = (wallet(1), wallet(3));
+        let utxo_set = new_chain(&miner);
+        let tx = spend(&thief, &[(tip_reward(&utxo_set), 0)], &[(thief.get_address(), 100)], false);
+        assert!(matches!(tx_error(utxo_set.verify_transactions(&[tx])), TxError::OwnerMismatch { vout: 0, .. }));
+    }
+
+    #[test]
+    fn malformed_signature_and_public_key_are_rejected() {
+        let _dir = enter_temp_dir();
+        let (miner, payee) = (wallet(1), wallet(2));
+        let utxo_set = new_chain(&miner);
+        let tx = spend(&miner, &[(tip_reward(&utxo_set), 0)], &[(payee.get_address(), 100)], false);
+        let mut short_signature = tx.clone();
+        short_signature.vin[0].signature.truncate(10);
+        assert!(matches!(tx_error(utxo_set.verify_transactions(&[short_signature])), TxError::InvalidSignature { .. }));
+        let mut short_key = tx;
+        short_key.vin[0].pub_key.truncate(3);
+        assert!(matches!(tx_error(utxo_set.verify_transactions(&[short_key])), TxError::OwnerMismatch { .. }));
+    }
+
+    #[test]
+    fn output_spent_twice_in_a_block_is_rejected() {
+        let _dir = enter_temp_dir();
+        let (miner, payee) = (wallet(1), wallet(2));
+        let utxo_set = new_chain(&miner);
+        let reward = [(tip_reward(&utxo_set), 0)];
+        let first = spend(&miner, &reward, &[(payee.get_address(), 100)], false);
+        let second = spend(&miner, &reward, &[(miner.get_address(), 100)], false);
+        utxo_set.verify_transactions(std::slice::from_ref(&first)).unwrap();
+        assert!(matches!(tx_error(utxo_set.verify_transactions(&[first, second])), TxError::DuplicateInput { .. }));
+        let twice = spend(&miner, &[reward[0].clone(), reward[0].clone()], &[(payee.get_address(), 200)], false);
+        assert!(matches!(tx_error(utxo_set.verify_transactions(&[twice])), TxError::DuplicateInput { .. }));
+    }
+
+    #[test]
+    fn outputs_must_not_exceed_inputs() {
+        let _dir = enter_temp_dir();
+        let (miner, payee) = (wallet(1), wallet(2));
+        let utxo_set = new_chain(&miner);
+        let tx = spend(&miner, &[(tip_reward(&utxo_set), 0)], &[(payee.get_address(), 60), (miner.get_address(), 41)], false);
+        assert!(matches!(tx_error(utxo_set.verify_transactions(&[tx])),
+            TxError::InsufficientInputs { input: 100, output: 101 }));
+    }
+
+    #[test]
+    fn unknown_previous_transaction_is_an_error() {
+        let _dir = enter_temp_dir();
+        let (miner, payee) = (wallet(1), wallet(2));
+        let utxo_set = new_chain(&miner);
+        let tx = spend(&miner, &[("ab".repeat(32), 0)], &[(payee.get_address(), 10)], false);
+        assert!(matches!(tx_error(utxo_set.verify_transactions(std::slice::from_ref(&tx))), TxError::UnknownOutput { vout: 0, .. }));
+        let err = utxo_set.verify_transaction(&tx, &HashMap::new()).unwrap_err();
+        assert!(matches!(err.downcast::<TxError>().unwrap(), TxError::UnknownOutput { .. }));
+        let beyond = spend(&miner, &[(tip_reward(&utxo_set), 1)], &[(payee.get_address(), 10)], false);
+        assert!(matches!(tx_error(utxo_set.verify_transactions(&[beyond])), TxError::UnknownOutput { vout: 1, .. }));
+    }
+
+    #[test]
+    fn coinbase_comes_first_and_pays_at_most_the_subsidy_and_fees() {
+        let _dir = enter_temp_dir();
+        let (miner, payee) = (wallet(1), wallet(2));
+        let utxo_set = new_chain(&miner);
+        // pays a fee of 10
+        let tx = spend(&miner, &[(tip_reward(&utxo_set), 0)], &[(payee.get_address(), 90)], false);
+        let coinbase = |fees| Transaction::new_coinbase_with_fees(miner.get_address(), String::new(), fees).unwrap();
+
+        utxo_set.verify_transactions(&[coinbase(10), tx.clone()]).unwrap();
+        assert!(matches!(tx_error(utxo_set.verify_transactions(&[tx.clone(), coinbase(10)])), TxError::MisplacedCoinbase(_)));
+        assert!(matches!(tx_error(utxo_set.verify_transactions(&[coinbase(0), coinbase(0)])), TxError::MisplacedCoinbase(_)));
+        assert!(matches!(tx_error(utxo_set.verify_transactions(&[coinbase(11), tx])),
+            TxError::CoinbaseValue { value: 111, allowed: 110 }));
+    }
+}
@@ -1,14 +1,30 @@
-use crate::errors::Result;
+use crate::errors::{Result, WalletError};
 use std::collections::HashMap;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::SystemTime;
 use bitcoincash_addr::{Address, HashType, Scheme};
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use bip39::Mnemonic;
 use crypto::digest::Digest;
 use serde::{Deserialize, Serialize};
 use crypto::ed25519;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
 use crypto::ripemd160::Ripemd160;
-use crypto::sha2::Sha256;
+use crypto::scrypt::{scrypt, ScryptParams};
+use crypto::sha2::{Sha256, Sha512};
+use failure::format_err;
 use log::info;
 use rand::RngCore;
 
+// stop looking for used hd addresses after this many unused ones in a row
+pub const GAP_LIMIT: u32 = 20;
+// prefix of signed messages so a message signature can never pass for a transaction one
+const MESSAGE_MAGIC: &[u8] = b"Signed Message:\n";
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Wallet {
     pub private_key: Vec<u8>,      // account password
@@ -17,16 +33,6 @@ pub struct Wallet {
 
 impl Wallet {
 
-    pub fn new() -> Self {
-        let mut key = [0u8; 32];
-        rand::rngs::OsRng.fill_bytes(&mut key);
-        let (private_key, public_key) = ed25519::keypair(&key);
-        Wallet {
-            private_key: private_key.to_vec(),
-            public_key: public_key.to_vec()
-        }
-    }
-
     /*
     Address creation:
     A private-public key pair is generated using cryptographic algorithms.
@@ -36,8 +42,12 @@ impl Wallet {
     pub fn get_address(&self) -> String {
         let mut pub_key = self.public_key.clone();
         Wallet::hash_pub_key(&mut pub_key);
+        Wallet::address_of(pub_key)
+    }
+
+    pub fn address_of(pub_key_hash: Vec<u8>) -> String {
         let address = Address {
-            body: pub_key,
+            body: pub_key_hash,
             scheme: Scheme::Base58,
             hash_type: HashType::Script,
             ..Default::default()
@@ -45,9 +55,77 @@ impl Wallet {
         address.encode().unwrap()
     }
 
+    // rebuild a wallet from the 64 bytes ed25519 secret (seed followed by public key)
+    pub fn from_private_key(private_key: &[u8]) -> Result<Self> {
+        if private_key.len() != 64 {
+            return Err(format_err!("invalid private key length {}", private_key.len()));
+        }
+        let (secret, public_key) = ed25519::keypair(&private_key[..32]);
+        if secret[..] != private_key[..] {
+            return Err(format_err!("private key doesn't match its public key"));
+        }
+        Ok(Wallet {
+            private_key: secret.to_vec(),
+            public_key: public_key.to_vec(),
+        })
+    }
+
+    // false while the wallet database is locked
+    pub fn can_sign(&self) -> bool {
+        !self.private_key.is_empty()
+    }
+
+    pub fn get_pub_key_hash(&self) -> Vec<u8> {
+        let mut pub_key = self.public_key.clone();
+        Wallet::hash_pub_key(&mut pub_key);
+        pub_key
+    }
+
+    // prove ownership of the address: hex of the public key followed by the signature
+    pub fn sign_message(&self, message: &str) -> Result<String> {
+        if !self.can_sign() {
+            return Err(WalletError::Locked.into());
+        }
+        let sig = ed25519::signature(&Wallet::message_hash(message), &self.private_key);
+        let mut data = self.public_key.clone();
+        data.extend_from_slice(&sig);
+        Ok(hex::encode(data))
+    }
+
+    // ed25519::verify slices the key and the signature, so other lengths are refused first
+    pub fn verify_signature(message: &[u8], pub_key: &[u8], signature: &[u8]) -> bool {
+        pub_key.len() == 32 && signature.len() == 64 && ed25519::verify(message, pub_key, signature)
+    }
+
+    pub fn verify_message(address: &str, signature: &str, message: &str) -> Result<bool> {
+        let pub_key_hash = Address::decode(address)
+            .map_err(|e| format_err!("invalid address {}: {:?}", address, e))?
+            .body;
+        let data = hex::decode(signature.trim())?;
+        if data.len() != 96 {
+            return Err(format_err!("invalid message signature length {}", data.len()));
+        }
+        let (pub_key, sig) = data.split_at(32);
+        let mut signer = pub_key.to_vec();
+        Wallet::hash_pub_key(&mut signer);
+        Ok(signer == pub_key_hash && ed25519::verify(&Wallet::message_hash(message), pub_key, sig))
+    }
+
+    fn message_hash(message: &str) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.input(MESSAGE_MAGIC);
+        hasher.input(&(message.len() as u64).to_le_bytes());
+        hasher.input(message.as_bytes());
+        let mut hash = vec![0u8; 32];
+        hasher.result(&mut hash);
+        hash
+    }
+
     pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
         let mut hasher = Sha256::new();
         hasher.input(pub_key);
+        // the digest is written in place, a public key received from a peer may be shorter
+        pub_key.resize(32, 0);
         hasher.result(pub_key);
         let mut hasher2 = Ripemd160::new();
         hasher2.input(pub_key);
@@ -56,30 +134,550 @@ impl Wallet {
     }
 }
 
+// hd wallet: every key derives from the seed of a bip39 mnemonic following slip-10
+// for ed25519, which only allows hardened derivation, at m/44'/1'/0'/index'
+#[derive(Debug, Clone)]
+pub struct HdChain {
+    entropy: Vec<u8>,       // empty while the wallet is locked
+    seed: Vec<u8>,          // of the mnemonic, computed once as it's slow to stretch
+    pub next_index: u32,
+}
+
+impl HdChain {
+    const PATH: [u32; 3] = [44, 1, 0];
+
+    pub fn generate() -> Result<HdChain> {
+        let mut entropy = vec![0u8; 16];
+        rand::rngs::OsRng.fill_bytes(&mut entropy);
+        HdChain::from_entropy(entropy, 0)
+    }
+
+    pub fn from_mnemonic(words: &str) -> Result<HdChain> {
+        HdChain::from_entropy(Mnemonic::parse(words)?.to_entropy(), 0)
+    }
+
+    // no entropy is a locked chain, which can't derive keys
+    fn from_entropy(entropy: Vec<u8>, next_index: u32) -> Result<HdChain> {
+        let seed = if entropy.is_empty() {
+            Vec::new()
+        } else {
+            Mnemonic::from_entropy(&entropy)?.to_seed("").to_vec()
+        };
+        Ok(HdChain { entropy, seed, next_index })
+    }
+
+    fn lock(&mut self) {
+        self.entropy.clear();
+        self.seed.clear();
+    }
+
+    pub fn mnemonic(&self) -> Result<String> {
+        if self.entropy.is_empty() {
+            return Err(WalletError::Locked.into());
+        }
+        Ok(Mnemonic::from_entropy(&self.entropy)?.to_string())
+    }
+
+    pub fn derive(&self, index: u32) -> Result<Wallet> {
+        if self.seed.is_empty() {
+            return Err(WalletError::Locked.into());
+        }
+        let mut node = hmac_sha512(b"ed25519 seed", &self.seed);
+        for idx in HdChain::PATH.iter().chain(std::iter::once(&index)) {
+            let mut data = vec![0u8];
+            data.extend_from_slice(&node[..32]);
+            data.extend_from_slice(&(idx | 0x8000_0000).to_be_bytes());
+            node = hmac_sha512(&node[32..], &data);
+        }
+        let (private_key, public_key) = ed25519::keypair(&node[..32]);
+        Ok(Wallet {
+            private_key: private_key.to_vec(),
+            public_key: public_key.to_vec(),
+        })
+    }
+}
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> [u8; 64] {
+    let mut mac = Hmac::new(Sha512::new(), key);
+    mac.input(data);
+    let mut output = [0u8; 64];
+    mac.raw_result(&mut output);
+    output
+}
+
+// address tracked without its private key, the public key is known when imported with importpubkey
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WatchOnly {
+    pub pub_key: Option<Vec<u8>>,
+}
+
+// backupwallet file, json:
+// {"version":1,"mnemonic":"<words>"|null,"next_index":<n>,"keys":[{"address":"..","private_key":"<hex>","label":".."|null}]}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WalletBackup {
+    pub version: u32,
+    pub mnemonic: Option<String>,
+    pub next_index: u32,
+    pub keys: Vec<BackupKey>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BackupKey {
+    pub address: String,
+    pub private_key: String,
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+impl WalletBackup {
+    const VERSION: u32 = 1;
+}
+
+// key derivation parameters and a known plaintext encrypted with the derived key,
+// used to check a passphrase before unlocking
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Encryption {
+    salt: Vec<u8>,
+    log_n: u8,
+    r: u32,
+    p: u32,
+    check: Sealed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Sealed {
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+    tag: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct EncryptedWallet {
+    public_key: Vec<u8>,
+    private_key: Sealed,
+}
+
+// derived key sealed with a session token that is only handed to the user, kept
+// until the unlock timeout expires or walletlock is called
+#[derive(Serialize, Deserialize, Debug)]
+struct UnlockSession {
+    key: Sealed,
+    expires: u128,
+}
+
+impl Sealed {
+    fn seal(key: &[u8], plaintext: &[u8]) -> Sealed {
+        let mut nonce = vec![0u8; 12];
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+        let mut cipher = AesGcm::new(KeySize::KeySize256, key, &nonce, &[]);
+        let mut ciphertext = vec![0u8; plaintext.len()];
+        let mut tag = vec![0u8; 16];
+        cipher.encrypt(plaintext, &mut ciphertext, &mut tag);
+        Sealed { nonce, ciphertext, tag }
+    }
+
+    fn open(&self, key: &[u8]) -> Option<Vec<u8>> {
+        // AesGcm asserts on the key length
+        if key.len() != 32 {
+            return None;
+        }
+        let mut cipher = AesGcm::new(KeySize::KeySize256, key, &self.nonce, &[]);
+        let mut plaintext = vec![0u8; self.ciphertext.len()];
+        if cipher.decrypt(&self.ciphertext, &mut plaintext, &self.tag) {
+            Some(plaintext)
+        } else {
+            None
+        }
+    }
+}
+
+impl Encryption {
+    const CHECK: &'static [u8] = b"simple-blockchain wallet";
+
+    fn new(passphrase: &str) -> (Encryption, Vec<u8>) {
+        let mut salt = vec![0u8; 16];
+        rand::rngs::OsRng.fill_bytes(&mut salt);
+        let (log_n, r, p) = (14, 8, 1);
+        let key = Encryption::derive_key(passphrase, &salt, log_n, r, p);
+        let check = Sealed::seal(&key, Encryption::CHECK);
+        (Encryption { salt, log_n, r, p, check }, key)
+    }
+
+    fn derive_key(passphrase: &str, salt: &[u8], log_n: u8, r: u32, p: u32) -> Vec<u8> {
+        let mut key = vec![0u8; 32];
+        scrypt(passphrase.as_bytes(), salt, &ScryptParams::new(log_n, r, p), &mut key);
+        key
+    }
+
+    fn unlock(&self, passphrase: &str) -> Result<Vec<u8>> {
+        let key = Encryption::derive_key(passphrase, &self.salt, self.log_n, self.r, self.p);
+        if !self.check_key(&key) {
+            return Err(WalletError::WrongPassphrase.into());
+        }
+        Ok(key)
+    }
+
+    fn check_key(&self, key: &[u8]) -> bool {
+        self.check.open(key).is_some_and(|check| check == Encryption::CHECK)
+    }
+}
+
 pub struct WalletManager {
     pub wallets: HashMap<String, Wallet>,
+    pub watch_only: HashMap<String, WatchOnly>,
+    pub labels: HashMap<String, String>,      // own or watched address -> label
+    pub contacts: HashMap<String, String>,    // contact name -> external address
+    pub change_outputs: HashMap<String, i32>, // replaceable txid -> vout of its change
+    hd: Option<HdChain>,
+    encryption: Option<Encryption>,
+    key: Option<Vec<u8>>,
 }
 
 impl WalletManager {
+    const PATH: &'static str = "data/wallets";
+    const UNLOCK_PATH: &'static str = "data/wallet.unlock";
+    pub const SESSION_VAR: &'static str = "WALLET_SESSION";
+
     pub fn new() -> Result<Self> {
         let mut wallets = HashMap::new();
-        let db = sled::open("data/wallets")?;
+        let db = sled::open(WalletManager::PATH)?;
+        let encryption = match db.open_tree("encryption")?.get("params")? {
+            Some(value) => Some(bincode::deserialize::<Encryption>(&value)?),
+            None => None,
+        };
+        let key = match &encryption {
+            Some(encryption) => WalletManager::load_session(encryption)?,
+            None => None,
+        };
         for item in db.iter() {
-            let (key, value) = item?;
-            let address = String::from_utf8(key.to_vec())?;
-            let wallet = bincode::deserialize::<Wallet>(&value)?;
+            let (key_bytes, value) = item?;
+            let address = String::from_utf8(key_bytes.to_vec())?;
+            let wallet = match &encryption {
+                None => bincode::deserialize::<Wallet>(&value)?,
+                Some(_) => {
+                    let encrypted = bincode::deserialize::<EncryptedWallet>(&value)?;
+                    let private_key = match &key {
+                        Some(key) => encrypted.private_key.open(key)
+                            .ok_or_else(|| format_err!("can't decrypt wallet {}", address))?,
+                        None => Vec::new(),
+                    };
+                    Wallet { private_key, public_key: encrypted.public_key }
+                }
+            };
             wallets.insert(address, wallet);
         }
+        let watch_only = load_tree::<WatchOnly>(&db.open_tree("watchonly")?)?;
+        let labels = load_tree::<String>(&db.open_tree("labels")?)?;
+        let contacts = load_tree::<String>(&db.open_tree("contacts")?)?;
+        let change_outputs = load_tree::<i32>(&db.open_tree("change")?)?;
+        let hd_tree = db.open_tree("hd")?;
+        let hd = match hd_tree.get("next_index")? {
+            Some(index) => {
+                let entropy = match (hd_tree.get("entropy")?, &encryption, &key) {
+                    (Some(value), None, _) => value.to_vec(),
+                    (Some(value), Some(_), Some(key)) => bincode::deserialize::<Sealed>(&value)?.open(key)
+                        .ok_or_else(|| format_err!("can't decrypt hd seed"))?,
+                    _ => Vec::new(),
+                };
+                let next_index = bincode::deserialize::<u32>(&index)?;
+                Some(HdChain::from_entropy(entropy, next_index)?)
+            }
+            None => None,
+        };
         drop(db);
-        Ok(WalletManager { wallets })
+        Ok(WalletManager { wallets, watch_only, labels, contacts, change_outputs, hd, encryption, key })
     }
 
-    pub fn new_wallet(&mut self) -> String {
-        let wallet = Wallet::new();
+    fn load_session(encryption: &Encryption) -> Result<Option<Vec<u8>>> {
+        let data = match std::fs::read(WalletManager::UNLOCK_PATH) {
+            Ok(data) => data,
+            Err(_) => return Ok(None),
+        };
+        let session = match bincode::deserialize::<UnlockSession>(&data) {
+            Ok(session) if session.expires > now_millis()? => session,
+            _ => {
+                info!("wallet unlock expired");
+                std::fs::remove_file(WalletManager::UNLOCK_PATH)?;
+                return Ok(None);
+            }
+        };
+        let token = match std::env::var(WalletManager::SESSION_VAR) {
+            Ok(token) => hex::decode(token.trim()).ok().filter(|token| token.len() == 32),
+            Err(_) => return Ok(None),
+        };
+        match token.and_then(|token| session.key.open(&token)) {
+            Some(key) if encryption.check_key(&key) => Ok(Some(key)),
+            _ => {
+                info!("{} doesn't match the wallet unlock", WalletManager::SESSION_VAR);
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<()> {
+        if self.encryption.is_some() {
+            return Err(WalletError::AlreadyEncrypted.into());
+        }
+        let (encryption, key) = Encryption::new(passphrase);
+        let params = bincode::serialize(&encryption)?;
+        self.encryption = Some(encryption);
+        self.key = Some(key);
+        // the sled log still holds the plaintext keys, so the encrypted wallet is
+        // written to a fresh database which then replaces the old one
+        let new_path = format!("{}.new", WalletManager::PATH);
+        let old_path = format!("{}.old", WalletManager::PATH);
+        for path in [&new_path, &old_path] {
+            if std::path::Path::new(path).exists() {
+                std::fs::remove_dir_all(path)?;
+            }
+        }
+        let db = sled::open(&new_path)?;
+        db.open_tree("encryption")?.insert("params", params)?;
+        self.save_to(&db)?;
+        drop(db);
+        std::fs::rename(WalletManager::PATH, &old_path)?;
+        std::fs::rename(&new_path, WalletManager::PATH)?;
+        std::fs::remove_dir_all(&old_path)?;
+        // a freshly encrypted wallet starts locked
+        self.lock()
+    }
+
+    // keep the derived key sealed with a random token for timeout seconds, the token
+    // is returned to be passed to the following commands through WALLET_SESSION
+    pub fn unlock(&mut self, passphrase: &str, timeout: u64) -> Result<String> {
+        let encryption = self.encryption.as_ref().ok_or(WalletError::NotEncrypted)?;
+        let key = encryption.unlock(passphrase)?;
+        let mut token = vec![0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut token);
+        let session = UnlockSession {
+            key: Sealed::seal(&token, &key),
+            expires: now_millis()? + timeout as u128 * 1000,
+        };
+        if std::path::Path::new(WalletManager::UNLOCK_PATH).exists() {
+            std::fs::remove_file(WalletManager::UNLOCK_PATH)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(WalletManager::UNLOCK_PATH)?;
+        file.write_all(&bincode::serialize(&session)?)?;
+        self.key = Some(key);
+        Ok(hex::encode(token))
+    }
+
+    pub fn lock(&mut self) -> Result<()> {
+        if self.encryption.is_none() {
+            return Err(WalletError::NotEncrypted.into());
+        }
+        if let Err(_e) = std::fs::remove_file(WalletManager::UNLOCK_PATH) {
+            info!("wallet already locked");
+        }
+        self.key = None;
+        for wallet in self.wallets.values_mut() {
+            wallet.private_key.clear();
+        }
+        if let Some(hd) = &mut self.hd {
+            hd.lock();
+        }
+        Ok(())
+    }
+
+    // derive the next address of the hd chain, creating the seed on first use
+    pub fn new_wallet(&mut self) -> Result<String> {
+        if self.hd.is_none() {
+            self.hd = Some(HdChain::generate()?);
+        }
+        let hd = self.hd.as_mut().unwrap();
+        let wallet = hd.derive(hd.next_index)?;
+        hd.next_index += 1;
         let address = wallet.get_address();
         self.wallets.insert(address.clone(), wallet);
         info!("create wallet at address {}", address);
-        address
+        Ok(address)
+    }
+
+    pub fn get_hd_chain(&self) -> Option<&HdChain> {
+        self.hd.as_ref()
+    }
+
+    // recreate the hd chain from its mnemonic, keeping every derived address reported
+    // as used by is_used and stopping after GAP_LIMIT unused addresses in a row
+    pub fn restore(&mut self, words: &str, is_used: impl Fn(&[u8]) -> bool) -> Result<Vec<String>> {
+        if self.hd.is_some() {
+            return Err(format_err!("wallet already has an hd seed"));
+        }
+        let mut hd = HdChain::from_mnemonic(words)?;
+        let mut restored = Vec::new();
+        let mut index = 0;
+        let mut gap = 0;
+        while gap < GAP_LIMIT {
+            let wallet = hd.derive(index)?;
+            if is_used(&wallet.get_pub_key_hash()) {
+                let address = wallet.get_address();
+                self.wallets.insert(address.clone(), wallet);
+                restored.push(address);
+                hd.next_index = index + 1;
+                gap = 0;
+            } else {
+                gap += 1;
+            }
+            index += 1;
+        }
+        self.hd = Some(hd);
+        Ok(restored)
+    }
+
+    pub fn dump_private_key(&self, address: &str) -> Result<String> {
+        let wallet = self.get_wallet(address)
+            .ok_or_else(|| format_err!("no wallet for address {}", address))?;
+        if !wallet.can_sign() {
+            return Err(WalletError::Locked.into());
+        }
+        Ok(hex::encode(&wallet.private_key))
+    }
+
+    pub fn import_private_key(&mut self, private_key: &str) -> Result<String> {
+        let wallet = Wallet::from_private_key(&hex::decode(private_key.trim())?)?;
+        let address = wallet.get_address();
+        self.watch_only.remove(&address);
+        self.wallets.insert(address.clone(), wallet);
+        info!("import wallet at address {}", address);
+        Ok(address)
+    }
+
+    // track an address without its keys, only for balance and history
+    pub fn import_address(&mut self, address: &str) -> Result<()> {
+        Address::decode(address).map_err(|e| format_err!("invalid address {}: {:?}", address, e))?;
+        if self.wallets.contains_key(address) {
+            return Err(format_err!("address {} is already in the wallet", address));
+        }
+        self.watch_only.insert(address.to_string(), WatchOnly { pub_key: None });
+        Ok(())
+    }
+
+    pub fn import_pub_key(&mut self, pub_key: &str) -> Result<String> {
+        let pub_key = hex::decode(pub_key.trim())?;
+        if pub_key.len() != 32 {
+            return Err(format_err!("invalid public key length {}", pub_key.len()));
+        }
+        let mut pub_key_hash = pub_key.clone();
+        Wallet::hash_pub_key(&mut pub_key_hash);
+        let address = Wallet::address_of(pub_key_hash);
+        self.import_address(&address)?;
+        self.watch_only.insert(address.clone(), WatchOnly { pub_key: Some(pub_key) });
+        Ok(address)
+    }
+
+    pub fn is_watch_only(&self, address: &str) -> bool {
+        self.watch_only.contains_key(address)
+    }
+
+    // own and watch-only addresses, for balance and history
+    pub fn get_tracked_addresses(&self) -> Vec<String> {
+        let mut addresses = self.get_all_addresses();
+        addresses.extend(self.watch_only.keys().cloned());
+        addresses.sort();
+        addresses
+    }
+
+    pub fn set_label(&mut self, address: &str, label: &str) -> Result<()> {
+        if !self.wallets.contains_key(address) && !self.is_watch_only(address) {
+            return Err(format_err!("address {} is not in the wallet", address));
+        }
+        if self.contacts.contains_key(label) {
+            return Err(format_err!("{} is already the name of a contact", label));
+        }
+        if label.is_empty() {
+            self.labels.remove(address);
+        } else {
+            self.labels.insert(address.to_string(), label.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn add_contact(&mut self, name: &str, address: &str) -> Result<()> {
+        Address::decode(address).map_err(|e| format_err!("invalid address {}: {:?}", address, e))?;
+        if self.labels.values().any(|label| label == name) {
+            return Err(format_err!("{} is already the label of a wallet address", name));
+        }
+        self.contacts.insert(name.to_string(), address.to_string());
+        Ok(())
+    }
+
+    pub fn remove_contact(&mut self, name: &str) -> Result<()> {
+        self.contacts.remove(name)
+            .map(|_| ())
+            .ok_or_else(|| format_err!("no contact named {}", name))
+    }
+
+    // turn a contact name or the label of a wallet address into an address,
+    // anything else is taken as an address already
+    pub fn resolve(&self, name: &str) -> String {
+        if let Some(address) = self.contacts.get(name) {
+            return address.clone();
+        }
+        match self.labels.iter().find(|(_, label)| label.as_str() == name) {
+            Some((address, _)) => address.clone(),
+            None => name.to_string(),
+        }
+    }
+
+    // label of a wallet address or name of a contact
+    pub fn get_label(&self, address: &str) -> Option<&str> {
+        if let Some(label) = self.labels.get(address) {
+            return Some(label);
+        }
+        self.contacts.iter()
+            .find(|(_, contact)| contact.as_str() == address)
+            .map(|(name, _)| name.as_str())
+    }
+
+    pub fn backup(&self) -> Result<WalletBackup> {
+        let mut keys = Vec::new();
+        // watch-only entries have no key to back up
+        for address in self.get_all_addresses() {
+            keys.push(BackupKey {
+                private_key: self.dump_private_key(&address)?,
+                label: self.labels.get(&address).cloned(),
+                address,
+            });
+        }
+        keys.sort_by(|a, b| a.address.cmp(&b.address));
+        let (mnemonic, next_index) = match &self.hd {
+            Some(hd) => (Some(hd.mnemonic()?), hd.next_index),
+            None => (None, 0),
+        };
+        Ok(WalletBackup { version: WalletBackup::VERSION, mnemonic, next_index, keys })
+    }
+
+    // import the keys of a backup, and its hd seed if the wallet has none yet
+    pub fn import_backup(&mut self, backup: &WalletBackup) -> Result<Vec<String>> {
+        if backup.version != WalletBackup::VERSION {
+            return Err(format_err!("unsupported wallet backup version {}", backup.version));
+        }
+        let mut imported = Vec::new();
+        for key in &backup.keys {
+            let address = self.import_private_key(&key.private_key)?;
+            if address != key.address {
+                return Err(format_err!("private key doesn't match address {}", key.address));
+            }
+            if let Some(label) = &key.label {
+                self.labels.insert(address.clone(), label.clone());
+            }
+            imported.push(address);
+        }
+        if let Some(mnemonic) = &backup.mnemonic {
+            match &self.hd {
+                Some(_) => info!("wallet already has an hd seed, keep it"),
+                None => {
+                    let mut hd = HdChain::from_mnemonic(mnemonic)?;
+                    hd.next_index = backup.next_index;
+                    self.hd = Some(hd);
+                }
+            }
+        }
+        Ok(imported)
     }
 
     pub fn get_wallet(&self, name: &str) -> Option<&Wallet> {
@@ -91,12 +689,176 @@ impl WalletManager {
     }
 
     pub fn save_all(&self) -> Result<()> {
-        let db = sled::open("data/wallets")?;
+        let db = sled::open(WalletManager::PATH)?;
+        self.save_to(&db)?;
+        drop(db);
+        Ok(())
+    }
+
+    fn save_to(&self, db: &sled::Db) -> Result<()> {
         for (address, wallet) in &self.wallets {
-            db.insert(address, bincode::serialize(wallet)?)?;
+            let value = match &self.encryption {
+                None => bincode::serialize(wallet)?,
+                Some(_) => {
+                    // while locked the stored entry of a loaded wallet is left as is
+                    if !wallet.can_sign() && db.contains_key(address)? {
+                        continue;
+                    }
+                    let key = self.key.as_ref().ok_or(WalletError::Locked)?;
+                    bincode::serialize(&EncryptedWallet {
+                        public_key: wallet.public_key.clone(),
+                        private_key: Sealed::seal(key, &wallet.private_key),
+                    })?
+                }
+            };
+            db.insert(address, value)?;
+        }
+        save_tree(&db.open_tree("watchonly")?, &self.watch_only)?;
+        save_tree(&db.open_tree("labels")?, &self.labels)?;
+        save_tree(&db.open_tree("contacts")?, &self.contacts)?;
+        save_tree(&db.open_tree("change")?, &self.change_outputs)?;
+        if let Some(hd) = &self.hd {
+            let hd_tree = db.open_tree("hd")?;
+            if !hd.entropy.is_empty() {
+                let value = match &self.encryption {
+                    None => hd.entropy.clone(),
+                    Some(_) => {
+                        let key = self.key.as_ref().ok_or(WalletError::Locked)?;
+                        bincode::serialize(&Sealed::seal(key, &hd.entropy))?
+                    }
+                };
+                hd_tree.insert("entropy", value)?;
+            }
+            hd_tree.insert("next_index", bincode::serialize(&hd.next_index)?)?;
         }
         db.flush()?;
-        drop(db);
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+fn load_tree<V: serde::de::DeserializeOwned>(tree: &sled::Tree) -> Result<HashMap<String, V>> {
+    let mut map = HashMap::new();
+    for item in tree.iter() {
+        let (key, value) = item?;
+        map.insert(String::from_utf8(key.to_vec())?, bincode::deserialize::<V>(&value)?);
+    }
+    Ok(map)
+}
+
+// write the map to the tree, removing the entries no longer in it
+fn save_tree<V: Serialize>(tree: &sled::Tree, map: &HashMap<String, V>) -> Result<()> {
+    for (key, value) in map {
+        tree.insert(key, bincode::serialize(value)?)?;
+    }
+    for item in tree.iter() {
+        let (key, _) = item?;
+        if !map.contains_key(&*String::from_utf8_lossy(&key)) {
+            tree.remove(key)?;
+        }
+    }
+    Ok(())
+}
+
+fn now_millis() -> Result<u128> {
+    Ok(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use crate::testutil::enter_temp_dir;
+
+    // an encrypted wallet unlocked for a minute, with its address and session token
+    fn unlocked_wallet() -> (String, String) {
+        let mut wm = WalletManager::new().unwrap();
+        let address = wm.new_wallet().unwrap();
+        wm.encrypt("passphrase").unwrap();
+        let token = wm.unlock("passphrase", 60).unwrap();
+        (address, token)
+    }
+
+    fn can_sign_with(address: &str, token: &str) -> bool {
+        std::env::set_var(WalletManager::SESSION_VAR, token);
+        let wm = WalletManager::new().unwrap();
+        std::env::remove_var(WalletManager::SESSION_VAR);
+        wm.get_wallet(address).unwrap().can_sign()
+    }
+
+    #[test]
+    fn session_token_unlocks_the_wallet() {
+        let _dir = enter_temp_dir();
+        let (address, token) = unlocked_wallet();
+        assert!(can_sign_with(&address, &token));
+    }
+
+    #[test]
+    fn malformed_session_token_leaves_the_wallet_locked() {
+        let _dir = enter_temp_dir();
+        let (address, token) = unlocked_wallet();
+        for token in ["zz", "abcd", "", &token[..62], &format!("{}00", token)] {
+            assert!(!can_sign_with(&address, token), "{:?}", token);
+        }
+    }
+
+    #[test]
+    fn wrong_session_token_leaves_the_wallet_locked() {
+        let _dir = enter_temp_dir();
+        let (address, _) = unlocked_wallet();
+        assert!(!can_sign_with(&address, &hex::encode([7u8; 32])));
+    }
+
+    #[test]
+    fn sealed_key_of_another_size_doesnt_open() {
+        let sealed = Sealed::seal(&[1u8; 32], b"secret");
+        assert_eq!(sealed.open(&[1u8; 32]), Some(b"secret".to_vec()));
+        assert_eq!(sealed.open(&[1u8; 16]), None);
+        assert_eq!(sealed.open(&[]), None);
+    }
+
+    // the addresses at the first indices of the hd chain of a new wallet, and its mnemonic
+    fn hd_addresses(count: usize) -> (Vec<String>, String) {
+        let mut wm = WalletManager::new().unwrap();
+        let addresses = (0..count).map(|_| wm.new_wallet().unwrap()).collect();
+        (addresses, wm.get_hd_chain().unwrap().mnemonic().unwrap())
+    }
+
+    #[test]
+    fn same_mnemonic_derives_the_same_addresses() {
+        let _dir = enter_temp_dir();
+        let (addresses, words) = hd_addresses(3);
+        assert_eq!(addresses.iter().collect::<HashSet<_>>().len(), 3);
+        for hd in [HdChain::from_mnemonic(&words).unwrap(), HdChain::from_mnemonic(&words).unwrap()] {
+            for (index, address) in addresses.iter().enumerate().rev() {
+                assert_eq!(&hd.derive(index as u32).unwrap().get_address(), address);
+            }
+        }
+        let (others, _) = hd_addresses(1);
+        assert_ne!(others[0], addresses[0]);
+    }
+
+    #[test]
+    fn restore_regenerates_the_used_addresses() {
+        let _dir = enter_temp_dir();
+        let (addresses, words) = hd_addresses(4);
+        // an address past GAP_LIMIT unused ones isn't looked for
+        let beyond = HdChain::from_mnemonic(&words).unwrap().derive(3 + GAP_LIMIT + 1).unwrap();
+        let used: HashSet<Vec<u8>> = [&addresses[1], &addresses[3]].iter()
+            .map(|address| Address::decode(address).unwrap().body)
+            .chain(std::iter::once(beyond.get_pub_key_hash()))
+            .collect();
+
+        let mut wm = WalletManager::new().unwrap();
+        assert!(wm.get_all_addresses().is_empty());
+        let restored = wm.restore(&words, |pub_key_hash| used.contains(pub_key_hash)).unwrap();
+        assert_eq!(restored, vec![addresses[1].clone(), addresses[3].clone()]);
+        assert!(wm.get_wallet(&addresses[1]).unwrap().can_sign());
+        assert!(wm.get_wallet(&addresses[0]).is_none());
+        assert!(wm.get_wallet(&beyond.get_address()).is_none());
+
+        // new addresses continue after the last one used
+        assert_eq!(wm.get_hd_chain().unwrap().next_index, 4);
+        assert_eq!(wm.new_wallet().unwrap(), HdChain::from_mnemonic(&words).unwrap().derive(4).unwrap().get_address());
+        assert!(wm.restore(&words, |_| true).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+// compact block relay between nodes running on localhost, each in its own
+// data directory: blocks are rebuilt from the mempool of the receiving node,
+// which asks the sending peer for the transactions it misses
+use std::io::{BufRead, BufReader};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde_json::{json, Value};
+
+const BIN: &str = env!("CARGO_BIN_EXE_simple-blockchain");
+const TIMEOUT: Duration = Duration::from_secs(60);
+// time for a transaction to be relayed to every node
+const RELAY_DELAY: Duration = Duration::from_secs(3);
+
+fn run(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new(BIN).args(args).current_dir(dir).output().unwrap();
+    assert!(output.status.success(), "{:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn new_address(dir: &Path) -> String {
+    let output = run(dir, &["createwallet"]);
+    let line = output.lines().find(|line| line.starts_with("Created wallet at ")).unwrap();
+    line.split(' ').nth(3).unwrap().to_string()
+}
+
+struct RawTx {
+    id: String,
+    hex: String,
+}
+
+// a transaction signed offline by the wallets of dir, spending (txid, vout, address)
+fn raw_tx(dir: &Path, inputs: &[(&str, i32, &str)], outputs: &[(&str, i32)]) -> RawTx {
+    let ins: Vec<Value> = inputs.iter().map(|(txid, vout, _)| json!({"txid": txid, "vout": vout})).collect();
+    let prevouts: Vec<Value> = inputs.iter()
+        .map(|(txid, vout, address)| json!({"txid": txid, "vout": vout, "address": address}))
+        .collect();
+    let outs: serde_json::Map<String, Value> = outputs.iter().map(|(address, value)| (address.to_string(), json!(value))).collect();
+    let unsigned = run(dir, &["createrawtransaction", &Value::from(ins).to_string(), &Value::from(outs).to_string()]);
+    let signed = run(dir, &["signrawtransaction", unsigned.trim(), "--prevouts", &Value::from(prevouts).to_string()]);
+    let hex = signed.lines().last().unwrap().to_string();
+    RawTx { id: decode(dir, &hex)["txid"].as_str().unwrap().to_string(), hex }
+}
+
+fn decode(dir: &Path, hex: &str) -> Value {
+    serde_json::from_str(&run(dir, &["decoderawtransaction", hex])).unwrap()
+}
+
+fn vout_of(dir: &Path, tx: &RawTx, address: &str) -> i32 {
+    let outputs = decode(dir, &tx.hex)["vout"].as_array().unwrap().clone();
+    outputs.iter().position(|out| out["address"] == address).unwrap() as i32
+}
+
+struct Node {
+    child: Child,
+    lines: Arc<Mutex<Vec<String>>>,
+}
+
+impl Node {
+    fn start(dir: &Path, port: u16, connect: Option<u16>) -> Node {
+        let mut args = vec!["startnode".to_string(), port.to_string()];
+        if let Some(peer) = connect {
+            args.push("--connect".to_string());
+            args.push(format!("localhost:{}", peer));
+        }
+        let mut child = Command::new(BIN).args(&args).current_dir(dir)
+            .stdout(Stdio::piped()).stderr(Stdio::null())
+            .spawn().unwrap();
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let stdout = child.stdout.take().unwrap();
+        let collected = lines.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(|line| line.ok()) {
+                collected.lock().unwrap().push(line);
+            }
+        });
+        let start = Instant::now();
+        while TcpStream::connect(("localhost", port)).is_err() {
+            assert!(start.elapsed() < TIMEOUT, "node {} doesn't listen", port);
+            thread::sleep(Duration::from_millis(100));
+        }
+        Node { child, lines }
+    }
+
+    // the first line printed by the node containing text
+    fn wait_for(&self, text: &str) -> String {
+        let start = Instant::now();
+        loop {
+            if let Some(line) = self.lines.lock().unwrap().iter().find(|line| line.contains(text)) {
+                return line.clone();
+            }
+            assert!(start.elapsed() < TIMEOUT, "no {:?} in {:?}", text, self.lines.lock().unwrap());
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("simple-blockchain-relay-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn mine(dir: &Path, address: &str, port: u16) -> String {
+    let output = run(dir, &["mine", address, "--node", &format!("localhost:{}", port)]);
+    output.split(' ').nth(2).unwrap().to_string()
+}
+
+#[test]
+fn compact_blocks_relay_through_three_nodes() {
+    let (d1, d2, d3, client) = (TempDir::new("1"), TempDir::new("2"), TempDir::new("3"), TempDir::new("client"));
+    let base = 21_000 + (std::process::id() % 1_000) as u16 * 3;
+    let (p1, p2, p3) = (base, base + 1, base + 2);
+
+    // a chain with two spendable coins, a of the miner and c, and the
+    // transactions spending them signed ahead
+    let (miner, payee, c) = (new_address(&d1.0), new_address(&d1.0), new_address(&d1.0));
+    run(&d1.0, &["create", &miner]);
+    run(&d1.0, &["transfer", &miner, &c, "50"]);
+    let chain: Value = serde_json::from_str(&run(&d1.0, &["printchain", "--format", "json", "--from", "1", "--to", "1"])).unwrap();
+    let funding = chain[0]["transactions"][0]["txid"].as_str().unwrap().to_string();
+    let shared = raw_tx(&d1.0, &[(&funding, 0, &c)], &[(&payee, 20), (&c, 29)]);
+    let change = vout_of(&d1.0, &shared, &c);
+    let missing = raw_tx(&d1.0, &[(&funding, 1, &miner)], &[(&payee, 10), (&miner, 39)]);
+    let shared_child = raw_tx(&d1.0, &[(&shared.id, change, &c)], &[(&payee, 5), (&c, 23)]);
+
+    let node1 = Node::start(&d1.0, p1, None);
+    let node2 = Node::start(&d2.0, p2, Some(p1));
+    node2.wait_for("Synced at height 1");
+    let node3 = Node::start(&d3.0, p3, Some(p2));
+    node3.wait_for("Synced at height 1");
+
+    // every node has the transaction: the block is rebuilt without a round trip
+    run(&client.0, &["sendrawtransaction", &shared.hex, "--node", &format!("localhost:{}", p1)]);
+    thread::sleep(RELAY_DELAY);
+    let hash = mine(&client.0, &miner, p1);
+    for node in [&node2, &node3] {
+        let line = node.wait_for("at height 2:");
+        assert!(line.contains(&hash), "{}", line);
+        assert!(line.contains("1 of 2 transactions from the mempool, 0 requested"), "{}", line);
+    }
+
+    // a transaction only the miner has is requested from the peer relaying the block
+    drop(node1);
+    run(&d1.0, &["sendrawtransaction", &missing.hex, "--nomine"]);
+    let _node1 = Node::start(&d1.0, p1, None);
+    run(&client.0, &["sendrawtransaction", &shared_child.hex, "--node", &format!("localhost:{}", p1)]);
+    thread::sleep(RELAY_DELAY);
+    let hash = mine(&client.0, &miner, p1);
+    for node in [&node2, &node3] {
+        let line = node.wait_for("at height 3:");
+        assert!(line.contains(&hash), "{}", line);
+        assert!(line.contains("1 of 3 transactions from the mempool, 1 requested"), "{}", line);
+    }
+}